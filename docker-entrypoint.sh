@@ -1,13 +1,32 @@
 #!/bin/bash
 
-export GITHUB_URL=${GITHUB_URL}
+# The GITHUB_ names are what earlier versions of bootstrap generated
+export GIT_URL=${GIT_URL:-${GITHUB_URL}}
 export POLL_INTERVAL=${POLL_INTERVAL:-300}
-export GITHUB_USERNAME=${GITHUB_USERNAME}
-export GITHUB_TOKEN=${GITHUB_TOKEN}
+export GIT_USERNAME=${GIT_USERNAME:-${GITHUB_USERNAME}}
+export GIT_TOKEN=${GIT_TOKEN:-${GITHUB_TOKEN}}
 
 exec /usr/bin/docker-stack-deploy \
   --kdbx /app/repo/.secrets.kdbc \
+  ${LOG_FORMAT:+--log-format "${LOG_FORMAT}"} \
+  ${NOTIFY_WEBHOOK:+--notify-webhook "${NOTIFY_WEBHOOK}"} \
+  ${ORDER_BY_PATH:+--order-by-path} \
+  ${DEPLOY_REPORT:+--report "${DEPLOY_REPORT}"} \
   run \
   --poll-interval "${POLL_INTERVAL}" \
   --repo-dir "${STACK_REPO_DIR}" \
-  --repo-url "${GITHUB_URL}"
+  --repo-url "${GIT_URL}" \
+  ${GIT_SSH_KEY:+--ssh-key "${GIT_SSH_KEY}"} \
+  ${GIT_TOKEN_SECRET:+--git-token-secret "${GIT_TOKEN_SECRET}"} \
+  ${GIT_RESET_ON_CONFLICT:+--git-reset-on-conflict} \
+  ${GIT_BRANCH:+--branch "${GIT_BRANCH}"} \
+  ${GIT_DEPTH:+--git-depth "${GIT_DEPTH}"} \
+  ${GIT_FILTER:+--git-filter "${GIT_FILTER}"} \
+  ${GIT_TIMEOUT:+--git-timeout "${GIT_TIMEOUT}"} \
+  ${PRUNE_REMOVED:+--prune-removed} \
+  ${CHANGED_ONLY:+--changed-only} \
+  ${REQUIRE_SIGNED_COMMITS:+--require-signed-commits} \
+  ${GIT_TRUSTED_KEYS:+--trusted-keys "${GIT_TRUSTED_KEYS}"} \
+  ${METRICS_ADDR:+--metrics-addr "${METRICS_ADDR}"} \
+  ${BACKOFF_FACTOR:+--backoff-factor "${BACKOFF_FACTOR}"} \
+  ${MAX_BACKOFF:+--max-backoff "${MAX_BACKOFF}"}
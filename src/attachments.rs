@@ -0,0 +1,127 @@
+//! Indexing of the binary attachments of KeePass entries.
+//! The keepass crate decodes the attachment data, but discards the
+//! references from each entry to its attachments, so those are
+//! recovered from the XML of the database.
+use anyhow::Context;
+use xml::reader::{EventReader, XmlEvent};
+
+/// An attachment of an entry, as referenced from the XML
+pub struct AttachmentRef {
+    /// The names of the groups containing the entry, starting with
+    /// the root group, followed by the title of the entry and then
+    /// the name of the attachment
+    pub path: Vec<String>,
+    /// The `Ref` attribute, which is an index into the inner header
+    /// attachments of a KDBX4 database, or the `ID` of an element of
+    /// the `Meta/Binaries` of a KDBX3 database
+    pub reference: String,
+}
+
+#[derive(Default)]
+struct EntryState {
+    title: Option<String>,
+    attachments: Vec<(String, String)>,
+    /// The key of the String or Binary element being parsed
+    key: Option<String>,
+}
+
+/// Parse the XML of a database, returning a reference to each attachment
+/// of each entry.  The attachments of historical versions of entries,
+/// and of entries whose title is protected, are not included.
+pub fn index(xml: &[u8]) -> anyhow::Result<Vec<AttachmentRef>> {
+    let mut refs = vec![];
+    // The names of the elements enclosing the current position
+    let mut elements: Vec<String> = vec![];
+    let mut groups: Vec<String> = vec![];
+    let mut entry: Option<EntryState> = None;
+    let mut protected = false;
+
+    for event in EventReader::new(xml) {
+        match event.context("failed to parse the database XML")? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                let parent = elements.last().map(String::as_str);
+                let in_history = elements.iter().any(|e| e == "History");
+                match (parent, name.local_name.as_str()) {
+                    (_, "Group") => groups.push(String::new()),
+                    (_, "Entry") if !in_history => entry = Some(EntryState::default()),
+                    (Some("Binary"), "Value") if !in_history => {
+                        let reference = attributes
+                            .iter()
+                            .find(|attr| attr.name.local_name == "Ref")
+                            .map(|attr| attr.value.clone());
+                        if let (Some(state), Some(reference)) = (entry.as_mut(), reference) {
+                            if let Some(key) = state.key.clone() {
+                                state.attachments.push((key, reference));
+                            }
+                        }
+                    }
+                    (Some("String"), "Value") => {
+                        protected = attributes.iter().any(|attr| {
+                            attr.name.local_name == "Protected"
+                                && attr.value.eq_ignore_ascii_case("true")
+                        });
+                    }
+                    _ => {}
+                }
+                elements.push(name.local_name);
+            }
+            XmlEvent::Characters(text) => {
+                let n = elements.len();
+                let tail: Vec<&str> = elements[n.saturating_sub(3)..]
+                    .iter()
+                    .map(String::as_str)
+                    .collect();
+                let in_history = elements.iter().any(|e| e == "History");
+                match tail[..] {
+                    [_, "Group", "Name"] => {
+                        if let Some(group) = groups.last_mut() {
+                            *group = text;
+                        }
+                    }
+                    ["Entry", "String" | "Binary", "Key"] if !in_history => {
+                        if let Some(state) = entry.as_mut() {
+                            state.key = Some(text);
+                        }
+                    }
+                    ["Entry", "String", "Value"] if !in_history && !protected => {
+                        if let Some(state) = entry.as_mut() {
+                            if state.key.as_deref() == Some("Title") {
+                                state.title = Some(text);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            XmlEvent::EndElement { .. } => {
+                let element = elements.pop();
+                let in_history = elements.iter().any(|e| e == "History");
+                match element.as_deref() {
+                    Some("Group") => {
+                        groups.pop();
+                    }
+                    Some("Entry") if !in_history => {
+                        if let Some(EntryState {
+                            title: Some(title),
+                            attachments,
+                            ..
+                        }) = entry.take()
+                        {
+                            for (name, reference) in attachments {
+                                let mut path = groups.clone();
+                                path.push(title.clone());
+                                path.push(name);
+                                refs.push(AttachmentRef { path, reference });
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(refs)
+}
@@ -0,0 +1,75 @@
+//! Exponential backoff for the poll loop of `run`, so that a repo that
+//! keeps failing to update or deploy isn't retried at every poll.
+use std::time::Duration;
+
+pub struct Backoff {
+    base: Duration,
+    factor: f64,
+    max: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    /// The delay starts at base, and is multiplied by factor after each
+    /// consecutive failure, up to max
+    pub fn new(base: Duration, factor: f64, max: Duration) -> Self {
+        Self {
+            base,
+            factor: factor.max(1.0),
+            max: max.max(base),
+            failures: 0,
+        }
+    }
+
+    /// The number of consecutive failures
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// How long to wait before trying again
+    pub fn delay(&self) -> Duration {
+        let exponent = self.failures.saturating_sub(1).min(64) as i32;
+        let delay = self.base.as_secs_f64() * self.factor.powi(exponent);
+        Duration::from_secs_f64(delay.min(self.max.as_secs_f64()))
+    }
+
+    /// Record a failure, returning how long to wait before trying again
+    pub fn failed(&mut self) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+        self.delay()
+    }
+
+    /// Record a success, which resets the delay to base
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_until_capped_and_resets() {
+        let secs = Duration::from_secs;
+        let mut backoff = Backoff::new(secs(10), 2.0, secs(60));
+        assert_eq!(backoff.delay(), secs(10));
+        assert_eq!(backoff.failed(), secs(10));
+        assert_eq!(backoff.failed(), secs(20));
+        assert_eq!(backoff.failed(), secs(40));
+        assert_eq!(backoff.failed(), secs(60));
+        assert_eq!(backoff.failed(), secs(60));
+        assert_eq!(backoff.failures(), 5);
+
+        backoff.succeeded();
+        assert_eq!(backoff.failures(), 0);
+        assert_eq!(backoff.delay(), secs(10));
+        assert_eq!(backoff.failed(), secs(10));
+
+        // A factor below 1 doesn't shrink the delay, and a max below
+        // base doesn't cap it below base
+        let mut backoff = Backoff::new(secs(10), 0.5, secs(1));
+        assert_eq!(backoff.failed(), secs(10));
+        assert_eq!(backoff.failed(), secs(10));
+    }
+}
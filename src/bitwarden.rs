@@ -0,0 +1,149 @@
+//! A SecretStore backed by a Bitwarden or Vaultwarden vault,
+//! accessed via the `bw` CLI.
+use crate::json::Value;
+use crate::secrets::SecretStore;
+use crate::watchdog::TrackedCommand;
+use anyhow::Context;
+
+pub struct BitwardenStore {
+    session: String,
+    /// folder id -> folder name
+    folders: Vec<(String, String)>,
+    items: Vec<Value>,
+}
+
+fn run_bw(args: &[&str], session: Option<&str>) -> anyhow::Result<String> {
+    let mut cmd = std::process::Command::new("bw");
+    cmd.args(args);
+    if let Some(session) = session {
+        cmd.env("BW_SESSION", session);
+    }
+    let output = cmd
+        .tracked_output()
+        .with_context(|| format!("failed to run bw {}", args.join(" ")))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "bw {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl BitwardenStore {
+    /// Open the vault.  If no session key is provided then the vault
+    /// is unlocked using the provided master password.
+    /// The `bw` CLI must already be configured for the appropriate server
+    /// and logged in.
+    pub fn open(session: Option<String>, password: Option<String>) -> anyhow::Result<Self> {
+        let session = match (session, password) {
+            (Some(session), _) => session,
+            (None, Some(password)) => {
+                let mut cmd = std::process::Command::new("bw");
+                cmd.args(["unlock", "--raw", "--passwordenv", "BW_PASSWORD"]);
+                cmd.env("BW_PASSWORD", password);
+                let output = cmd.tracked_output().context("failed to run bw unlock")?;
+                anyhow::ensure!(
+                    output.status.success(),
+                    "bw unlock failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+                String::from_utf8_lossy(&output.stdout).trim().to_string()
+            }
+            (None, None) => {
+                anyhow::bail!("Missing Bitwarden session or master password");
+            }
+        };
+
+        if let Err(err) = run_bw(&["sync"], Some(&session)) {
+            log::warn!("Failed to sync Bitwarden vault, using cached data: {err:#}");
+        }
+
+        let folders = Value::parse(&run_bw(&["list", "folders"], Some(&session))?)
+            .context("failed to parse bw list folders output")?;
+        let folders = folders
+            .as_array()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|folder| {
+                Some((
+                    folder.get("id")?.as_str()?.to_string(),
+                    folder.get("name")?.as_str()?.to_string(),
+                ))
+            })
+            .collect();
+
+        let items = Value::parse(&run_bw(&["list", "items"], Some(&session))?)
+            .context("failed to parse bw list items output")?;
+        let items = items.as_array().unwrap_or_default().to_vec();
+
+        Ok(Self {
+            session,
+            folders,
+            items,
+        })
+    }
+
+    fn folder_name(&self, item: &Value) -> Option<&str> {
+        let id = item.get("folderId")?.as_str()?;
+        self.folders
+            .iter()
+            .find(|(folder_id, _)| folder_id == id)
+            .map(|(_, name)| name.as_str())
+    }
+}
+
+impl SecretStore for BitwardenStore {
+    /// Given a path like "folder/itemname/fieldname" returns the
+    /// value of the field.  Nested folders are separated by `/`,
+    /// and items that are not in a folder are referenced as
+    /// "itemname/fieldname".
+    /// The fieldname may be one of `username`, `password`, `totp`,
+    /// `notes` or `uri`, or the name of a custom field.
+    /// The path elements are case insensitive.
+    fn resolve_value(&self, path: &str) -> Option<String> {
+        let elements: Vec<&str> = path.split('/').collect();
+        if elements.len() < 2 {
+            return None;
+        }
+        let field = elements[elements.len() - 1];
+        let item_name = elements[elements.len() - 2];
+        let folder = elements[..elements.len() - 2].join("/");
+
+        let item = self.items.iter().find(|item| {
+            let name_matches = item
+                .get("name")
+                .and_then(Value::as_str)
+                .is_some_and(|name| name.eq_ignore_ascii_case(item_name));
+            let folder_matches = match self.folder_name(item) {
+                Some(name) => name.eq_ignore_ascii_case(&folder),
+                None => folder.is_empty(),
+            };
+            name_matches && folder_matches
+        })?;
+
+        let login = item.get("login");
+        let string_field = |value: Option<&Value>| value?.as_str().map(|s| s.to_string());
+
+        match field.to_ascii_lowercase().as_str() {
+            "username" => string_field(login?.get("username")),
+            "password" => string_field(login?.get("password")),
+            "notes" => string_field(item.get("notes")),
+            "uri" => string_field(login?.get("uris")?.as_array()?.first()?.get("uri")),
+            "totp" => {
+                let id = item.get("id")?.as_str()?;
+                run_bw(&["get", "totp", id], Some(&self.session)).ok()
+            }
+            _ => item
+                .get("fields")?
+                .as_array()?
+                .iter()
+                .find(|f| {
+                    f.get("name")
+                        .and_then(Value::as_str)
+                        .is_some_and(|name| name.eq_ignore_ascii_case(field))
+                })
+                .and_then(|f| string_field(f.get("value"))),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Discovery of the secret paths in the KeePass databases, either by
+//! listing all of them, or by navigating the groups interactively.
+use crate::secrets::KeePassDB;
+use keepass::db::{Entry, Group, Node};
+use std::io::{BufRead, Write};
+
+#[derive(Clone, Copy)]
+enum Item<'a> {
+    Group(&'a Group),
+    Entry(&'a Entry, &'a str),
+    Field(&'a str),
+}
+
+/// Returns the names of the fields of entry, in sorted order
+fn sorted_fields(entry: &Entry) -> Vec<&str> {
+    let mut fields: Vec<&str> = entry.fields.keys().map(String::as_str).collect();
+    fields.sort_unstable();
+    fields
+}
+
+/// Returns the groups and then the entries of group.
+/// Entries without a title cannot be addressed by a path, so they
+/// are omitted.
+fn children(group: &Group) -> Vec<Item<'_>> {
+    let groups = group.children.iter().filter_map(|node| match node {
+        Node::Group(group) => Some(Item::Group(group)),
+        Node::Entry(_) => None,
+    });
+    let entries = group.children.iter().filter_map(|node| match node {
+        Node::Entry(entry) => Some(Item::Entry(entry, entry.get_title()?)),
+        Node::Group(_) => None,
+    });
+    groups.chain(entries).collect()
+}
+
+/// Returns every `group/.../entry/field` path in the databases
+pub fn list_paths(db: &KeePassDB) -> Vec<String> {
+    fn walk(group: &Group, prefix: &str, paths: &mut Vec<String>) {
+        let prefix = format!("{prefix}{}/", group.name);
+        for item in children(group) {
+            match item {
+                Item::Group(group) => walk(group, &prefix, paths),
+                Item::Entry(entry, title) => {
+                    for field in sorted_fields(entry) {
+                        paths.push(format!("{prefix}{title}/{field}"));
+                    }
+                }
+                Item::Field(_) => {}
+            }
+        }
+    }
+
+    let mut paths = vec![];
+    for root in db.roots() {
+        walk(root, "", &mut paths);
+    }
+    paths
+}
+
+/// Interactively navigate the groups and entries of the databases,
+/// using stderr for the prompts so that stdout can be captured.
+/// Returns the path of the chosen field, or None if the user quit.
+pub fn browse(db: &KeePassDB) -> anyhow::Result<Option<String>> {
+    let roots: Vec<&Group> = db.roots().collect();
+    let mut groups: Vec<&Group> = vec![];
+    if let [root] = roots[..] {
+        groups.push(root);
+    }
+    let mut entry: Option<(&Entry, &str)> = None;
+
+    let stdin = std::io::stdin();
+    loop {
+        let items: Vec<Item> = match (entry, groups.last()) {
+            (Some((entry, _)), _) => sorted_fields(entry).into_iter().map(Item::Field).collect(),
+            (None, Some(group)) => children(group),
+            (None, None) => roots.iter().map(|root| Item::Group(root)).collect(),
+        };
+
+        let mut location: Vec<&str> = groups.iter().map(|group| group.name.as_str()).collect();
+        if let Some((_, title)) = entry {
+            location.push(title);
+        }
+        let location = location.join("/");
+
+        eprintln!();
+        eprintln!("{location}/");
+        for (idx, item) in items.iter().enumerate() {
+            let label = match item {
+                Item::Group(group) => format!("{}/", group.name),
+                Item::Entry(_, title) => title.to_string(),
+                Item::Field(field) => field.to_string(),
+            };
+            eprintln!("{:>4}  {label}", idx + 1);
+        }
+        eprint!("Enter a number, .. to go up, or q to quit: ");
+        std::io::stderr().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        match line.trim() {
+            "q" => return Ok(None),
+            ".." => {
+                if entry.take().is_none() {
+                    groups.pop();
+                }
+            }
+            choice => {
+                let item = choice
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| n.checked_sub(1))
+                    .and_then(|idx| items.get(idx));
+                match item {
+                    Some(Item::Group(group)) => groups.push(group),
+                    Some(Item::Entry(e, title)) => entry = Some((e, title)),
+                    Some(Item::Field(field)) => return Ok(Some(format!("{location}/{field}"))),
+                    None => eprintln!("Invalid choice {choice:?}"),
+                }
+            }
+        }
+    }
+}
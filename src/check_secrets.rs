@@ -0,0 +1,65 @@
+//! Report whether each of the secrets referenced by deploy files can be
+//! resolved, without printing their values, for debugging secret_env
+//! and secret_file entries.
+use crate::deploy::secret_env_paths;
+use crate::deploy_file::DeployFile;
+use crate::secrets::SecretStore;
+
+/// Describe the outcome of resolving a secret, masking its value
+fn describe(value: Option<usize>) -> String {
+    match value {
+        Some(len) => format!("resolved ({len} bytes)"),
+        None => "NOT FOUND".to_string(),
+    }
+}
+
+/// Print each of the secret_env, secret_file and registry_login secrets
+/// of entries, and whether it resolved.  Returns false if any did not.
+pub fn check_secrets(db: &dyn SecretStore, entries: &[&DeployFile]) -> bool {
+    let mut all_resolved = true;
+    for entry in entries {
+        let deploy = &entry.deploy;
+        println!("{} ({})", deploy.name, entry.path.display());
+        let mut lines = vec![];
+        let mut invalid = vec![];
+        for (name, value) in &deploy.secret_env {
+            match secret_env_paths(value) {
+                Ok(paths) => {
+                    for path in paths {
+                        let resolved = db.resolve_value(path).map(|v| v.len());
+                        lines.push((format!("secret_env {name}"), path, resolved));
+                    }
+                }
+                Err(err) => invalid.push(format!("secret_env {name}: {err:#}")),
+            }
+        }
+        for (file_name, path) in &deploy.secret_file {
+            let resolved = db
+                .resolve_value(path)
+                .map(|v| v.len())
+                .or_else(|| db.resolve_binary(path).map(|v| v.len()));
+            lines.push((format!("secret_file {file_name}"), path, resolved));
+        }
+        for login in &deploy.registry_login {
+            let resolved = db.resolve_value(&login.password).map(|v| v.len());
+            lines.push((
+                format!("registry_login {}", login.registry),
+                &login.password,
+                resolved,
+            ));
+        }
+
+        if lines.is_empty() && invalid.is_empty() {
+            println!("  no secrets");
+        }
+        for problem in &invalid {
+            all_resolved = false;
+            println!("  {problem}");
+        }
+        for (what, path, resolved) in lines {
+            all_resolved &= resolved.is_some();
+            println!("  {what}: {path}: {}", describe(resolved));
+        }
+    }
+    all_resolved
+}
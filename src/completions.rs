@@ -0,0 +1,513 @@
+//! Shell completion scripts, generated from the clap definition of the
+//! command line, so that they stay accurate as the commands and their
+//! options change.
+use clap::ValueEnum;
+use std::fmt::Write;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+/// An option of a command, as needed to complete it
+struct Opt {
+    long: Option<String>,
+    short: Option<char>,
+    help: String,
+    takes_value: bool,
+    /// The values that the option accepts, if it is restricted to a set
+    values: Vec<String>,
+    multiple: bool,
+}
+
+impl Opt {
+    fn flags(&self) -> Vec<String> {
+        let mut flags = vec![];
+        if let Some(long) = &self.long {
+            flags.push(format!("--{long}"));
+        }
+        if let Some(short) = self.short {
+            flags.push(format!("-{short}"));
+        }
+        flags
+    }
+}
+
+/// A command, or subcommand, and its options
+struct Cmd {
+    name: String,
+    about: String,
+    opts: Vec<Opt>,
+    /// Whether the command takes positional arguments
+    positional: bool,
+}
+
+/// The first sentence of some help text, to keep descriptions short
+fn first_sentence(text: Option<&clap::builder::StyledStr>) -> String {
+    let text = text.map(|text| text.to_string()).unwrap_or_default();
+    let line = text.lines().next().unwrap_or_default();
+    let sentence = match line.split_once(". ") {
+        Some((sentence, _)) => sentence,
+        None => line.trim_end_matches('.'),
+    };
+    sentence.trim().to_string()
+}
+
+impl Cmd {
+    fn new(cmd: &clap::Command) -> Self {
+        let mut opts = vec![];
+        let mut positional = false;
+        for arg in cmd.get_arguments() {
+            if arg.is_hide_set() {
+                continue;
+            }
+            if arg.is_positional() {
+                positional = true;
+                continue;
+            }
+            let action = arg.get_action();
+            opts.push(Opt {
+                long: arg.get_long().map(String::from),
+                short: arg.get_short(),
+                help: first_sentence(arg.get_help()),
+                takes_value: action.takes_values(),
+                values: arg
+                    .get_possible_values()
+                    .iter()
+                    .filter(|v| !v.is_hide_set())
+                    .map(|v| v.get_name().to_string())
+                    .collect(),
+                multiple: matches!(action, clap::ArgAction::Append | clap::ArgAction::Count),
+            });
+        }
+        Self {
+            name: cmd.get_name().to_string(),
+            about: first_sentence(cmd.get_about()),
+            opts,
+            positional,
+        }
+    }
+
+    fn words(&self) -> Vec<String> {
+        self.opts.iter().flat_map(Opt::flags).collect()
+    }
+}
+
+/// Print the completion script for shell to stdout
+pub fn print_completions(shell: Shell, cmd: clap::Command) {
+    print!("{}", generate(shell, cmd));
+}
+
+/// Returns the completion script for shell
+fn generate(shell: Shell, mut cmd: clap::Command) -> String {
+    cmd.build();
+    let bin = cmd.get_name().to_string();
+    let root = Cmd::new(&cmd);
+    let subcommands: Vec<Cmd> = cmd
+        .get_subcommands()
+        .filter(|sub| !sub.is_hide_set())
+        .map(Cmd::new)
+        .collect();
+    match shell {
+        Shell::Bash => bash(&bin, &root, &subcommands),
+        Shell::Zsh => zsh(&bin, &root, &subcommands),
+        Shell::Fish => fish(&bin, &root, &subcommands),
+        Shell::Powershell => powershell(&bin, &root, &subcommands),
+    }
+}
+
+/// The name of a shell function for bin
+fn function_name(bin: &str) -> String {
+    format!("_{}", bin.replace('-', "_"))
+}
+
+/// Quote text for use within single quotes in bash, zsh and fish
+fn single_quote(text: &str) -> String {
+    text.replace('\'', "'\\''")
+}
+
+fn bash(bin: &str, root: &Cmd, subcommands: &[Cmd]) -> String {
+    let names: Vec<&str> = subcommands.iter().map(|sub| sub.name.as_str()).collect();
+    let mut script = String::new();
+    let func = function_name(bin);
+    writeln!(script, "{func}() {{").unwrap();
+    script.push_str(
+        "    local cur=\"${COMP_WORDS[COMP_CWORD]}\"\n    \
+        local prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n    \
+        local cmd=\"\" opts i\n    \
+        for ((i = 1; i < COMP_CWORD; i++)); do\n        \
+        case \"${COMP_WORDS[i]}\" in\n",
+    );
+    writeln!(
+        script,
+        "            {}) cmd=\"${{COMP_WORDS[i]}}\"; break ;;",
+        names.join("|")
+    )
+    .unwrap();
+    script.push_str("        esac\n    done\n    case \"$cmd\" in\n");
+
+    let mut emit = |label: &str, cmd: &Cmd, extra: &[&str]| {
+        writeln!(script, "        {label})").unwrap();
+        script.push_str("            case \"$prev\" in\n");
+        for opt in cmd.opts.iter().filter(|opt| opt.takes_value) {
+            let complete = if opt.values.is_empty() {
+                "compgen -f -- \"$cur\"".to_string()
+            } else {
+                format!("compgen -W \"{}\" -- \"$cur\"", opt.values.join(" "))
+            };
+            writeln!(
+                script,
+                "                {}) COMPREPLY=($({complete})); return ;;",
+                opt.flags().join("|")
+            )
+            .unwrap();
+        }
+        script.push_str("            esac\n");
+        let mut words = cmd.words();
+        words.extend(extra.iter().map(|s| s.to_string()));
+        writeln!(script, "            opts=\"{}\"", words.join(" ")).unwrap();
+        script.push_str("            ;;\n");
+    };
+    emit("\"\"", root, &names);
+    for sub in subcommands {
+        emit(&sub.name, sub, &[]);
+    }
+
+    script.push_str(
+        "    esac\n    \
+        if [[ \"$cur\" == -* || -z \"$cmd\" ]]; then\n        \
+        COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n    \
+        else\n        \
+        COMPREPLY=($(compgen -f -- \"$cur\"))\n    \
+        fi\n}\n",
+    );
+    writeln!(script, "complete -F {func} {bin}").unwrap();
+    script
+}
+
+/// Escape help text for use in the description of a zsh _arguments spec
+fn zsh_escape(text: &str) -> String {
+    single_quote(text)
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn zsh_arguments(script: &mut String, indent: &str, cmd: &Cmd, tail: &[&str]) {
+    writeln!(script, "{indent}_arguments -C \\").unwrap();
+    for opt in &cmd.opts {
+        let help = zsh_escape(&opt.help);
+        let repeat = if opt.multiple { "*" } else { "" };
+        let value = if !opt.takes_value {
+            String::new()
+        } else if opt.values.is_empty() {
+            ":value:_default".to_string()
+        } else {
+            format!(":value:({})", opt.values.join(" "))
+        };
+        for flag in opt.flags() {
+            writeln!(script, "{indent}    '{repeat}{flag}[{help}]{value}' \\").unwrap();
+        }
+    }
+    if cmd.positional {
+        writeln!(script, "{indent}    '*:file:_files' \\").unwrap();
+    }
+    for spec in tail {
+        writeln!(script, "{indent}    '{spec}' \\").unwrap();
+    }
+    // Terminate the continuation of the last line
+    writeln!(script, "{indent}    && return").unwrap();
+}
+
+fn zsh(bin: &str, root: &Cmd, subcommands: &[Cmd]) -> String {
+    let func = function_name(bin);
+    let mut script = String::new();
+    writeln!(script, "#compdef {bin}\n").unwrap();
+    writeln!(script, "{func}() {{").unwrap();
+    script.push_str("    local curcontext=\"$curcontext\" state line\n");
+    zsh_arguments(&mut script, "    ", root, &["1: :->command", "*:: :->args"]);
+    script.push_str("    case $state in\n        command)\n            local -a commands\n            commands=(\n");
+    for sub in subcommands {
+        writeln!(
+            script,
+            "                '{}:{}'",
+            sub.name,
+            single_quote(&sub.about).replace(':', "\\:")
+        )
+        .unwrap();
+    }
+    script.push_str(
+        "            )\n            _describe 'command' commands\n            ;;\n        \
+        args)\n            case $line[1] in\n",
+    );
+    for sub in subcommands {
+        writeln!(script, "                {})", sub.name).unwrap();
+        zsh_arguments(&mut script, "                    ", sub, &[]);
+        script.push_str("                    ;;\n");
+    }
+    script.push_str("            esac\n            ;;\n    esac\n}\n\n");
+    writeln!(script, "{func} \"$@\"").unwrap();
+    script
+}
+
+fn fish_options(script: &mut String, bin: &str, condition: &str, cmd: &Cmd) {
+    for opt in &cmd.opts {
+        write!(script, "complete -c {bin} -n \"{condition}\"").unwrap();
+        if let Some(long) = &opt.long {
+            write!(script, " -l {long}").unwrap();
+        }
+        if let Some(short) = opt.short {
+            write!(script, " -s {short}").unwrap();
+        }
+        if opt.takes_value {
+            script.push_str(" -r");
+            if !opt.values.is_empty() {
+                write!(script, " -f -a \"{}\"", opt.values.join(" ")).unwrap();
+            }
+        }
+        writeln!(script, " -d '{}'", single_quote(&opt.help)).unwrap();
+    }
+}
+
+fn fish(bin: &str, root: &Cmd, subcommands: &[Cmd]) -> String {
+    let mut script = String::new();
+    fish_options(&mut script, bin, "__fish_use_subcommand", root);
+    for sub in subcommands {
+        writeln!(
+            script,
+            "complete -c {bin} -n \"__fish_use_subcommand\" -f -a {} -d '{}'",
+            sub.name,
+            single_quote(&sub.about)
+        )
+        .unwrap();
+    }
+    for sub in subcommands {
+        let condition = format!("__fish_seen_subcommand_from {}", sub.name);
+        fish_options(&mut script, bin, &condition, sub);
+    }
+    script
+}
+
+/// Quote a list of words as a PowerShell array
+fn powershell_array(words: &[String]) -> String {
+    let quoted: Vec<String> = words
+        .iter()
+        .map(|word| format!("'{}'", word.replace('\'', "''")))
+        .collect();
+    format!("@({})", quoted.join(", "))
+}
+
+fn powershell(bin: &str, root: &Cmd, subcommands: &[Cmd]) -> String {
+    let mut script = String::new();
+    writeln!(
+        script,
+        "Register-ArgumentCompleter -Native -CommandName '{bin}' -ScriptBlock {{"
+    )
+    .unwrap();
+    script.push_str("    param($wordToComplete, $commandAst, $cursorPosition)\n");
+    script.push_str("    $commands = @{\n");
+    let mut top = root.words();
+    top.extend(subcommands.iter().map(|sub| sub.name.clone()));
+    writeln!(script, "        '' = {}", powershell_array(&top)).unwrap();
+    for sub in subcommands {
+        writeln!(
+            script,
+            "        '{}' = {}",
+            sub.name,
+            powershell_array(&sub.words())
+        )
+        .unwrap();
+    }
+    script.push_str(
+        "    }\n    \
+        $command = ''\n    \
+        foreach ($element in $commandAst.CommandElements | Select-Object -Skip 1) {\n        \
+        $text = $element.ToString()\n        \
+        if ($text -ne $wordToComplete -and $commands.ContainsKey($text)) {\n            \
+        $command = $text\n            \
+        break\n        \
+        }\n    \
+        }\n    \
+        $commands[$command] | Where-Object { $_ -like \"$wordToComplete*\" } | ForEach-Object {\n        \
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)\n    \
+        }\n}\n",
+    );
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction, Command};
+
+    /// A small command line, with help text that needs quoting
+    fn command() -> Command {
+        Command::new("my-tool")
+            .arg(
+                Arg::new("verbose")
+                    .long("verbose")
+                    .short('v')
+                    .action(ArgAction::SetTrue)
+                    .help("Log more. Even more with -vv"),
+            )
+            .subcommand(
+                Command::new("deploy")
+                    .about("Deploy it's [stacks]: now")
+                    .arg(
+                        Arg::new("mode")
+                            .long("mode")
+                            .value_parser(["compose", "swarm"])
+                            .help("The mode"),
+                    )
+                    .arg(
+                        Arg::new("tag")
+                            .long("tag")
+                            .action(ArgAction::Append)
+                            .help("A tag: it's [repeatable]"),
+                    )
+                    .arg(Arg::new("files").num_args(0..)),
+            )
+            .subcommand(Command::new("secret").hide(true))
+            .disable_help_flag(true)
+            .disable_help_subcommand(true)
+    }
+
+    #[test]
+    fn bash() {
+        let script = generate(Shell::Bash, command());
+        assert_eq!(
+            script,
+            r#"_my_tool() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    local prev="${COMP_WORDS[COMP_CWORD-1]}"
+    local cmd="" opts i
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case "${COMP_WORDS[i]}" in
+            deploy) cmd="${COMP_WORDS[i]}"; break ;;
+        esac
+    done
+    case "$cmd" in
+        "")
+            case "$prev" in
+            esac
+            opts="--verbose -v deploy"
+            ;;
+        deploy)
+            case "$prev" in
+                --mode) COMPREPLY=($(compgen -W "compose swarm" -- "$cur")); return ;;
+                --tag) COMPREPLY=($(compgen -f -- "$cur")); return ;;
+            esac
+            opts="--mode --tag"
+            ;;
+    esac
+    if [[ "$cur" == -* || -z "$cmd" ]]; then
+        COMPREPLY=($(compgen -W "$opts" -- "$cur"))
+    else
+        COMPREPLY=($(compgen -f -- "$cur"))
+    fi
+}
+complete -F _my_tool my-tool
+"#
+        );
+
+        // The script must be valid bash, and complete as expected
+        let complete = |words: &str| {
+            let output = std::process::Command::new("bash")
+                .arg("-c")
+                .arg(format!(
+                    "{script}\nCOMP_WORDS=({words}); COMP_CWORD=$((${{#COMP_WORDS[@]}} - 1)); \
+                    _my_tool; echo \"${{COMPREPLY[*]}}\""
+                ))
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{output:?}");
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        assert_eq!(complete("my-tool ''"), "--verbose -v deploy");
+        assert_eq!(complete("my-tool d"), "deploy");
+        assert_eq!(complete("my-tool deploy --"), "--mode --tag");
+        assert_eq!(complete("my-tool deploy --mode s"), "swarm");
+    }
+
+    #[test]
+    fn zsh() {
+        assert_eq!(
+            generate(Shell::Zsh, command()),
+            r#"#compdef my-tool
+
+_my_tool() {
+    local curcontext="$curcontext" state line
+    _arguments -C \
+        '--verbose[Log more]' \
+        '-v[Log more]' \
+        '1: :->command' \
+        '*:: :->args' \
+        && return
+    case $state in
+        command)
+            local -a commands
+            commands=(
+                'deploy:Deploy it'\''s [stacks]\: now'
+            )
+            _describe 'command' commands
+            ;;
+        args)
+            case $line[1] in
+                deploy)
+                    _arguments -C \
+                        '--mode[The mode]:value:(compose swarm)' \
+                        '*--tag[A tag\: it'\''s \[repeatable\]]:value:_default' \
+                        '*:file:_files' \
+                        && return
+                    ;;
+            esac
+            ;;
+    esac
+}
+
+_my_tool "$@"
+"#
+        );
+    }
+
+    #[test]
+    fn fish() {
+        assert_eq!(
+            generate(Shell::Fish, command()),
+            r#"complete -c my-tool -n "__fish_use_subcommand" -l verbose -s v -d 'Log more'
+complete -c my-tool -n "__fish_use_subcommand" -f -a deploy -d 'Deploy it'\''s [stacks]: now'
+complete -c my-tool -n "__fish_seen_subcommand_from deploy" -l mode -r -f -a "compose swarm" -d 'The mode'
+complete -c my-tool -n "__fish_seen_subcommand_from deploy" -l tag -r -d 'A tag: it'\''s [repeatable]'
+"#
+        );
+    }
+
+    #[test]
+    fn powershell() {
+        assert_eq!(
+            generate(Shell::Powershell, command()),
+            r#"Register-ArgumentCompleter -Native -CommandName 'my-tool' -ScriptBlock {
+    param($wordToComplete, $commandAst, $cursorPosition)
+    $commands = @{
+        '' = @('--verbose', '-v', 'deploy')
+        'deploy' = @('--mode', '--tag')
+    }
+    $command = ''
+    foreach ($element in $commandAst.CommandElements | Select-Object -Skip 1) {
+        $text = $element.ToString()
+        if ($text -ne $wordToComplete -and $commands.ContainsKey($text)) {
+            $command = $text
+            break
+        }
+    }
+    $commands[$command] | Where-Object { $_ -like "$wordToComplete*" } | ForEach-Object {
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+    }
+}
+"#
+        );
+    }
+}
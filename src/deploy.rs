@@ -0,0 +1,2502 @@
+//! Deploying stacks with docker compose, or to a swarm with docker
+//! stack: resolving their secrets, bringing them up, down or restarting
+//! them, and sequencing the deploys of a set of stacks according to
+//! their dependencies.
+use crate::deploy_file::*;
+use crate::secrets::SecretStore;
+use crate::watchdog::TrackedCommand;
+use crate::{env_file, output, runtime, status};
+use anyhow::Context;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Deploys a plan of stacks, resolving their secrets from a store.
+/// A plan is a list of stacks in dependency order, such as is
+/// returned by [load_stacks].
+pub struct Deployer<'a> {
+    db: &'a dyn SecretStore,
+    pub(crate) opts: DeployOptions,
+    /// How long each stack took to deploy, by name
+    durations: Mutex<BTreeMap<String, Duration>>,
+}
+
+impl<'a> Deployer<'a> {
+    pub fn new(db: &'a dyn SecretStore, opts: DeployOptions) -> Self {
+        Self {
+            db,
+            opts,
+            durations: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Deploy each of the stacks in plan, returning the status of
+    /// each one, in the same order as plan
+    pub fn deploy<'p>(&self, plan: &'p [DeployFile]) -> Vec<(&'p DeployFile, StackStatus)> {
+        self.durations.lock().unwrap().clear();
+        deploy_stacks(self.db, plan, &self.opts, &self.durations)
+    }
+
+    /// How long the named stack took to deploy during the most recent
+    /// call to deploy, or None if it was not attempted
+    pub fn duration(&self, name: &str) -> Option<Duration> {
+        self.durations.lock().unwrap().get(name).copied()
+    }
+
+    /// Check the stacks in set, their secrets and their compose files,
+    /// without deploying anything.  Returns the problems that were found.
+    pub fn validate(&self, set: &StackSet) -> anyhow::Result<Vec<String>> {
+        validate_stacks(self.db, set, &self.opts)
+    }
+
+    /// Resolve the secrets of each of the stacks in plan, without
+    /// running docker.  Returns the stacks that have secrets that
+    /// could not be resolved, along with a description of each one.
+    pub fn missing_secrets<'p>(
+        &self,
+        plan: &'p [DeployFile],
+    ) -> anyhow::Result<Vec<(&'p DeployFile, Vec<String>)>> {
+        let mut missing = vec![];
+        for entry in plan {
+            let secrets = resolve_secrets(self.db, &entry.path, &entry.deploy)?;
+            if !secrets.problems.is_empty() {
+                missing.push((entry, secrets.problems));
+            }
+        }
+        Ok(missing)
+    }
+}
+
+/// Returns the directory that contains the deploy file at `path`
+pub fn stack_dir(path: &Path) -> anyhow::Result<&Path> {
+    path.parent()
+        .ok_or_else(|| anyhow::anyhow!("path {path:?} has no parent!?"))
+}
+
+/// Prepare a `docker compose` command for the stack defined by the
+/// deploy file at `path`.  The command runs in the directory that
+/// contains the deploy file, and selects the project name of the stack
+/// and any explicitly configured compose files.
+pub fn compose_command(path: &Path, deploy: &StackDeploy) -> anyhow::Result<std::process::Command> {
+    let mut cmd = docker_command(deploy.docker_context.as_deref());
+    cmd.arg("compose");
+    cmd.args(["--project-name", &deploy.project_name()]);
+    if let Some(compose_file) = &deploy.compose_file {
+        for file in compose_file.files() {
+            cmd.args(["-f", file]);
+        }
+    }
+    for profile in &deploy.profiles {
+        cmd.args(["--profile", profile]);
+    }
+    cmd.current_dir(stack_dir(path)?);
+    Ok(cmd)
+}
+
+/// Prepare a `docker stack` command for a stack whose mode is swarm.
+/// The command runs in the directory that contains the deploy file.
+fn stack_command(path: &Path, deploy: &StackDeploy) -> anyhow::Result<std::process::Command> {
+    let mut cmd = docker_command(deploy.docker_context.as_deref());
+    cmd.arg("stack");
+    cmd.current_dir(stack_dir(path)?);
+    Ok(cmd)
+}
+
+/// Unlike docker compose, `docker stack` doesn't look for a compose
+/// file by itself, so returns the `--compose-file` arguments for the
+/// configured compose files, or for the first default one that exists
+fn stack_compose_file_args(path: &Path, deploy: &StackDeploy) -> anyhow::Result<Vec<String>> {
+    let files = match &deploy.compose_file {
+        Some(compose_file) => compose_file.files(),
+        None => {
+            let dir = stack_dir(path)?;
+            let file = DEFAULT_COMPOSE_FILES
+                .iter()
+                .find(|file| dir.join(file).exists())
+                .ok_or_else(|| anyhow::anyhow!("no compose file found in {dir:?}"))?;
+            vec![*file]
+        }
+    };
+    Ok(files
+        .into_iter()
+        .flat_map(|file| ["--compose-file".to_string(), file.to_string()])
+        .collect())
+}
+
+/// Returns the reasons that the options of a swarm stack cannot be
+/// applied by `docker stack deploy`
+fn swarm_problems(deploy: &StackDeploy) -> Vec<String> {
+    let mut problems = vec![];
+    if deploy.mode != DeployMode::Swarm {
+        return problems;
+    }
+    if runtime::Runtime::get() != runtime::Runtime::Docker {
+        problems.push("swarm mode requires docker".to_string());
+    }
+    if !deploy.profiles.is_empty() {
+        problems.push("profiles are not supported in swarm mode".to_string());
+    }
+    if deploy.healthcheck.is_some() {
+        problems.push("healthcheck is not supported in swarm mode".to_string());
+    }
+    if !deploy.scale.is_empty() {
+        problems.push(
+            "scale is not supported in swarm mode; use deploy.replicas in the compose file"
+                .to_string(),
+        );
+    }
+    problems
+}
+
+/// Returns a `docker` (or `podman`) command that uses the specified
+/// docker context (or podman connection)
+pub fn docker_command(context: Option<&str>) -> std::process::Command {
+    let mut cmd = runtime::command();
+    if let Some(context) = context {
+        cmd.args([runtime::Runtime::get().context_option(), context]);
+    }
+    cmd
+}
+
+/// The secrets for a stack, resolved from the database
+#[derive(Default)]
+pub struct ResolvedSecrets {
+    /// Environment variables to set when running docker compose,
+    /// from env_file and then secret_env, with later sources taking
+    /// precedence.  The `.env` file in the stack directory is left for
+    /// compose to load.
+    pub env: Vec<(String, String)>,
+    /// Files that should be written before running docker compose
+    pub files: Vec<(PathBuf, Vec<u8>)>,
+    /// The passwords of the registry_login entries, in the same order
+    pub registry_passwords: Vec<String>,
+    /// Descriptions of the secret_env, secret_file and render entries
+    /// that could not be resolved
+    pub problems: Vec<String>,
+}
+
+pub fn resolve_secrets(
+    db: &dyn SecretStore,
+    path: &Path,
+    deploy: &StackDeploy,
+) -> anyhow::Result<ResolvedSecrets> {
+    resolve_secrets_or_prompt(db, path, deploy, false)
+}
+
+/// Prompt for the value of the secret_env variable key of deploy, whose
+/// secret was not found.  Returns None if no value was entered.
+/// The value is only held in memory for the current deploy.
+fn prompt_secret_env(deploy: &StackDeploy, key: &str, problem: &str) -> Option<String> {
+    // Stacks that are deployed in parallel take turns at the terminal
+    static PROMPT: Mutex<()> = Mutex::new(());
+    let _turn = PROMPT.lock().unwrap();
+    match rpassword::prompt_password(format!(
+        "[{}] {problem}; enter the value of {key}, or nothing to skip it: ",
+        deploy.name
+    )) {
+        Ok(value) if !value.is_empty() => Some(value),
+        Ok(_) => None,
+        Err(err) => {
+            log::warn!(stack = deploy.name.as_str(); "Failed to prompt for {key}: {err:#}");
+            None
+        }
+    }
+}
+
+/// Resolve the secrets of the stack.  When prompt is set, the value of a
+/// secret_env variable whose secret is not found is prompted for, rather
+/// than being reported as a problem.
+fn resolve_secrets_or_prompt(
+    db: &dyn SecretStore,
+    path: &Path,
+    deploy: &StackDeploy,
+    prompt: bool,
+) -> anyhow::Result<ResolvedSecrets> {
+    let dir = stack_dir(path)?;
+    let mut resolved = ResolvedSecrets::default();
+
+    // docker compose loads this itself, with a lower precedence than
+    // anything that we set in its environment, and interpolates its
+    // values.  It is only parsed here to warn about conflicts with
+    // secret_env.
+    let dot_env_path = dir.join(".env");
+    let dot_env = if dot_env_path.exists() {
+        env_file::load(&dot_env_path).unwrap_or_else(|err| {
+            log::debug!(
+                stack = deploy.name.as_str();
+                "Not checking {dot_env_path:?} for conflicts with secret_env: {err:#}"
+            );
+            vec![]
+        })
+    } else {
+        vec![]
+    };
+
+    let mut env_file_vars = vec![];
+    if let Some(env_file) = &deploy.env_file {
+        match env_file::load(&dir.join(env_file)) {
+            Ok(vars) => env_file_vars = vars,
+            Err(err) => resolved.problems.push(format!("env_file: {err:#}")),
+        }
+    }
+
+    let mut secret_vars = vec![];
+    for (k, v) in deploy.secret_env.iter() {
+        let (value, problems) = if is_secret_env_template(v) {
+            match substitute_secrets(db, v, SECRET_ENV_PREFIXES) {
+                Ok((value, missing)) => {
+                    let problems: Vec<String> = missing
+                        .iter()
+                        .map(|path| format!("secret_env {k}: {path} was not found in database"))
+                        .collect();
+                    (missing.is_empty().then_some(value), problems)
+                }
+                Err(err) => {
+                    // Prompting can't fix the template
+                    resolved.problems.push(format!("secret_env {k}: {err:#}"));
+                    continue;
+                }
+            }
+        } else {
+            match db.resolve_value(v) {
+                Some(value) => (Some(value), vec![]),
+                None => (
+                    None,
+                    vec![format!("secret_env {k}: {v} was not found in database")],
+                ),
+            }
+        };
+        let value = match value {
+            None if prompt => prompt_secret_env(deploy, k, &problems.join(", ")),
+            value => value,
+        };
+        match value {
+            Some(value) => secret_vars.push((k.to_string(), value)),
+            None => resolved.problems.extend(problems),
+        }
+    }
+
+    let layered = env_file::layer(dot_env, env_file_vars, secret_vars);
+    for k in &layered.dot_env_conflicts {
+        log::warn!(
+            stack = deploy.name.as_str();
+            "{k} is defined by both {dot_env_path:?} and secret_env; the value from secret_env is used"
+        );
+    }
+    resolved.env = layered.vars;
+
+    for (file_name, v) in deploy.secret_file.iter() {
+        let relative = Path::new(file_name);
+        if !is_within_stack_dir(relative) {
+            resolved.problems.push(format!(
+                "secret_file {file_name} must be a relative path within the stack directory"
+            ));
+            continue;
+        }
+        // A field takes precedence over an attachment with the same path
+        match db
+            .resolve_value(v)
+            .map(String::into_bytes)
+            .or_else(|| db.resolve_binary(v))
+        {
+            Some(v) => {
+                resolved.files.push((dir.join(relative), v));
+            }
+            None => {
+                resolved.problems.push(format!(
+                    "secret_file {file_name}: {v} was not found in database"
+                ));
+            }
+        }
+    }
+
+    for login in &deploy.registry_login {
+        match db.resolve_value(&login.password) {
+            Some(password) => resolved.registry_passwords.push(password),
+            None => resolved.problems.push(format!(
+                "registry_login {}: {} was not found in database",
+                login.registry, login.password
+            )),
+        }
+    }
+
+    for (template, output) in deploy.render.iter() {
+        if !is_within_stack_dir(Path::new(template)) || !is_within_stack_dir(Path::new(output)) {
+            resolved.problems.push(format!(
+                "render {template}: the template and output must be relative paths \
+                within the stack directory"
+            ));
+            continue;
+        }
+        let text = match std::fs::read_to_string(dir.join(template)) {
+            Ok(text) => text,
+            Err(err) => {
+                resolved
+                    .problems
+                    .push(format!("render {template}: failed to read it: {err:#}"));
+                continue;
+            }
+        };
+        let (rendered, missing) = match substitute_secrets(db, &text, RENDER_PREFIXES) {
+            Ok(result) => result,
+            Err(err) => {
+                resolved
+                    .problems
+                    .push(format!("render {template}: {err:#}"));
+                continue;
+            }
+        };
+        if missing.is_empty() {
+            resolved
+                .files
+                .push((dir.join(output), rendered.into_bytes()));
+        }
+        for v in missing {
+            resolved
+                .problems
+                .push(format!("render {template}: {v} was not found in database"));
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Returns true if relative is a relative path that doesn't
+/// reach outside of the stack directory
+fn is_within_stack_dir(relative: &Path) -> bool {
+    !relative.is_absolute()
+        && !relative
+            .components()
+            .any(|c| matches!(c, std::path::Component::ParentDir))
+}
+
+/// The placeholders of a secret_env template.  A value that contains
+/// either of them is a template, rather than the path of a secret.
+/// `${secret:PATH}` is accepted too, so that the `$` of the placeholders
+/// of render templates doesn't end up in the value.
+const SECRET_ENV_PREFIXES: &[&str] = &["${secret:", "{secret:"];
+
+/// The placeholders of a render template.  Other text, including
+/// compose-style `${VAR}` references, is left as-is.
+const RENDER_PREFIXES: &[&str] = &["${secret:"];
+
+fn is_secret_env_template(value: &str) -> bool {
+    SECRET_ENV_PREFIXES
+        .iter()
+        .any(|prefix| value.contains(prefix))
+}
+
+/// A part of a template
+#[derive(Debug, PartialEq, Eq)]
+enum Segment<'a> {
+    Text(&'a str),
+    /// The path of a secret whose value replaces the placeholder
+    Secret(&'a str),
+}
+
+/// Split text into its literal text and its placeholders, each of which
+/// starts with one of prefixes and ends with the next `}`, on the same
+/// line.  Where prefixes overlap, the longer one must be listed first.
+fn parse_template<'a>(text: &'a str, prefixes: &[&str]) -> anyhow::Result<Vec<Segment<'a>>> {
+    let mut segments = vec![];
+    let mut rest = text;
+    // min_by_key picks the first of the prefixes that start at the same place
+    while let Some((start, prefix)) = prefixes
+        .iter()
+        .filter_map(|prefix| rest.find(prefix).map(|start| (start, prefix)))
+        .min_by_key(|(start, _)| *start)
+    {
+        if start > 0 {
+            segments.push(Segment::Text(&rest[..start]));
+        }
+        let after = &rest[start + prefix.len()..];
+        let end = after
+            .find(['}', '\n'])
+            .filter(|&end| after[end..].starts_with('}'))
+            .ok_or_else(|| {
+                let placeholder = after.lines().next().unwrap_or_default();
+                anyhow::anyhow!("the placeholder {prefix}{placeholder} has no closing }}")
+            })?;
+        segments.push(Segment::Secret(&after[..end]));
+        rest = &after[end + 1..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest));
+    }
+    Ok(segments)
+}
+
+/// Returns the paths of the secrets that the secret_env value refers to:
+/// those of its placeholders if it is a template, or else itself
+pub fn secret_env_paths(value: &str) -> anyhow::Result<Vec<&str>> {
+    if !is_secret_env_template(value) {
+        return Ok(vec![value]);
+    }
+    Ok(parse_template(value, SECRET_ENV_PREFIXES)?
+        .into_iter()
+        .filter_map(|segment| match segment {
+            Segment::Secret(path) => Some(path),
+            Segment::Text(_) => None,
+        })
+        .collect())
+}
+
+/// Replace each placeholder in text with the secret at its path.
+/// Returns the rendered text and the paths that could not be resolved,
+/// or an error if a placeholder is unterminated.
+fn substitute_secrets(
+    db: &dyn SecretStore,
+    text: &str,
+    prefixes: &[&str],
+) -> anyhow::Result<(String, Vec<String>)> {
+    let mut rendered = String::with_capacity(text.len());
+    let mut missing = vec![];
+    for segment in parse_template(text, prefixes)? {
+        match segment {
+            Segment::Text(text) => rendered.push_str(text),
+            Segment::Secret(path) => match db.resolve_value(path) {
+                Some(value) => rendered.push_str(&value),
+                None => missing.push(path.to_string()),
+            },
+        }
+    }
+    Ok((rendered, missing))
+}
+
+/// Bring down the stack.  db is only used to resolve the secret_env of
+/// stacks that set secrets_on_down, and may be None if none of them do.
+pub fn do_compose_down(
+    db: Option<&dyn SecretStore>,
+    path: &Path,
+    deploy: &StackDeploy,
+) -> anyhow::Result<()> {
+    if deploy.mode == DeployMode::Swarm {
+        let mut cmd = stack_command(path, deploy)?;
+        cmd.args(["rm", &deploy.project_name()]);
+        let status = output::run(&mut cmd, &deploy.name)
+            .with_context(|| format!("failed to run docker stack rm for {path:?}"))?;
+        anyhow::ensure!(status.success(), "exit status is {status:?}");
+        return Ok(());
+    }
+
+    let mut cmd = compose_command(path, deploy)?;
+    cmd.arg("down");
+    if deploy.remove_orphans {
+        cmd.arg("--remove-orphans");
+    }
+    if deploy.secrets_on_down {
+        let db = db.ok_or_else(|| {
+            anyhow::anyhow!("secrets_on_down requires the secrets database to be opened")
+        })?;
+        let secrets = resolve_secrets(db, path, deploy)?;
+        anyhow::ensure!(
+            secrets.problems.is_empty(),
+            "{}",
+            secrets.problems.join(", ")
+        );
+        cmd.envs(secrets.env);
+    }
+
+    let status = output::run(&mut cmd, &deploy.name)
+        .with_context(|| format!("failed to run docker compose down in directory of {path:?}"))?;
+    anyhow::ensure!(status.success(), "exit status is {status:?}");
+    Ok(())
+}
+
+/// Restart the containers of the stack.  The secrets are resolved
+/// as they are for a deploy, so that they are available to any
+/// compose file interpolation that docker compose performs.
+pub fn do_compose_restart(
+    db: &dyn SecretStore,
+    path: &Path,
+    deploy: &StackDeploy,
+    opts: &DeployOptions,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        deploy.mode != DeployMode::Swarm,
+        "restart is not supported in swarm mode; deploy the stack again instead"
+    );
+    let secrets = resolve_secrets(db, path, deploy)?;
+    anyhow::ensure!(
+        secrets.problems.is_empty(),
+        "{}",
+        secrets.problems.join(", ")
+    );
+
+    // The secret files may have been removed after the deploy,
+    // but the containers that bind mount them need them again
+    for (file_name, content) in &secrets.files {
+        write_secret_file(file_name, content)?;
+    }
+
+    let env_files = EnvFiles::write(path, deploy, &secrets, opts)?;
+    let mut cmd = compose_command(path, deploy)?;
+    env_files.compose_env(path, &secrets)?.apply(&mut cmd);
+    cmd.arg("restart");
+    let result = output::run(&mut cmd, &deploy.name)
+        .with_context(|| format!("failed to run docker compose restart in directory of {path:?}"));
+
+    if deploy.remove_secret_files {
+        remove_secret_files(&secrets);
+    }
+    drop(env_files);
+
+    let status = result?;
+    anyhow::ensure!(status.success(), "exit status is {status:?}");
+    Ok(())
+}
+
+fn remove_secret_files(secrets: &ResolvedSecrets) {
+    for (file_name, _) in &secrets.files {
+        if let Err(err) = std::fs::remove_file(file_name) {
+            log::warn!("Failed to remove secret_file {file_name:?}: {err:#}");
+        }
+    }
+}
+
+/// Returns the stack named name, or an error that explains why
+/// there is no such stack on this host
+pub fn find_stack<'a>(set: &'a StackSet, name: &str) -> anyhow::Result<&'a DeployFile> {
+    if let Some(entry) = set.stacks.get(name) {
+        return Ok(entry);
+    }
+    if set
+        .other_hosts
+        .iter()
+        .any(|entry| entry.deploy.name == *name)
+    {
+        anyhow::bail!("stack {name} does not run on this host");
+    }
+    anyhow::bail!("there is no stack named {name}");
+}
+
+/// Options that influence how stacks are deployed
+#[derive(Default)]
+pub struct DeployOptions {
+    /// How many independent stacks may be deployed concurrently
+    pub parallel: usize,
+    /// Print what would be done rather than running docker
+    pub dry_run: bool,
+    /// Bring down the stacks deployed by this run if any stack fails
+    pub rollback_on_failure: bool,
+    /// Stop deploying at the first stack that fails
+    pub fail_fast: bool,
+    /// Pass the environment of each stack to docker compose via a
+    /// temporary env file, rather than via its own environment
+    pub secure_secrets: bool,
+    /// Don't wait for any stack to become healthy, overriding
+    /// the wait field of their deploy files
+    pub no_wait: bool,
+    /// Check the compose configuration of each stack with
+    /// `docker compose config` before bringing it up
+    pub check_config: bool,
+    /// Label every container with the name of its stack, and the
+    /// commit from which it was deployed
+    pub stamp_labels: bool,
+    /// The passphrase with which a `secret.env.gpg` file is decrypted
+    pub passphrase: Option<String>,
+    /// The age identity file with which a `secret.env.age` file
+    /// is decrypted
+    pub age_identity: Option<PathBuf>,
+    /// Prompt for the value of each secret_env variable whose secret
+    /// is not found, rather than failing to deploy the stack
+    pub prompt_missing_secrets: bool,
+}
+
+impl DeployOptions {
+    /// Whether the first failure should stop any further stacks
+    /// from being deployed
+    pub fn stop_on_failure(&self) -> bool {
+        self.fail_fast || self.rollback_on_failure
+    }
+
+    /// Whether deploying the stack should wait for its services
+    /// to become healthy
+    pub fn wait(&self, deploy: &StackDeploy) -> bool {
+        deploy.wait && !self.no_wait
+    }
+}
+
+/// Produce a shell-like representation of cmd, with the values
+/// of its environment variables masked
+fn describe_command(cmd: &std::process::Command) -> String {
+    let mut result = vec![];
+    for (k, v) in cmd.get_envs() {
+        if v.is_some() {
+            result.push(format!("{}=***", k.to_string_lossy()));
+        }
+    }
+    result.push(cmd.get_program().to_string_lossy().to_string());
+    for arg in cmd.get_args() {
+        result.push(arg.to_string_lossy().to_string());
+    }
+    result.join(" ")
+}
+
+pub fn do_compose_up(
+    db: &dyn SecretStore,
+    path: &Path,
+    deploy: &StackDeploy,
+    opts: &DeployOptions,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !(opts.secure_secrets && deploy.mode == DeployMode::Swarm),
+        "--secure-secrets is not supported in swarm mode, \
+        as docker stack deploy can't read an env file"
+    );
+    let secrets = resolve_secrets_or_prompt(
+        db,
+        path,
+        deploy,
+        opts.prompt_missing_secrets && !opts.dry_run,
+    )?;
+    let env_files = EnvFiles::write(path, deploy, &secrets, opts)?;
+    let compose_env = env_files.compose_env(path, &secrets)?;
+
+    let stamped;
+    // A dry run doesn't run compose to find the services to label
+    let (deploy, _labels_file) =
+        if opts.stamp_labels && !opts.dry_run && secrets.problems.is_empty() {
+            let (with_labels, labels_file) = stamp_labels(path, deploy, &compose_env)?;
+            stamped = with_labels;
+            (&stamped, Some(labels_file))
+        } else {
+            (deploy, None)
+        };
+
+    let (mut cmd, mut pull) = match deploy.mode {
+        DeployMode::Compose => compose_up_commands(path, deploy, &compose_env, opts)?,
+        DeployMode::Swarm => {
+            let mut cmd = stack_deploy_command(path, deploy)?;
+            cmd.envs(secrets.env.iter().map(|(k, v)| (k, v)));
+            (cmd, None)
+        }
+    };
+
+    if opts.dry_run {
+        println!("Stack {} ({path:?})", deploy.name);
+        println!("  cd {}", stack_dir(path)?.display());
+        if let Some(name) = encrypted_env_file(path)? {
+            println!("  decrypt {name}");
+        }
+        for (file_name, _) in &secrets.files {
+            println!("  write {}", file_name.display());
+        }
+        for login in &deploy.registry_login {
+            println!("  login {} as {}", login.registry, login.username);
+        }
+        for wait_for in &deploy.wait_for {
+            wait_for.endpoint()?;
+            println!("  wait for {wait_for}");
+        }
+        if opts.stamp_labels {
+            println!("  label the services with the stack name and commit");
+        }
+        if let Some(pull) = &pull {
+            println!("  {}", describe_command(pull));
+        }
+        println!("  {}", describe_command(&cmd));
+        for hook in &deploy.post_deploy {
+            println!("  post_deploy: {hook}");
+        }
+    }
+
+    if !secrets.problems.is_empty() {
+        return Err(UnresolvedSecrets(secrets.problems.clone()).into());
+    }
+
+    let check_config = || -> anyhow::Result<()> {
+        if !opts.check_config {
+            return Ok(());
+        }
+        match check_compose_config(path, deploy, &compose_env)? {
+            Some(problem) => anyhow::bail!("{problem}"),
+            None => Ok(()),
+        }
+    };
+
+    if opts.dry_run {
+        return check_config();
+    }
+
+    // Run as a closure so that the cleanup below happens however it ends
+    let result = (|| {
+        for wait_for in &deploy.wait_for {
+            wait_for_endpoint(deploy, wait_for)?;
+        }
+
+        for (file_name, content) in &secrets.files {
+            // Removed if the deadline is exceeded while deploying
+            crate::watchdog::temp_file_created(file_name.clone());
+            write_secret_file(file_name, content)?;
+        }
+        check_config()?;
+
+        // Removed, along with the logins, when it is dropped
+        let _registry_auth = if deploy.registry_login.is_empty() {
+            None
+        } else {
+            let auth = RegistryAuth::new(&deploy.registry_login)?;
+            for (login, password) in deploy
+                .registry_login
+                .iter()
+                .zip(&secrets.registry_passwords)
+            {
+                auth.login(deploy, login, password)?;
+            }
+            auth.apply(&mut cmd);
+            if let Some(pull) = pull.as_mut() {
+                auth.apply(pull);
+            }
+            Some(auth)
+        };
+
+        let attempts = deploy.retries + 1;
+        let mut attempt = 1;
+        loop {
+            let result = run_compose_up(path, deploy, pull.as_mut(), &mut cmd);
+            match &result {
+                Err(err) if attempt < attempts && !crate::watchdog::expired() => {
+                    let backoff = deploy
+                        .retry_backoff_seconds
+                        .saturating_mul(1 << (attempt - 1).min(16));
+                    log::warn!(
+                        stack = deploy.name.as_str();
+                        "Attempt {attempt}/{attempts} to deploy {path:?} failed: {err:#}. \
+                        Retrying in {backoff} seconds"
+                    );
+                    std::thread::sleep(std::time::Duration::from_secs(backoff));
+                    attempt += 1;
+                }
+                _ => break result,
+            }
+        }
+    })();
+
+    if deploy.remove_secret_files {
+        remove_secret_files(&secrets);
+    }
+    // The secret files that remain are no longer temporary
+    for (file_name, _) in &secrets.files {
+        crate::watchdog::temp_file_removed(file_name);
+    }
+    drop(env_files);
+
+    result?;
+
+    match &deploy.healthcheck {
+        Some(healthcheck) if opts.wait(deploy) => wait_for_healthy(path, deploy, healthcheck)?,
+        Some(_) => log::info!(
+            stack = deploy.name.as_str();
+            "Not running the healthcheck, as waiting is disabled"
+        ),
+        None => {}
+    }
+
+    run_post_deploy(path, deploy, &secrets.env)
+}
+
+/// A temporary docker config directory that holds the registry logins
+/// of a single deploy, so that they don't persist on the host, and so
+/// that stacks that are deployed at the same time don't affect each
+/// other's logins.  The rest of the config of the host, such as its
+/// contexts, CLI plugins and other logins, remains available through it.
+/// It is removed when this is dropped.
+struct RegistryAuth {
+    dir: PathBuf,
+}
+
+impl RegistryAuth {
+    fn new(logins: &[RegistryLogin]) -> anyhow::Result<Self> {
+        use std::os::unix::fs::DirBuilderExt;
+
+        let dir = temp_path("docker");
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("failed to create {dir:?}"))?;
+        crate::watchdog::temp_file_created(dir.clone());
+        let auth = Self { dir };
+
+        match runtime::Runtime::get() {
+            runtime::Runtime::Docker => auth.link_docker_config(logins)?,
+            runtime::Runtime::Podman => {
+                let host_auth = std::env::var_os("REGISTRY_AUTH_FILE")
+                    .map(PathBuf::from)
+                    .or_else(|| {
+                        std::env::var_os("XDG_RUNTIME_DIR")
+                            .map(|dir| PathBuf::from(dir).join("containers/auth.json"))
+                    });
+                if let Some(host_auth) = host_auth.filter(|path| path.exists()) {
+                    std::fs::copy(&host_auth, auth.auth_file())
+                        .with_context(|| format!("failed to copy {host_auth:?}"))?;
+                }
+            }
+        }
+        Ok(auth)
+    }
+
+    /// The file in which podman stores the logins
+    fn auth_file(&self) -> PathBuf {
+        self.dir.join("auth.json")
+    }
+
+    /// Link each of the entries of the docker config directory of the
+    /// host into this one, other than `config.json`, which is copied so
+    /// that the logins are written to the copy.  The credential helpers
+    /// for the registries of logins are removed from the copy, as they
+    /// would store the credentials on the host.
+    fn link_docker_config(&self, logins: &[RegistryLogin]) -> anyhow::Result<()> {
+        let host_dir = match std::env::var_os("DOCKER_CONFIG") {
+            Some(dir) => PathBuf::from(dir),
+            None => match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".docker"),
+                None => return Ok(()),
+            },
+        };
+        let Ok(entries) = std::fs::read_dir(&host_dir) else {
+            return Ok(());
+        };
+        for entry in entries {
+            let entry = entry.with_context(|| format!("failed to read {host_dir:?}"))?;
+            let name = entry.file_name();
+            if name != "config.json" {
+                std::os::unix::fs::symlink(entry.path(), self.dir.join(&name))
+                    .with_context(|| format!("failed to link {:?}", entry.path()))?;
+            }
+        }
+
+        let host_config = host_dir.join("config.json");
+        let Ok(text) = std::fs::read_to_string(&host_config) else {
+            return Ok(());
+        };
+        let mut config = match crate::json::Value::parse(&text) {
+            Ok(crate::json::Value::Object(fields)) => fields,
+            _ => {
+                log::warn!("Not using {host_config:?}, as it is not a JSON object");
+                return Ok(());
+            }
+        };
+        config.retain(|(key, _)| key != "credsStore");
+        for (key, value) in &mut config {
+            if let (true, crate::json::Value::Object(helpers)) = (key == "credHelpers", value) {
+                helpers.retain(|(registry, _)| !logins.iter().any(|l| &l.registry == registry));
+            }
+        }
+        std::fs::write(
+            self.dir.join("config.json"),
+            crate::json::Value::Object(config).to_string(),
+        )
+        .with_context(|| format!("failed to write the config.json in {:?}", self.dir))
+    }
+
+    /// Make cmd use the logins held here
+    fn apply(&self, cmd: &mut std::process::Command) {
+        match runtime::Runtime::get() {
+            runtime::Runtime::Docker => cmd.env("DOCKER_CONFIG", &self.dir),
+            runtime::Runtime::Podman => cmd.env("REGISTRY_AUTH_FILE", self.auth_file()),
+        };
+    }
+
+    /// Log in to the registry of login, passing the password via stdin
+    /// so that it doesn't appear in the arguments of docker
+    fn login(
+        &self,
+        deploy: &StackDeploy,
+        login: &RegistryLogin,
+        password: &str,
+    ) -> anyhow::Result<()> {
+        use std::io::Write;
+
+        log::info!(stack = deploy.name.as_str(); "Logging in to {} as {}", login.registry, login.username);
+        let mut cmd = docker_command(deploy.docker_context.as_deref());
+        self.apply(&mut cmd);
+        let mut child = cmd
+            .args(["login", "--username", &login.username, "--password-stdin"])
+            .arg(&login.registry)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .tracked_spawn()
+            .with_context(|| format!("failed to run docker login {}", login.registry))?;
+        let written = match child.stdin.take() {
+            Some(mut stdin) => writeln!(stdin, "{password}"),
+            None => Ok(()),
+        };
+        // Wait in any case, so that the child is reaped
+        let output = crate::watchdog::wait_with_output(child)?;
+        written.with_context(|| {
+            format!(
+                "failed to pass the password for {} to docker login",
+                login.registry
+            )
+        })?;
+        anyhow::ensure!(
+            output.status.success(),
+            "failed to log in to {}: {}",
+            login.registry,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(())
+    }
+}
+
+impl Drop for RegistryAuth {
+    fn drop(&mut self) {
+        // It is already gone if the deadline was exceeded
+        match std::fs::remove_dir_all(&self.dir) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!("Failed to remove {:?}: {err:#}", self.dir);
+            }
+            _ => {}
+        }
+        crate::watchdog::temp_file_removed(&self.dir);
+    }
+}
+
+/// Returns the `docker compose up` command for a stack, along with
+/// the `docker compose pull` command that should be run before it,
+/// both of which are run with env.
+/// Unless waiting is disabled by opts, `up` waits for the services to
+/// be healthy.
+fn compose_up_commands(
+    path: &Path,
+    deploy: &StackDeploy,
+    env: &ComposeEnv,
+    opts: &DeployOptions,
+) -> anyhow::Result<(std::process::Command, Option<std::process::Command>)> {
+    let compose_command = |path, deploy| -> anyhow::Result<std::process::Command> {
+        let mut cmd = compose_command(path, deploy)?;
+        env.apply(&mut cmd);
+        Ok(cmd)
+    };
+    let mut cmd = compose_command(path, deploy)?;
+    cmd.arg("up");
+    if deploy.remove_orphans {
+        cmd.arg("--remove-orphans");
+    }
+    cmd.arg("--detach");
+    if !deploy.scale.is_empty() {
+        // An unknown service would fail the whole of compose up.  A dry
+        // run doesn't run compose, so shows the scale of every service.
+        let services = if opts.dry_run {
+            None
+        } else {
+            match compose_services(path, deploy, env) {
+                Ok(services) => Some(services),
+                Err(err) => {
+                    log::warn!(stack = deploy.name.as_str(); "Unable to check the services to scale: {err:#}");
+                    None
+                }
+            }
+        };
+        for (service, replicas) in &deploy.scale {
+            if services.as_ref().is_some_and(|s| !s.contains(service)) {
+                log::warn!(
+                    stack = deploy.name.as_str();
+                    "Ignoring scale {service}, as there is no such service in the compose file"
+                );
+                continue;
+            }
+            cmd.args(["--scale", &format!("{service}={replicas}")]);
+        }
+    }
+    if !opts.wait(deploy) {
+        log::debug!(stack = deploy.name.as_str(); "Not waiting for the services to be healthy");
+    } else if runtime::Runtime::get().supports_wait() {
+        cmd.arg("--wait");
+        if let Some(timeout) = deploy.deploy_timeout {
+            cmd.args(["--wait-timeout", &timeout.to_string()]);
+        }
+    } else {
+        log::info!(
+            stack = deploy.name.as_str();
+            "Not passing --wait to podman compose, as it may not be supported; \
+            use healthcheck to wait for the services to become healthy"
+        );
+    }
+
+    // An explicit pull is run for `always`; the other policies are
+    // left to `docker compose up` to apply
+    let mut pull = None;
+    match deploy.pull_policy {
+        Some(PullPolicy::Always) => {
+            let mut pull_cmd = compose_command(path, deploy)?;
+            pull_cmd.arg("pull");
+            pull = Some(pull_cmd);
+        }
+        Some(policy) => {
+            cmd.args(["--pull", policy.as_str()]);
+        }
+        None => {}
+    }
+    Ok((cmd, pull))
+}
+
+/// Returns the `docker stack deploy` command for a swarm stack.
+/// The files written by secret_file and render can be referenced from
+/// the `secrets` and `configs` of the compose file, which swarm then
+/// stores and distributes to the nodes that run the services.
+fn stack_deploy_command(
+    path: &Path,
+    deploy: &StackDeploy,
+) -> anyhow::Result<std::process::Command> {
+    let problems = swarm_problems(deploy);
+    anyhow::ensure!(problems.is_empty(), "{}", problems.join(", "));
+
+    let mut cmd = stack_command(path, deploy)?;
+    cmd.arg("deploy");
+    cmd.args(stack_compose_file_args(path, deploy)?);
+    if deploy.remove_orphans {
+        cmd.arg("--prune");
+    }
+    if let Some(policy) = deploy.pull_policy {
+        let resolve_image = match policy {
+            PullPolicy::Always => "always",
+            PullPolicy::Missing => "changed",
+            PullPolicy::Never => "never",
+        };
+        cmd.args(["--resolve-image", resolve_image]);
+    }
+    // Pass on the registry credentials of this host, so that the
+    // other nodes of the swarm can pull private images
+    cmd.arg("--with-registry-auth");
+    cmd.arg(deploy.project_name());
+    Ok(cmd)
+}
+
+/// Run the post_deploy hooks of the stack, with its secret_env, the
+/// name of the stack and the current git commit in the environment
+fn run_post_deploy(
+    path: &Path,
+    deploy: &StackDeploy,
+    env: &[(String, String)],
+) -> anyhow::Result<()> {
+    if deploy.post_deploy.is_empty() {
+        return Ok(());
+    }
+
+    let dir = stack_dir(path)?;
+    let commit = get_repo_commit_hash(&dir.to_string_lossy()).ok();
+
+    for hook in &deploy.post_deploy {
+        log::info!(stack = deploy.name.as_str(); "Running post_deploy hook: {hook}");
+        let mut cmd = std::process::Command::new("sh");
+        cmd.args(["-c", hook]);
+        cmd.current_dir(dir);
+        cmd.envs(env.iter().map(|(k, v)| (k, v)));
+        cmd.env("STACK_NAME", &deploy.name);
+        if let Some(commit) = &commit {
+            cmd.env("STACK_COMMIT", commit);
+        }
+        let status = output::run(&mut cmd, &deploy.name)
+            .with_context(|| format!("failed to run post_deploy hook {hook:?}"))?;
+        anyhow::ensure!(
+            status.success(),
+            "post_deploy hook {hook:?} failed with exit status {status:?}"
+        );
+    }
+    Ok(())
+}
+
+/// Run the optional pull command, followed by the up command
+fn run_compose_up(
+    path: &Path,
+    deploy: &StackDeploy,
+    pull: Option<&mut std::process::Command>,
+    up: &mut std::process::Command,
+) -> anyhow::Result<()> {
+    if let Some(pull) = pull {
+        let status = output::run(pull, &deploy.name).with_context(|| {
+            format!("failed to run docker compose pull in directory of {path:?}")
+        })?;
+        anyhow::ensure!(
+            status.success(),
+            "docker compose pull exit status is {status:?}"
+        );
+    }
+
+    let status = run_with_timeout(
+        up,
+        deploy.deploy_timeout.map(std::time::Duration::from_secs),
+        &deploy.name,
+    )
+    .with_context(|| format!("failed to run docker compose up in directory of {path:?}"))?;
+    anyhow::ensure!(status.success(), "exit status is {status:?}");
+    Ok(())
+}
+
+/// Check whether endpoint is reachable, returning the reason if it isn't
+fn check_endpoint(endpoint: Endpoint, timeout: std::time::Duration) -> Result<(), String> {
+    match endpoint {
+        Endpoint::Tcp(address) => {
+            use std::net::ToSocketAddrs;
+            let addrs = address
+                .to_socket_addrs()
+                .map_err(|err| format!("failed to resolve {address}: {err}"))?;
+            let mut last_err = format!("{address} did not resolve to any address");
+            for addr in addrs {
+                match std::net::TcpStream::connect_timeout(&addr, timeout) {
+                    Ok(_) => return Ok(()),
+                    Err(err) => last_err = format!("failed to connect to {addr}: {err}"),
+                }
+            }
+            Err(last_err)
+        }
+        Endpoint::Http(url) => {
+            let output = std::process::Command::new("curl")
+                .args([
+                    "--silent",
+                    "--show-error",
+                    "--fail",
+                    "--location",
+                    "--output",
+                    "/dev/null",
+                    "--max-time",
+                ])
+                .arg(timeout.as_secs().max(1).to_string())
+                .arg(url)
+                .tracked_output()
+                .map_err(|err| format!("failed to run curl: {err}"))?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+            }
+        }
+    }
+}
+
+/// Poll the endpoint of wait_for until it is reachable, or its timeout
+/// has elapsed
+fn wait_for_endpoint(deploy: &StackDeploy, wait_for: &WaitFor) -> anyhow::Result<()> {
+    const INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+    const ATTEMPT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+    let endpoint = wait_for.endpoint()?;
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(wait_for.timeout);
+    let mut logged = false;
+    loop {
+        crate::watchdog::ensure_not_expired()?;
+        let problem = match check_endpoint(endpoint, ATTEMPT_TIMEOUT) {
+            Ok(()) => {
+                if logged {
+                    log::info!(stack = deploy.name.as_str(); "{wait_for} is reachable");
+                }
+                return Ok(());
+            }
+            Err(problem) => problem,
+        };
+        if std::time::Instant::now() + INTERVAL >= deadline {
+            anyhow::bail!(
+                "{wait_for} was not reachable within {} seconds: {problem}",
+                wait_for.timeout
+            );
+        }
+        if !logged {
+            log::info!(
+                stack = deploy.name.as_str();
+                "Waiting for {wait_for} to become reachable: {problem}"
+            );
+            logged = true;
+        }
+        std::thread::sleep(INTERVAL);
+    }
+}
+
+/// Poll the containers of the stack until all of them are running and
+/// none of them are unhealthy or still starting up
+fn wait_for_healthy(
+    path: &Path,
+    deploy: &StackDeploy,
+    healthcheck: &HealthCheck,
+) -> anyhow::Result<()> {
+    let retries = healthcheck.retries.max(1);
+    let mut not_ready = vec![];
+    for attempt in 1..=retries {
+        if attempt > 1 {
+            std::thread::sleep(std::time::Duration::from_secs(healthcheck.interval_seconds));
+        }
+        crate::watchdog::ensure_not_expired()?;
+        not_ready.clear();
+        match status::compose_ps(path, deploy) {
+            Ok(containers) => {
+                for container in &containers {
+                    let state = status::field(container, "State");
+                    let health = status::field(container, "Health");
+                    if state != "running" || health == "starting" || health == "unhealthy" {
+                        not_ready.push(format!(
+                            "{} {state} {health}",
+                            status::field(container, "Service")
+                        ));
+                    }
+                }
+                if not_ready.is_empty() {
+                    return Ok(());
+                }
+            }
+            Err(err) => not_ready.push(format!("{err:#}")),
+        }
+
+        log::info!(
+            stack = deploy.name.as_str();
+            "Waiting for services to become healthy \
+            (attempt {attempt}/{retries}): {}",
+            not_ready.join(", ")
+        );
+    }
+
+    anyhow::bail!(
+        "services are not healthy after {retries} checks: {}",
+        not_ready.join(", ")
+    );
+}
+
+/// Run cmd to completion, killing it if it takes longer than timeout.
+/// Each line of its output is prefixed with prefix.
+fn run_with_timeout(
+    cmd: &mut std::process::Command,
+    timeout: Option<std::time::Duration>,
+    prefix: &str,
+) -> anyhow::Result<std::process::ExitStatus> {
+    let mut child = output::PrefixedChild::spawn(cmd, prefix)?;
+    let Some(timeout) = timeout else {
+        return Ok(child.wait()?);
+    };
+
+    let start = std::time::Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if start.elapsed() >= timeout {
+            if let Err(err) = child.kill() {
+                log::warn!("Failed to kill timed out process: {err:#}");
+            }
+            anyhow::bail!("timed out after {} seconds", timeout.as_secs());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+/// The environment with which docker compose is run for a stack: the
+/// variables that are set in its own environment, and the env files
+/// that are passed to it, with later files taking precedence
+#[derive(Default)]
+struct ComposeEnv<'a> {
+    vars: &'a [(String, String)],
+    files: Vec<&'a Path>,
+}
+
+impl ComposeEnv<'_> {
+    /// Apply this to cmd, which must be a `docker compose` command
+    /// that doesn't have a subcommand yet
+    fn apply(&self, cmd: &mut std::process::Command) {
+        for file in &self.files {
+            cmd.arg("--env-file").arg(file);
+        }
+        cmd.envs(self.vars.iter().map(|(k, v)| (k, v)));
+    }
+}
+
+/// The temporary env files that are passed to compose for a stack: its
+/// decrypted encrypted env file, and with --secure-secrets, its resolved
+/// secret_env.  They are removed when this is dropped.
+struct EnvFiles {
+    decrypted: Option<TempFile>,
+    secure_secrets: Option<TempFile>,
+}
+
+impl EnvFiles {
+    /// Decrypt and write the env files of the stack at path.  A dry run
+    /// only checks that its encrypted env file can be decrypted.
+    fn write(
+        path: &Path,
+        deploy: &StackDeploy,
+        secrets: &ResolvedSecrets,
+        opts: &DeployOptions,
+    ) -> anyhow::Result<Self> {
+        let decrypted = match decrypt_env_file(path, opts)? {
+            Some((name, _)) if deploy.mode == DeployMode::Swarm => anyhow::bail!(
+                "{name} is not supported in swarm mode, as docker stack deploy can't read an env file"
+            ),
+            Some((_, content)) if !opts.dry_run => Some(TempFile::write("env", &content)?),
+            Some((name, _)) => {
+                // Decrypting it checks that it can be, but only a real
+                // deploy needs its content
+                log::debug!(stack = deploy.name.as_str(); "Decrypted {name}");
+                None
+            }
+            None => None,
+        };
+        let secure_secrets = if opts.secure_secrets && !opts.dry_run && secrets.problems.is_empty()
+        {
+            Some(TempFile::write(
+                "env",
+                env_file::format(&secrets.env).as_bytes(),
+            )?)
+        } else {
+            None
+        };
+        Ok(Self {
+            decrypted,
+            secure_secrets,
+        })
+    }
+
+    /// The environment of compose for the stack at path, whose secrets
+    /// are passed in the env file of --secure-secrets if there is one,
+    /// or in the environment of compose if not
+    fn compose_env<'a>(
+        &'a self,
+        path: &Path,
+        secrets: &'a ResolvedSecrets,
+    ) -> anyhow::Result<ComposeEnv<'a>> {
+        // The env file of secure_secrets comes last, so that secret_env
+        // takes precedence over the decrypted env file, as it does when
+        // it is set in the environment of compose
+        let files = compose_env_files(
+            path,
+            self.decrypted
+                .iter()
+                .chain(&self.secure_secrets)
+                .map(|file| file.path.as_path()),
+        )?;
+        Ok(ComposeEnv {
+            vars: if self.secure_secrets.is_some() {
+                &[]
+            } else {
+                &secrets.env
+            },
+            files,
+        })
+    }
+}
+
+/// The names of the encrypted env files that may be placed in the
+/// directory of a stack, in the order in which they are looked for
+const ENCRYPTED_ENV_FILES: &[&str] = &["secret.env.age", "secret.env.gpg"];
+
+/// Returns the name of the encrypted env file of the stack, if it has one
+fn encrypted_env_file(path: &Path) -> anyhow::Result<Option<&'static str>> {
+    let dir = stack_dir(path)?;
+    Ok(ENCRYPTED_ENV_FILES
+        .iter()
+        .copied()
+        .find(|name| dir.join(name).exists()))
+}
+
+/// Returns the files to pass to compose with `--env-file` for the stack
+/// at path, given the files for its environment.  Passing any of them
+/// stops compose from loading `.env` by itself, so `.env` is passed too,
+/// first, so that it keeps the lowest precedence.  Compose runs in the
+/// stack directory, so its path is relative to it.
+fn compose_env_files<'a>(
+    path: &Path,
+    files: impl IntoIterator<Item = &'a Path>,
+) -> anyhow::Result<Vec<&'a Path>> {
+    let mut files: Vec<&Path> = files.into_iter().collect();
+    if !files.is_empty() && stack_dir(path)?.join(".env").exists() {
+        files.insert(0, Path::new(".env"));
+    }
+    Ok(files)
+}
+
+/// Decrypt the encrypted env file of the stack, if it has one, returning
+/// its name and decrypted content.  `secret.env.age` is decrypted by age
+/// using opts.age_identity, and `secret.env.gpg` by gpg, using
+/// opts.passphrase if it was encrypted with a passphrase.
+fn decrypt_env_file(
+    path: &Path,
+    opts: &DeployOptions,
+) -> anyhow::Result<Option<(&'static str, Vec<u8>)>> {
+    use std::io::Write;
+
+    let Some(name) = encrypted_env_file(path)? else {
+        return Ok(None);
+    };
+    let file = stack_dir(path)?.join(name);
+    let mut passphrase = None;
+    let mut cmd = if name.ends_with(".age") {
+        let identity = opts.age_identity.as_deref().with_context(|| {
+            format!("--age-identity must be specified in order to decrypt {file:?}")
+        })?;
+        let mut cmd = std::process::Command::new("age");
+        cmd.arg("--decrypt").arg("--identity").arg(identity);
+        cmd
+    } else {
+        let mut cmd = std::process::Command::new("gpg");
+        cmd.args(["--batch", "--quiet", "--decrypt"]);
+        if let Some(secret) = &opts.passphrase {
+            cmd.args(["--pinentry-mode", "loopback", "--passphrase-fd", "0"]);
+            passphrase = Some(secret);
+        }
+        cmd
+    };
+    cmd.arg(&file)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+
+    let program = cmd.get_program().to_string_lossy().to_string();
+    let mut child = cmd
+        .tracked_spawn()
+        .with_context(|| format!("failed to run {program} to decrypt {file:?}"))?;
+    let written = match (child.stdin.take(), passphrase) {
+        (Some(mut stdin), Some(passphrase)) => writeln!(stdin, "{passphrase}"),
+        _ => Ok(()),
+    };
+    // Wait in any case, so that the child is reaped
+    let output = crate::watchdog::wait_with_output(child)?;
+    written.with_context(|| format!("failed to pass the passphrase to {program}"))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "failed to decrypt {file:?}: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(Some((name, output.stdout)))
+}
+
+/// Returns a new path for a temporary file with the given extension,
+/// preferring a tmpfs so that secrets never reach the disk
+fn temp_path(extension: &str) -> PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = [
+        Some(PathBuf::from("/dev/shm")),
+        std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from),
+    ]
+    .into_iter()
+    .flatten()
+    .find(|dir| dir.is_dir())
+    .unwrap_or_else(std::env::temp_dir);
+    dir.join(format!(
+        "stack-deploy-{}-{}.{extension}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// A temporary file for docker compose to read, which is removed when
+/// this is dropped, such as an env file holding the environment of a
+/// stack.  Passing the environment to docker compose via such a file,
+/// rather than setting it in the environment of docker compose, keeps
+/// the secrets out of `/proc/PID/environ`.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Write content to a new file with the given extension that is
+    /// only readable by its owner, preferring a tmpfs so that secrets
+    /// never reach the disk
+    fn write(extension: &str, content: &[u8]) -> anyhow::Result<Self> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let path = temp_path(extension);
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("failed to create {path:?}"))?;
+        crate::watchdog::temp_file_created(path.clone());
+        let temp_file = Self { path };
+        file.write_all(content)
+            .with_context(|| format!("failed to write {:?}", temp_file.path))?;
+        Ok(temp_file)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // It is already gone if the deadline was exceeded
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!("Failed to remove {:?}: {err:#}", self.path);
+            }
+            _ => {}
+        }
+        crate::watchdog::temp_file_removed(&self.path);
+    }
+}
+
+/// Returns the compose files that docker compose would read for the stack
+/// when none are passed with `-f`: the first of the default files that
+/// exists, along with its `.override` file, if that exists too
+fn default_compose_files(path: &Path) -> anyhow::Result<Vec<String>> {
+    let dir = stack_dir(path)?;
+    let file = DEFAULT_COMPOSE_FILES
+        .iter()
+        .find(|file| dir.join(file).exists())
+        .ok_or_else(|| anyhow::anyhow!("no compose file found in {dir:?}"))?;
+    let mut files = vec![file.to_string()];
+    if let Some((stem, extension)) = file.rsplit_once('.') {
+        let override_file = format!("{stem}.override.{extension}");
+        if dir.join(&override_file).exists() {
+            files.push(override_file);
+        }
+    }
+    Ok(files)
+}
+
+/// Add the `compose.HOSTNAME.yml` (or `.yaml`) file of the stack in
+/// entry, if there is one, after the compose files that it already uses,
+/// so that it can adjust the stack for the host on which it runs
+pub fn add_host_compose_file(entry: &mut DeployFile, hostname: &str) -> anyhow::Result<()> {
+    let dir = stack_dir(&entry.path)?;
+    let Some(host_file) = ["yml", "yaml"]
+        .iter()
+        .map(|extension| format!("compose.{hostname}.{extension}"))
+        .find(|file| dir.join(file).exists())
+    else {
+        return Ok(());
+    };
+    let mut files = match &entry.deploy.compose_file {
+        Some(compose_file) => compose_file.files().into_iter().map(String::from).collect(),
+        None => default_compose_files(&entry.path)
+            .with_context(|| format!("{host_file} requires a compose file to overlay"))?,
+    };
+    if files.contains(&host_file) {
+        return Ok(());
+    }
+    log::debug!(stack = entry.deploy.name.as_str(); "Using the host overlay {host_file}");
+    files.push(host_file);
+    entry.deploy.compose_file = Some(ComposeFile::Multiple(files));
+    Ok(())
+}
+
+/// Override the images of some of the services of the stack in entry,
+/// with images holding `(service, image)` pairs, by writing a compose
+/// override file and adding it after the other compose files of the stack.
+/// The file lives outside of the repo, and is removed when the returned
+/// TempFile is dropped, so the override only applies to this deploy.
+/// Unless dry_run is set, the services are checked to exist, which runs
+/// compose.
+pub fn override_images(
+    entry: &mut DeployFile,
+    images: &[(String, String)],
+    dry_run: bool,
+) -> anyhow::Result<TempFile> {
+    let services = if dry_run {
+        None
+    } else {
+        Some(compose_services(
+            &entry.path,
+            &entry.deploy,
+            &ComposeEnv::default(),
+        )?)
+    };
+    let mut yaml = "services:\n".to_string();
+    for (service, image) in images {
+        anyhow::ensure!(
+            services.as_ref().is_none_or(|s| s.contains(service)),
+            "{} has no service named {service}",
+            entry.deploy.name
+        );
+        yaml.push_str(&format!(
+            "  {}:\n    image: {}\n",
+            yaml_string(service),
+            yaml_string(image)
+        ));
+    }
+    add_override_file(&entry.path, &mut entry.deploy, yaml)
+}
+
+/// Returns a copy of deploy that adds labels naming the stack, and the
+/// commit of the repo from which it is being deployed, to each of its
+/// services, via a compose override file that is removed when the
+/// returned TempFile is dropped.  env is the environment of the stack,
+/// which its compose files may need in order to be parsed.
+fn stamp_labels(
+    path: &Path,
+    deploy: &StackDeploy,
+    env: &ComposeEnv,
+) -> anyhow::Result<(StackDeploy, TempFile)> {
+    let mut labels = vec![("com.stackdeploy.name", deploy.name.clone())];
+    if let Some(commit) = repo_commit(stack_dir(path)?) {
+        labels.push(("com.stackdeploy.commit", commit));
+    }
+    let mut yaml = "services:\n".to_string();
+    for service in compose_services(path, deploy, env)? {
+        yaml.push_str(&format!("  {}:\n    labels:\n", yaml_string(&service)));
+        for (name, value) in &labels {
+            yaml.push_str(&format!("      {name}: {}\n", yaml_string(value)));
+        }
+    }
+    let mut stamped = deploy.clone();
+    let labels_file = add_override_file(path, &mut stamped, yaml)?;
+    Ok((stamped, labels_file))
+}
+
+/// Quote s for use in the compose override files that we generate.
+/// JSON strings are also valid YAML strings.
+fn yaml_string(s: &str) -> String {
+    crate::json::Value::from(s).to_string()
+}
+
+/// Returns the commit of the git checkout containing dir, if it is in one
+fn repo_commit(dir: &Path) -> Option<String> {
+    let output = std::process::Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .current_dir(dir)
+        .tracked_output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Returns the names of the services defined by the compose files of
+/// the stack, when compose is run with env
+fn compose_services(
+    path: &Path,
+    deploy: &StackDeploy,
+    env: &ComposeEnv,
+) -> anyhow::Result<Vec<String>> {
+    let mut cmd = compose_command(path, deploy)?;
+    env.apply(&mut cmd);
+    cmd.args(["config", "--services"]);
+    let output = cmd
+        .tracked_output()
+        .with_context(|| format!("failed to list the services of {}", deploy.name))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "failed to list the services of {}: {}",
+        deploy.name,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+/// Write yaml to a temporary compose override file, and add it to the
+/// compose files of deploy, after those that it already uses
+fn add_override_file(
+    path: &Path,
+    deploy: &mut StackDeploy,
+    yaml: String,
+) -> anyhow::Result<TempFile> {
+    let override_file = TempFile::write("yml", yaml.as_bytes())?;
+    let mut files = match &deploy.compose_file {
+        Some(compose_file) => compose_file.files().into_iter().map(String::from).collect(),
+        None => default_compose_files(path)?,
+    };
+    files.push(override_file.path.to_string_lossy().to_string());
+    deploy.compose_file = Some(ComposeFile::Multiple(files));
+    Ok(override_file)
+}
+
+/// Write a secret to the specified file, ensuring that it is only
+/// readable by its owner.
+fn write_secret_file(file_name: &Path, content: &[u8]) -> anyhow::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    if let Some(parent) = file_name.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create_dir_all {parent:?}"))?;
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(file_name)
+        .with_context(|| format!("failed to open secret_file {file_name:?}"))?;
+    // The mode above only applies to newly created files, so
+    // explicitly tighten up the permissions of a pre-existing file
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("failed to set permissions on secret_file {file_name:?}"))?;
+    file.write_all(content)
+        .with_context(|| format!("failed to write secret_file {file_name:?}"))?;
+    Ok(())
+}
+
+/// The file names that docker compose looks for when no compose
+/// file has been explicitly specified
+const DEFAULT_COMPOSE_FILES: &[&str] = &[
+    "compose.yaml",
+    "compose.yml",
+    "docker-compose.yaml",
+    "docker-compose.yml",
+];
+
+/// Returns the paths of the files that are read when deploying the stack
+/// in entry, changes to which should cause it to be redeployed.
+/// The files written by secret_file and render are not included.
+/// The `compose.HOSTNAME.yml` overlays of every host are included, as
+/// which of them is used depends on the host.
+pub fn input_files(entry: &DeployFile) -> Vec<PathBuf> {
+    let Ok(dir) = stack_dir(&entry.path) else {
+        return vec![entry.path.clone()];
+    };
+    let mut files = vec![entry.path.clone(), dir.join(".env")];
+    match &entry.deploy.compose_file {
+        Some(compose_file) => files.extend(compose_file.files().into_iter().map(|f| dir.join(f))),
+        None => {
+            for file in DEFAULT_COMPOSE_FILES {
+                files.push(dir.join(file));
+                if let Some((stem, extension)) = file.rsplit_once('.') {
+                    files.push(dir.join(format!("{stem}.override.{extension}")));
+                }
+            }
+        }
+    }
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for dir_entry in entries.flatten() {
+            let name = dir_entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("compose.") && (name.ends_with(".yml") || name.ends_with(".yaml")) {
+                files.push(dir_entry.path());
+            }
+        }
+    }
+    files.extend(entry.deploy.env_file.iter().map(|f| dir.join(f)));
+    files.extend(entry.deploy.render.keys().map(|f| dir.join(f)));
+    files.extend(ENCRYPTED_ENV_FILES.iter().map(|f| dir.join(f)));
+    files
+}
+
+/// Returns a hash of the files under root that affect what is deployed:
+/// the deploy file of every stack, whichever hosts it runs on, along
+/// with its input files, and the files that are shared by every stack.
+/// Missing files are hashed as such, so that creating or removing one
+/// changes the hash too.
+pub fn input_fingerprint(root: &str) -> anyhow::Result<String> {
+    use sha2::Digest;
+
+    let root_path = Path::new(root);
+    let mut files: BTreeSet<PathBuf> = [
+        ".secrets.kdbx",
+        DEFAULTS_FILE,
+        HOST_GROUPS_FILE,
+        crate::stackignore::STACK_IGNORE_FILE,
+    ]
+    .iter()
+    .map(|file| root_path.join(file))
+    .collect();
+    for path in deploy_file_paths(root, &[])? {
+        let deploy = read_deploy_file(&path)
+            .ok()
+            .and_then(|text| parse_deploy_file(&path, &text).ok());
+        match deploy {
+            Some(deploy) => files.extend(input_files(&DeployFile { path, deploy })),
+            None => {
+                // It can't be deployed as it is, but its content is
+                // still hashed, so that fixing it is noticed
+                let dir = stack_dir(&path)?;
+                files.extend(DEFAULT_COMPOSE_FILES.iter().map(|f| dir.join(f)));
+                files.insert(path);
+            }
+        }
+    }
+
+    let mut hasher = sha2::Sha256::new();
+    for file in &files {
+        hasher.update(file.as_os_str().as_encoded_bytes());
+        match std::fs::read(file) {
+            Ok(content) => {
+                hasher.update((content.len() as u64).to_le_bytes());
+                hasher.update(&content);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => hasher.update(b"missing"),
+            Err(err) => return Err(err).with_context(|| format!("failed to read {file:?}")),
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Check the stacks, their secrets and their compose files without
+/// deploying anything.  Returns the list of problems that were found.
+fn validate_stacks(
+    db: &dyn SecretStore,
+    set: &StackSet,
+    opts: &DeployOptions,
+) -> anyhow::Result<Vec<String>> {
+    let mut problems = set.dependency_problems();
+    if problems.is_empty() {
+        // Check for cycles
+        if let Err(err) = set.sorted() {
+            problems.push(format!("{err:#}"));
+        }
+    }
+
+    let contexts: BTreeSet<&str> = set
+        .stacks
+        .values()
+        .filter_map(|entry| entry.deploy.docker_context.as_deref())
+        .collect();
+    for context in contexts {
+        if let Err(err) = check_docker_context(context) {
+            problems.push(format!("{err:#}"));
+        }
+    }
+
+    for (name, entry) in &set.stacks {
+        let secrets = resolve_secrets(db, &entry.path, &entry.deploy)?;
+        for problem in secrets.problems {
+            problems.push(format!("{name}: {problem}"));
+        }
+
+        for problem in swarm_problems(&entry.deploy) {
+            problems.push(format!("{name}: {problem}"));
+        }
+
+        for wait_for in &entry.deploy.wait_for {
+            if let Err(err) = wait_for.endpoint() {
+                problems.push(format!("{name}: wait_for {err:#}"));
+            }
+        }
+
+        let dir = stack_dir(&entry.path)?;
+        for output in entry.deploy.render.values() {
+            if is_git_ignored(dir, output) == Some(false) {
+                problems.push(format!(
+                    "{name}: render output {output} is not ignored by git, \
+                    so the secrets rendered into it could be committed"
+                ));
+            }
+        }
+
+        let compose_files_present = match &entry.deploy.compose_file {
+            Some(compose_file) => {
+                let mut present = true;
+                for file in compose_file.files() {
+                    if !dir.join(file).exists() {
+                        problems.push(format!("{name}: compose file {file} does not exist"));
+                        present = false;
+                    }
+                }
+                present
+            }
+            None => {
+                let present = DEFAULT_COMPOSE_FILES
+                    .iter()
+                    .any(|file| dir.join(file).exists());
+                if !present {
+                    problems.push(format!("{name}: no compose file found in {dir:?}"));
+                }
+                present
+            }
+        };
+        if !compose_files_present {
+            continue;
+        }
+
+        // The compose files may interpolate the variables of the
+        // encrypted env file, so it is decrypted as for a deploy
+        let decrypted = match decrypt_env_file(&entry.path, opts) {
+            Ok(Some((file_name, _))) if entry.deploy.mode == DeployMode::Swarm => {
+                problems.push(format!(
+                    "{name}: {file_name} is not supported in swarm mode, \
+                    as docker stack deploy can't read an env file"
+                ));
+                continue;
+            }
+            Ok(Some((_, content))) => Some(TempFile::write("env", &content)?),
+            Ok(None) => None,
+            Err(err) => {
+                problems.push(format!("{name}: {err:#}"));
+                continue;
+            }
+        };
+        let env = ComposeEnv {
+            vars: &secrets.env,
+            files: compose_env_files(
+                &entry.path,
+                decrypted.iter().map(|file| file.path.as_path()),
+            )?,
+        };
+        if let Some(problem) = check_compose_config(&entry.path, &entry.deploy, &env)
+            .with_context(|| format!("checking the compose configuration of {name}"))?
+        {
+            problems.push(format!("{name}: {problem}"));
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Run `docker compose config`, or `docker stack config` for a swarm
+/// stack, with env, the environment of the stack.  This catches syntax
+/// errors in the compose files, and variables that they interpolate
+/// but which are not set.
+/// Returns the output of compose describing the problem, if any.
+fn check_compose_config(
+    path: &Path,
+    deploy: &StackDeploy,
+    env: &ComposeEnv,
+) -> anyhow::Result<Option<String>> {
+    let (mut cmd, label) = match deploy.mode {
+        DeployMode::Compose => {
+            let mut cmd = compose_command(path, deploy)?;
+            env.apply(&mut cmd);
+            cmd.args(["config", "--quiet"]);
+            (cmd, "docker compose config")
+        }
+        DeployMode::Swarm => {
+            let mut cmd = stack_command(path, deploy)?;
+            cmd.arg("config");
+            cmd.args(stack_compose_file_args(path, deploy)?);
+            cmd.envs(env.vars.iter().map(|(k, v)| (k, v)));
+            (cmd, "docker stack config")
+        }
+    };
+    let output = cmd
+        .tracked_output()
+        .with_context(|| format!("failed to run {label}"))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Ok(Some(format!("{label} failed: {}", stderr.trim())));
+    }
+    // Compose substitutes an empty string for unset variables,
+    // which is rarely what was intended
+    let unset: Vec<&str> = stderr
+        .lines()
+        .filter(|line| line.contains("variable is not set"))
+        .map(str::trim)
+        .collect();
+    if !unset.is_empty() {
+        return Ok(Some(format!("{label}: {}", unset.join("\n"))));
+    }
+    Ok(None)
+}
+
+/// Returns whether git ignores path, relative to dir, or None
+/// if that could not be determined, such as when dir is not
+/// part of a git checkout
+fn is_git_ignored(dir: &Path, path: &str) -> Option<bool> {
+    let status = std::process::Command::new("git")
+        .args(["check-ignore", "--quiet", path])
+        .current_dir(dir)
+        .stderr(std::process::Stdio::null())
+        .tracked_status()
+        .ok()?;
+    match status.code() {
+        Some(0) => Some(true),
+        Some(1) => Some(false),
+        _ => None,
+    }
+}
+
+/// Check that the docker daemon used by each of the stacks is
+/// reachable, so that a run can be aborted with a clear error,
+/// rather than failing each stack
+pub fn check_docker(sorted: &[DeployFile]) -> anyhow::Result<()> {
+    let contexts: BTreeSet<Option<&str>> = sorted
+        .iter()
+        .map(|entry| entry.deploy.docker_context.as_deref())
+        .collect();
+    for context in contexts {
+        if let Some(context) = context {
+            check_docker_context(context)?;
+        }
+        let runtime = runtime::Runtime::get();
+        let output = docker_command(context)
+            .args(["info", "--format", runtime.server_version_format()])
+            .tracked_output()
+            .with_context(|| {
+                format!(
+                    "{program} daemon not reachable: failed to run {program} info",
+                    program = runtime.program()
+                )
+            })?;
+        anyhow::ensure!(
+            output.status.success(),
+            "{} daemon{} not reachable: {}",
+            runtime.program(),
+            context
+                .map(|context| format!(" for context {context}"))
+                .unwrap_or_default(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Check that context is a known docker context, or podman connection
+fn check_docker_context(context: &str) -> anyhow::Result<()> {
+    if runtime::Runtime::get() == runtime::Runtime::Podman {
+        let output = runtime::command()
+            .args(["system", "connection", "list", "--format", "{{.Name}}"])
+            .tracked_output()
+            .context("failed to run podman system connection list")?;
+        anyhow::ensure!(
+            output.status.success()
+                && String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .any(|name| name.trim() == context),
+            "podman connection {context} does not exist"
+        );
+        return Ok(());
+    }
+
+    let output = runtime::command()
+        .args(["context", "inspect", context])
+        .tracked_output()
+        .context("failed to run docker context inspect")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "docker context {context} does not exist: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+/// The descriptions of the secrets of a stack that could not be
+/// resolved, which prevented it from being deployed
+#[derive(Debug)]
+struct UnresolvedSecrets(Vec<String>);
+
+impl std::fmt::Display for UnresolvedSecrets {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} secret(s) could not be resolved", self.0.len())
+    }
+}
+
+impl std::error::Error for UnresolvedSecrets {}
+
+/// The result of attempting to deploy a stack
+#[derive(Debug, Clone)]
+pub enum StackStatus {
+    Deployed,
+    Failed(String),
+    /// The stack could not be deployed because of the
+    /// secrets described here
+    MissingSecrets(Vec<String>),
+    /// The stack was not attempted, for the reason given
+    Skipped(String),
+}
+
+impl StackStatus {
+    pub fn is_deployed(&self) -> bool {
+        matches!(self, Self::Deployed)
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Self::Failed(_) | Self::MissingSecrets(_))
+    }
+
+    /// A short description of the status: deployed, failed or skipped
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Deployed => "deployed",
+            Self::Failed(_) | Self::MissingSecrets(_) => "failed",
+            Self::Skipped(_) => "skipped",
+        }
+    }
+
+    /// Why the stack failed, or was skipped
+    pub fn reason(&self) -> Option<String> {
+        match self {
+            Self::Deployed => None,
+            Self::Failed(err) => Some(err.clone()),
+            Self::MissingSecrets(problems) => Some(problems.join(", ")),
+            Self::Skipped(reason) => Some(reason.clone()),
+        }
+    }
+}
+
+/// Log a summary of the secrets that could not be resolved,
+/// grouped by stack, so that they can all be fixed in one pass
+fn log_missing_secrets(outcomes: &[(&DeployFile, StackStatus)]) {
+    let mut summary = vec![];
+    for (entry, status) in outcomes {
+        if let StackStatus::MissingSecrets(problems) = status {
+            summary.push(format!("  {}:", entry.deploy.name));
+            for problem in problems {
+                summary.push(format!("    {problem}"));
+            }
+        }
+    }
+    if !summary.is_empty() {
+        log::error!(
+            "The following secrets could not be resolved:\n{}",
+            summary.join("\n")
+        );
+    }
+}
+
+/// How often to check whether a dependency has become healthy
+const DEPENDENCY_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Wait for each dependency of entry that is marked as healthy to
+/// become healthy.  Dependencies that are not part of plan, such as
+/// when deploying with --only and --no-deps, are not waited for.
+fn wait_for_dependencies(
+    plan: &[DeployFile],
+    entry: &DeployFile,
+    opts: &DeployOptions,
+) -> anyhow::Result<()> {
+    if opts.dry_run {
+        return Ok(());
+    }
+    for dep in entry.deploy.depends_on.iter().filter(|dep| dep.healthy) {
+        let Some(dep_entry) = plan.iter().find(|e| e.deploy.name == dep.stack) else {
+            log::debug!(
+                stack = entry.deploy.name.as_str();
+                "Not waiting for {dep} to be healthy, as it is not being deployed"
+            );
+            continue;
+        };
+        let target = match &dep.service {
+            Some(service) => format!("service {service} of {dep}"),
+            None => dep.to_string(),
+        };
+        anyhow::ensure!(
+            dep_entry.deploy.mode != DeployMode::Swarm,
+            "can't wait for {target} to be healthy, as it is a swarm stack"
+        );
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(dep.timeout);
+        loop {
+            let not_ready = dependency_not_ready(dep_entry, dep)?;
+            if not_ready.is_empty() {
+                break;
+            }
+            anyhow::ensure!(
+                std::time::Instant::now() < deadline,
+                "{target} is not healthy after {} seconds: {}",
+                dep.timeout,
+                not_ready.join(", ")
+            );
+            log::info!(
+                stack = entry.deploy.name.as_str();
+                "Waiting for {target} to become healthy: {}",
+                not_ready.join(", ")
+            );
+            std::thread::sleep(DEPENDENCY_POLL_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+/// Returns a description of each container of the dependency that is
+/// not yet running and healthy, or an error if a container has no
+/// healthcheck, and so will never become healthy
+fn dependency_not_ready(entry: &DeployFile, dep: &Dependency) -> anyhow::Result<Vec<String>> {
+    let containers = status::compose_ps(&entry.path, &entry.deploy)?;
+    let mut not_ready = vec![];
+    let mut found = false;
+    for container in &containers {
+        let service = status::field(container, "Service");
+        if dep.service.as_ref().is_some_and(|s| *s != service) {
+            continue;
+        }
+        found = true;
+        let state = status::field(container, "State");
+        let health = status::field(container, "Health");
+        if state == "running" && health.is_empty() {
+            anyhow::bail!("service {service} of {dep} has no healthcheck, so can't become healthy");
+        }
+        if state != "running" || health != "healthy" {
+            not_ready.push(format!("{service} {state} {health}").trim().to_string());
+        }
+    }
+    if !found {
+        not_ready.push(match &dep.service {
+            Some(service) => format!("{service} has no containers"),
+            None => "no containers".to_string(),
+        });
+    }
+    Ok(not_ready)
+}
+
+fn deploy_one(
+    db: &dyn SecretStore,
+    plan: &[DeployFile],
+    entry: &DeployFile,
+    opts: &DeployOptions,
+    durations: &Mutex<BTreeMap<String, Duration>>,
+) -> StackStatus {
+    if crate::watchdog::expired() {
+        crate::watchdog::stack_finished(&entry.deploy.name);
+        return StackStatus::Skipped("the deploy exceeded --max-runtime".to_string());
+    }
+    let start = std::time::Instant::now();
+    let result = wait_for_dependencies(plan, entry, opts)
+        .and_then(|()| do_compose_up(db, &entry.path, &entry.deploy, opts));
+    crate::watchdog::stack_finished(&entry.deploy.name);
+    durations
+        .lock()
+        .unwrap()
+        .insert(entry.deploy.name.clone(), start.elapsed());
+    match result {
+        Ok(()) if opts.dry_run => StackStatus::Deployed,
+        Ok(()) => {
+            log::info!(stack = entry.deploy.name.as_str(); "Deployed {:?}!", entry.path);
+            StackStatus::Deployed
+        }
+        Err(err) => {
+            log::error!(
+                stack = entry.deploy.name.as_str();
+                "Failed to deploy {:?}: {err:#}", entry.path
+            );
+            match err.downcast::<UnresolvedSecrets>() {
+                Ok(UnresolvedSecrets(problems)) => StackStatus::MissingSecrets(problems),
+                Err(err) => StackStatus::Failed(format!("{err:#}")),
+            }
+        }
+    }
+}
+
+/// Deploy the stacks, which must be in dependency order.
+/// When `opts.parallel` is greater than 1, the stacks are grouped into waves
+/// such that each wave contains only stacks whose dependencies were
+/// deployed in an earlier wave, and up to `opts.parallel` stacks from a wave
+/// are deployed concurrently.  A stack whose dependencies failed to
+/// deploy is skipped in that mode.
+/// When `opts.fail_fast` or `opts.rollback_on_failure` is set, the first
+/// failure stops any further stacks from being deployed.  With the latter,
+/// the stacks that were deployed successfully are then brought down again,
+/// in reverse order.
+/// Returns the status of each stack, in the same order as `sorted`.
+fn deploy_stacks<'a>(
+    db: &dyn SecretStore,
+    sorted: &'a [DeployFile],
+    opts: &DeployOptions,
+    durations: &Mutex<BTreeMap<String, Duration>>,
+) -> Vec<(&'a DeployFile, StackStatus)> {
+    // The stacks that have been attempted, in the order in which
+    // they completed, along with their status
+    let attempted = Mutex::new(vec![]);
+    crate::watchdog::stacks_pending(sorted.iter().map(|entry| entry.deploy.name.as_str()));
+
+    let parallel = opts.parallel;
+    if parallel <= 1 || opts.dry_run {
+        for entry in sorted {
+            let status = deploy_one(db, sorted, entry, opts, durations);
+            let ok = status.is_deployed();
+            attempted.lock().unwrap().push((entry, status));
+            if !ok && opts.stop_on_failure() {
+                break;
+            }
+        }
+    } else {
+        deploy_waves(db, sorted, opts, &attempted, durations);
+    }
+
+    let attempted = attempted.into_inner().unwrap();
+    let any_failed = attempted.iter().any(|(_, status)| !status.is_deployed());
+    if opts.rollback_on_failure && !opts.dry_run && any_failed {
+        for (entry, _) in attempted
+            .iter()
+            .rev()
+            .filter(|(_, status)| status.is_deployed())
+        {
+            log::warn!(stack = entry.deploy.name.as_str(); "Rolling back {:?}", entry.path);
+            if let Err(err) = do_compose_down(Some(db), &entry.path, &entry.deploy) {
+                log::error!(
+                    stack = entry.deploy.name.as_str();
+                    "Failed to roll back {:?}: {err:#}", entry.path
+                );
+            }
+        }
+    }
+
+    log_missing_secrets(&attempted);
+
+    let mut status_by_name: BTreeMap<&str, StackStatus> = attempted
+        .into_iter()
+        .map(|(entry, status)| (entry.deploy.name.as_str(), status))
+        .collect();
+    sorted
+        .iter()
+        .map(|entry| {
+            let status = status_by_name
+                .remove(entry.deploy.name.as_str())
+                .unwrap_or_else(|| {
+                    StackStatus::Skipped("an earlier stack failed to deploy".to_string())
+                });
+            (entry, status)
+        })
+        .collect()
+}
+
+fn deploy_waves<'a>(
+    db: &dyn SecretStore,
+    sorted: &'a [DeployFile],
+    opts: &DeployOptions,
+    attempted: &Mutex<Vec<(&'a DeployFile, StackStatus)>>,
+    durations: &Mutex<BTreeMap<String, Duration>>,
+) {
+    // Compute the wave for each stack; since sorted is in dependency
+    // order, the waves of the dependencies are always known before
+    // we need them
+    let mut wave_of: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut waves: Vec<Vec<&DeployFile>> = vec![];
+    for entry in sorted {
+        let wave = entry
+            .deploy
+            .depends_on
+            .iter()
+            .filter_map(|dep| wave_of.get(dep.stack.as_str()))
+            .map(|w| w + 1)
+            .max()
+            .unwrap_or(0);
+        wave_of.insert(&entry.deploy.name, wave);
+        if waves.len() <= wave {
+            waves.resize_with(wave + 1, Vec::new);
+        }
+        waves[wave].push(entry);
+    }
+
+    let failed = Mutex::new(BTreeSet::new());
+    for wave in waves {
+        if opts.stop_on_failure() && !failed.lock().unwrap().is_empty() {
+            break;
+        }
+
+        let queue = Mutex::new(VecDeque::new());
+        for entry in wave {
+            let blocked_by: Vec<&String> = {
+                let failed = failed.lock().unwrap();
+                entry
+                    .deploy
+                    .depends_on
+                    .iter()
+                    .map(|dep| &dep.stack)
+                    .filter(|dep| failed.contains(dep.as_str()))
+                    .collect()
+            };
+            if blocked_by.is_empty() {
+                queue.lock().unwrap().push_back(entry);
+            } else {
+                log::error!(
+                    stack = entry.deploy.name.as_str();
+                    "Skipping {:?} because its dependencies failed: {blocked_by:?}",
+                    entry.path
+                );
+                failed.lock().unwrap().insert(entry.deploy.name.as_str());
+                attempted.lock().unwrap().push((
+                    entry,
+                    StackStatus::Skipped(format!("its dependencies failed: {blocked_by:?}")),
+                ));
+            }
+        }
+
+        let num_threads = opts.parallel.min(queue.lock().unwrap().len());
+        std::thread::scope(|scope| {
+            for _ in 0..num_threads {
+                scope.spawn(|| loop {
+                    let Some(entry) = queue.lock().unwrap().pop_front() else {
+                        break;
+                    };
+                    let status = deploy_one(db, sorted, entry, opts, durations);
+                    if !status.is_deployed() {
+                        failed.lock().unwrap().insert(entry.deploy.name.as_str());
+                    }
+                    attempted.lock().unwrap().push((entry, status));
+                });
+            }
+        });
+    }
+}
+
+pub fn get_repo_commit_hash(repo_dir: &str) -> anyhow::Result<String> {
+    let mut cmd = std::process::Command::new("git");
+    cmd.current_dir(repo_dir);
+    cmd.args(["rev-parse", "HEAD"]);
+    let output = cmd
+        .tracked_output()
+        .with_context(|| format!("failed to get current commit hash of git repo {repo_dir}"))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "exit status is {:?}",
+        output.status
+    );
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Secrets;
+
+    impl SecretStore for Secrets {
+        fn resolve_value(&self, path: &str) -> Option<String> {
+            (path == "Db/pw").then(|| "hunter2".to_string())
+        }
+    }
+
+    #[test]
+    fn secret_env_templates() {
+        let render = |text| substitute_secrets(&Secrets, text, SECRET_ENV_PREFIXES);
+        assert_eq!(
+            render("postgres://u:{secret:Db/pw}@db/{secret:Db/pw}").unwrap(),
+            ("postgres://u:hunter2@db/hunter2".to_string(), vec![])
+        );
+        // The $ of a render style placeholder is part of the placeholder
+        assert_eq!(
+            render("a ${secret:Db/pw} b").unwrap(),
+            ("a hunter2 b".to_string(), vec![])
+        );
+        assert_eq!(
+            render("{secret:Db/pw}:{secret:Db/other}").unwrap(),
+            ("hunter2:".to_string(), vec!["Db/other".to_string()])
+        );
+        assert_eq!(
+            format!("{:#}", render("x {secret:Db/pw").unwrap_err()),
+            "the placeholder {secret:Db/pw has no closing }"
+        );
+
+        assert_eq!(secret_env_paths("Db/pw").unwrap(), ["Db/pw"]);
+        assert_eq!(
+            secret_env_paths("{secret:A}-${secret:B}").unwrap(),
+            ["A", "B"]
+        );
+        assert!(secret_env_paths("{secret:A").is_err());
+    }
+
+    #[test]
+    fn render_templates() {
+        let render = |text| substitute_secrets(&Secrets, text, RENDER_PREFIXES);
+        // Only the ${secret:...} placeholders are replaced
+        assert_eq!(
+            render("pw=${secret:Db/pw}\nx={secret:Db/pw} ${VAR}\n").unwrap(),
+            ("pw=hunter2\nx={secret:Db/pw} ${VAR}\n".to_string(), vec![])
+        );
+        assert_eq!(
+            format!("{:#}", render("a\npw=${secret:Db/pw\nb=}\n").unwrap_err()),
+            "the placeholder ${secret:Db/pw has no closing }"
+        );
+    }
+
+    #[test]
+    fn fingerprint_includes_host_overlays() {
+        let root = std::env::temp_dir().join(format!("fingerprint-test-{}", std::process::id()));
+        let stack = root.join("web");
+        std::fs::create_dir_all(&stack).unwrap();
+        std::fs::write(
+            stack.join("stack-deploy.toml"),
+            "name = \"web\"\nruns_on = [\"vm\"]\n",
+        )
+        .unwrap();
+        std::fs::write(stack.join("compose.yml"), "services: {}\n").unwrap();
+        let fingerprint = || input_fingerprint(root.to_str().unwrap()).unwrap();
+
+        let before = fingerprint();
+        std::fs::write(stack.join("compose.vm.yml"), "services: {}\n").unwrap();
+        let added = fingerprint();
+        std::fs::write(stack.join("compose.vm.yml"), "services: {web: {}}\n").unwrap();
+        let changed = fingerprint();
+        std::fs::write(stack.join("compose.override.yml"), "services: {}\n").unwrap();
+        let overridden = fingerprint();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_ne!(before, added);
+        assert_ne!(added, changed);
+        assert_ne!(changed, overridden);
+    }
+}
@@ -1,8 +1,9 @@
+use crate::stackignore::{StackIgnore, STACK_IGNORE_FILE};
 use anyhow::Context;
 use filenamegen::Glob;
 use petgraph::prelude::DiGraphMap;
-use serde::Deserialize;
-use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone)]
@@ -17,106 +18,1376 @@ pub struct StackDeploy {
     /// Name of this stack
     pub name: String,
 
+    /// The docker compose project name to use for the stack.
+    /// Defaults to the name of the stack.
+    #[serde(default)]
+    pub project_name: Option<String>,
+
     /// List of stacks that should be deployed before this one
     #[serde(default)]
-    pub depends_on: Vec<String>,
+    pub depends_on: Vec<Dependency>,
+
+    /// Among the stacks whose dependencies are equally deep, those with
+    /// a lower order are deployed first.  This is a hint for sequencing,
+    /// rather than a dependency.
+    #[serde(default)]
+    pub order: i32,
 
     /// Map of environment variables that should be expanded
     /// from the keepass db when running docker compose.
+    /// A value that contains `{secret:PATH}` (or `${secret:PATH}`)
+    /// placeholders is a template, in which each placeholder is replaced
+    /// with the secret at PATH.
     #[serde(default)]
     pub secret_env: BTreeMap<String, String>,
 
-    // TODO: secret_file
-    /// List of host names on which to run this service
+    /// Map of file paths, relative to the compose directory, that should
+    /// be populated from the keepass db when running docker compose.
+    #[serde(default)]
+    pub secret_file: BTreeMap<String, String>,
+
+    /// Map of template file paths to the file paths that should be
+    /// rendered from them before running docker compose, by replacing
+    /// each `${secret:PATH}` placeholder with the secret at PATH.
+    /// Both paths are relative to the compose directory.
+    #[serde(default)]
+    pub render: BTreeMap<String, String>,
+
+    /// Whether the files produced by secret_file and render should be removed
+    /// after docker compose has finished running.
+    /// The default is to leave them in place, as containers that
+    /// bind mount them may need them to be present when they
+    /// are restarted.
+    #[serde(default)]
+    pub remove_secret_files: bool,
+
+    /// Whether to also set the secret_env variables when running
+    /// `docker compose down`, for compose files that interpolate them
+    /// into settings that down needs, such as the driver options of
+    /// volumes.  This is off by default, as it requires the secrets
+    /// to be resolved in order to stop the stack.
+    #[serde(default)]
+    pub secrets_on_down: bool,
+
+    /// Compose file(s) to use instead of the default compose.yml.
+    /// May be a single file name, or a list of file names that
+    /// will be overlaid in the order specified.
+    /// Paths are relative to the directory containing this deploy file.
+    #[serde(default)]
+    pub compose_file: Option<ComposeFile>,
+
+    /// Compose profiles to enable, each of which is passed to docker
+    /// compose via `--profile`
+    #[serde(default)]
+    pub profiles: Vec<String>,
+
+    /// The number of replicas to run of each of the named services,
+    /// overriding the compose file.  Each is passed to docker compose up
+    /// via `--scale SERVICE=N`.
+    #[serde(default)]
+    pub scale: BTreeMap<String, u32>,
+
+    /// How many seconds to allow for the stack to deploy and for its
+    /// services to become healthy, before treating it as failed
+    #[serde(default)]
+    pub deploy_timeout: Option<u64>,
+
+    /// Path, relative to the stack directory, of a file of non-secret
+    /// `KEY=VALUE` environment variables to set when running docker compose.
+    /// secret_env takes precedence over these.
+    #[serde(default)]
+    pub env_file: Option<String>,
+
+    /// How many times to retry `docker compose up` if it fails
+    #[serde(default)]
+    pub retries: u32,
+
+    /// How many seconds to wait before the first retry.  The delay
+    /// doubles for each subsequent retry.
+    #[serde(default = "StackDeploy::default_retry_backoff_seconds")]
+    pub retry_backoff_seconds: u64,
+
+    /// Controls whether images are pulled before the stack is started
+    #[serde(default)]
+    pub pull_policy: Option<PullPolicy>,
+
+    /// Poll the state of the services after deploying, and only consider
+    /// the stack to be deployed once they are all healthy
+    #[serde(default)]
+    pub healthcheck: Option<HealthCheck>,
+
+    /// Registries to log in to before pulling the images of the stack,
+    /// using passwords from the keepass db
+    #[serde(default)]
+    pub registry_login: Vec<RegistryLogin>,
+
+    /// Endpoints, such as `tcp://db.internal:5432` or
+    /// `https://api.internal/health`, that must be reachable before the
+    /// stack is started.  This allows a stack to depend on services that
+    /// are deployed to other hosts, which depends_on can't express.
+    #[serde(default)]
+    pub wait_for: Vec<WaitFor>,
+
+    /// Shell commands to run, in the directory of the stack, after the
+    /// stack has been deployed successfully
+    #[serde(default)]
+    pub post_deploy: Vec<String>,
+
+    /// The docker context, as managed by `docker context`, to use
+    /// when running docker compose, so that the stack can be deployed
+    /// to a remote docker host.  Defaults to the current context.
+    #[serde(default)]
+    pub docker_context: Option<String>,
+
+    /// Whether to pass `--remove-orphans` to docker compose, which
+    /// removes containers in the project that are not defined by
+    /// its compose files.  Turn this off if you run other containers
+    /// in the same project outside of compose.
+    #[serde(default = "StackDeploy::default_remove_orphans")]
+    pub remove_orphans: bool,
+
+    /// Whether to wait for the services to be running and healthy
+    /// before considering the stack to be deployed.  When turned off,
+    /// the stack is deployed once compose has started its containers,
+    /// and its health is not verified.
+    #[serde(default = "StackDeploy::default_wait")]
+    pub wait: bool,
+
+    /// How the stack is deployed: with docker compose, which is the
+    /// default, or to a Docker Swarm with `docker stack deploy`
+    #[serde(default)]
+    pub mode: DeployMode,
+
+    /// List of host names on which to run this service.
+    /// Entries of the form `@NAME` refer to a group of hosts
+    /// defined in the hosts.toml file.
     pub runs_on: Vec<String>,
 }
 
+impl StackDeploy {
+    fn default_retry_backoff_seconds() -> u64 {
+        5
+    }
+
+    fn default_remove_orphans() -> bool {
+        true
+    }
+
+    fn default_wait() -> bool {
+        true
+    }
+
+    /// Returns the compose project name for the stack.
+    /// Unless overridden by project_name, it is derived from the name
+    /// of the stack, using only the characters that compose permits.
+    pub fn project_name(&self) -> String {
+        if let Some(project_name) = &self.project_name {
+            return project_name.clone();
+        }
+        self.name
+            .to_lowercase()
+            .chars()
+            .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '-' || *c == '_')
+            .collect::<String>()
+            .trim_start_matches(['-', '_'])
+            .to_string()
+    }
+}
+
+/// A depends_on entry: either the name of a stack, or a glob pattern
+/// matching the names of stacks, or a table that also specifies that
+/// the stack must be healthy before the dependent stack is deployed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    /// The name of the stack, or a glob pattern
+    pub stack: String,
+    /// Wait for the services of the stack to be healthy before
+    /// deploying the stack that depends upon it
+    pub healthy: bool,
+    /// Only wait for this service, rather than all of those in the stack
+    pub service: Option<String>,
+    /// How many seconds to wait for the stack to become healthy
+    pub timeout: u64,
+}
+
+impl Dependency {
+    fn default_timeout() -> u64 {
+        120
+    }
+
+    /// A dependency on the named stack that only affects ordering
+    pub fn new(stack: &str) -> Self {
+        Self {
+            stack: stack.to_string(),
+            healthy: false,
+            service: None,
+            timeout: Self::default_timeout(),
+        }
+    }
+}
+
+impl std::fmt::Display for Dependency {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.stack.fmt(f)
+    }
+}
+
+/// The table form of a depends_on entry
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DependencyTable {
+    stack: String,
+    #[serde(default)]
+    healthy: bool,
+    #[serde(default)]
+    service: Option<String>,
+    #[serde(default = "Dependency::default_timeout")]
+    timeout: u64,
+}
+
+impl<'de> Deserialize<'de> for Dependency {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(
+            untagged,
+            expecting = "a stack name, or a table such as { stack = \"db\", healthy = true }"
+        )]
+        enum Entry {
+            Name(String),
+            Table(DependencyTable),
+        }
+
+        Ok(match Entry::deserialize(deserializer)? {
+            Entry::Name(stack) => Self::new(&stack),
+            Entry::Table(DependencyTable {
+                stack,
+                healthy,
+                service,
+                timeout,
+            }) => Self {
+                stack,
+                healthy,
+                service,
+                timeout,
+            },
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PullPolicy {
+    /// Run `docker compose pull` before starting the stack
+    Always,
+    /// Only pull images that are not present locally
+    Missing,
+    /// Never contact a registry; the images must already be present
+    Never,
+}
+
+impl PullPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Always => "always",
+            Self::Missing => "missing",
+            Self::Never => "never",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DeployMode {
+    /// Run `docker compose up` against a single docker host
+    #[default]
+    Compose,
+    /// Run `docker stack deploy` against a swarm manager
+    Swarm,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+    /// How many times to check the services before giving up
+    #[serde(default = "HealthCheck::default_retries")]
+    pub retries: u32,
+
+    /// How many seconds to wait between checks
+    #[serde(default = "HealthCheck::default_interval_seconds")]
+    pub interval_seconds: u64,
+}
+
+impl HealthCheck {
+    fn default_retries() -> u32 {
+        10
+    }
+
+    fn default_interval_seconds() -> u64 {
+        5
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RegistryLogin {
+    /// The registry, such as `ghcr.io`
+    pub registry: String,
+
+    /// The username to log in with
+    pub username: String,
+
+    /// The path of the password, or token, in the keepass db
+    pub password: String,
+}
+
+/// A wait_for entry: either the URL of an endpoint, or a table that
+/// also specifies how long to wait for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WaitFor {
+    /// A `tcp://HOST:PORT`, `http://` or `https://` URL
+    pub url: String,
+    /// How many seconds to wait for the endpoint to become reachable
+    pub timeout: u64,
+}
+
+/// How the reachability of a wait_for endpoint is checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint<'a> {
+    /// Connect to HOST:PORT
+    Tcp(&'a str),
+    /// Request the URL, which must respond with a success status
+    Http(&'a str),
+}
+
+impl WaitFor {
+    fn default_timeout() -> u64 {
+        120
+    }
+
+    pub fn endpoint(&self) -> anyhow::Result<Endpoint<'_>> {
+        if let Some(address) = self.url.strip_prefix("tcp://") {
+            let address = address.trim_end_matches('/');
+            match address.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok(Endpoint::Tcp(address))
+                }
+                _ => anyhow::bail!("{} must be of the form tcp://HOST:PORT", self.url),
+            }
+        } else if self.url.starts_with("http://") || self.url.starts_with("https://") {
+            Ok(Endpoint::Http(&self.url))
+        } else {
+            anyhow::bail!("{} must be a tcp://, http:// or https:// URL", self.url)
+        }
+    }
+}
+
+impl std::fmt::Display for WaitFor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.url.fmt(f)
+    }
+}
+
+/// The table form of a wait_for entry
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WaitForTable {
+    url: String,
+    #[serde(default = "WaitFor::default_timeout")]
+    timeout: u64,
+}
+
+/// The URL is not checked here, but by validate and when deploying, so
+/// that a mistake in one stack doesn't prevent the others from loading
+impl<'de> Deserialize<'de> for WaitFor {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(
+            untagged,
+            expecting = "a URL, or a table such as { url = \"tcp://db:5432\", timeout = 60 }"
+        )]
+        enum Entry {
+            Url(String),
+            Table(WaitForTable),
+        }
+
+        Ok(match Entry::deserialize(deserializer)? {
+            Entry::Url(url) => Self {
+                url,
+                timeout: Self::default_timeout(),
+            },
+            Entry::Table(WaitForTable { url, timeout }) => Self { url, timeout },
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ComposeFile {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl ComposeFile {
+    pub fn files(&self) -> Vec<&str> {
+        match self {
+            Self::Single(file) => vec![file.as_str()],
+            Self::Multiple(files) => files.iter().map(|f| f.as_str()).collect(),
+        }
+    }
+}
+
 impl DeployFile {}
 
+pub const DEFAULTS_FILE: &str = "stack-deploy.defaults.toml";
+
+/// Settings from the `stack-deploy.defaults.toml` file in the root
+/// of the project, which apply to every stack
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct StackDefaults {
+    /// secret_env entries that are merged into those of every stack.
+    /// An entry with the same name in a stack takes precedence.
+    #[serde(default)]
+    pub secret_env: BTreeMap<String, String>,
+}
+
+impl StackDefaults {
+    /// Load the defaults file from root.
+    /// If there is no such file, there are no defaults.
+    pub fn load(root: &Path) -> anyhow::Result<Self> {
+        let path = root.join(DEFAULTS_FILE);
+        let toml_text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).with_context(|| format!("failed to read {path:?}")),
+        };
+        let mut value: toml::Value = toml::from_str(&toml_text)
+            .with_context(|| format!("failed to parse {path:?} as toml"))?;
+        interpolate_value(&mut value)
+            .with_context(|| format!("failed to expand environment variables in {path:?}"))?;
+        value
+            .try_into()
+            .with_context(|| format!("failed to parse {path:?} as toml"))
+    }
+
+    /// Merge the defaults into deploy
+    fn apply(&self, deploy: &mut StackDeploy) {
+        for (name, path) in &self.secret_env {
+            deploy
+                .secret_env
+                .entry(name.to_string())
+                .or_insert_with(|| path.to_string());
+        }
+    }
+}
+
+pub const HOST_GROUPS_FILE: &str = "hosts.toml";
+
+/// Named groups of hosts, from the `hosts.toml` file in the root of the
+/// project, which can be referenced from runs_on as `@NAME`
+#[derive(Deserialize, Debug, Default)]
+#[serde(transparent)]
+pub struct HostGroups {
+    groups: BTreeMap<String, Vec<String>>,
+}
+
+impl HostGroups {
+    /// Load the host groups file from root.
+    /// If there is no such file, there are no groups.
+    pub fn load(root: &Path) -> anyhow::Result<Self> {
+        let path = root.join(HOST_GROUPS_FILE);
+        let toml_text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).with_context(|| format!("failed to read {path:?}")),
+        };
+        toml::from_str(&toml_text).with_context(|| format!("failed to parse {path:?} as toml"))
+    }
+
+    /// Returns true if hostname matches any of the entries in runs_on,
+    /// each of which is either a hostname pattern or a `@group`
+    /// whose members are hostname patterns
+    fn matches(&self, runs_on: &[String], hostname: &str) -> anyhow::Result<bool> {
+        for entry in runs_on {
+            let patterns = match entry.strip_prefix('@') {
+                Some(group) => self.groups.get(group).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "runs_on references {entry}, which is not defined in {HOST_GROUPS_FILE}"
+                    )
+                })?,
+                None => std::slice::from_ref(entry),
+            };
+            if patterns
+                .iter()
+                .any(|pattern| glob_matches(pattern, hostname))
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// The set of stacks that are in scope for this host, keyed by name
+pub struct StackSet {
+    pub stacks: BTreeMap<String, DeployFile>,
+    /// The stacks that were skipped because they don't run on this host
+    pub other_hosts: Vec<DeployFile>,
+    files_specified: bool,
+    order_by_path: bool,
+}
+
+/// Options that influence how the stacks are loaded
+#[derive(Default)]
+pub struct LoadOptions<'a> {
+    /// Overrides the hostname of the machine when matching
+    /// against the runs_on list of each stack
+    pub hostname: Option<&'a str>,
+    /// Keep depends_on patterns that match no stacks, so that they
+    /// are reported as problems, rather than dropping them
+    pub strict_depends_on: bool,
+    /// Overrides the docker_context of every stack
+    pub docker_context: Option<&'a str>,
+    /// Among the stacks that are otherwise equally ordered, deploy those
+    /// in shallower directories first, and then order them by path,
+    /// rather than by name
+    pub order_by_path: bool,
+}
+
 /// Load stacks from the specified root and/or list of files.
-/// The result is returned in dependency order, such that stacks that depend
-/// on others will be ordered after those dependencies.
-pub fn load_stacks(root: &str, files: &[PathBuf]) -> anyhow::Result<Vec<DeployFile>> {
-    let files_specified = !files.is_empty();
-    let files = if files.is_empty() {
-        let glob = Glob::new("**/stack-deploy.toml")?;
-        glob.walk(root)
-            .into_iter()
-            .map(|relative| Path::new(root).join(relative))
-            .collect()
+/// The result is returned in dependency order, such that stacks that
+/// depend on others will be ordered after those dependencies.
+pub fn load_stacks(
+    root: &str,
+    files: &[PathBuf],
+    opts: &LoadOptions,
+) -> anyhow::Result<Vec<DeployFile>> {
+    find_stacks(root, files, opts)?.sorted()
+}
+
+/// Returns the paths of the deploy files: files, if any were specified,
+/// or else those found by searching root, other than those that are
+/// ignored by its stackignore file
+pub fn deploy_file_paths(root: &str, files: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    if !files.is_empty() {
+        return Ok(files.to_vec());
+    }
+    let ignore = StackIgnore::load(Path::new(root))?;
+    let glob = Glob::new("**/stack-deploy.{toml,yaml,yml}")?;
+    let paths: Vec<PathBuf> = glob
+        .walk(root)
+        .into_iter()
+        .filter(|relative| {
+            if ignore.is_ignored(relative) {
+                log::info!("Skipping {relative:?} because it matches {STACK_IGNORE_FILE}");
+                false
+            } else {
+                true
+            }
+        })
+        .map(|relative| Path::new(root).join(relative))
+        .collect();
+
+    // Which deploy file to use would be ambiguous
+    let mut dirs: BTreeMap<&Path, &Path> = BTreeMap::new();
+    for path in &paths {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        if let Some(other) = dirs.insert(dir, path) {
+            anyhow::bail!("{other:?} and {path:?} are both deploy files for the same stack; remove one of them");
+        }
+    }
+    Ok(paths)
+}
+
+/// Returns true if path is a YAML deploy file, rather than TOML
+fn is_yaml(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("yaml" | "yml")
+    )
+}
+
+/// Parse text, the content of the deploy file at path, as TOML or
+/// YAML according to its extension, without interpreting it other
+/// than expanding the environment variables in its string values
+pub fn parse_deploy_value(path: &Path, text: &str) -> anyhow::Result<toml::Value> {
+    let mut value = if is_yaml(path) {
+        crate::yaml::parse(text).with_context(|| format!("failed to parse {path:?} as yaml"))
     } else {
-        files.to_vec()
+        toml::from_str(text).with_context(|| format!("failed to parse {path:?} as toml"))
+    }?;
+    interpolate_value(&mut value)
+        .with_context(|| format!("failed to expand environment variables in {path:?}"))?;
+    Ok(value)
+}
+
+/// Parse text, the content of the deploy file at path, as TOML or
+/// YAML according to its extension
+pub fn parse_deploy_file(path: &Path, text: &str) -> anyhow::Result<StackDeploy> {
+    let parsed: Result<StackDeploy, _> = parse_deploy_value(path, text)?.try_into();
+    parsed.map_err(|err| {
+        let context = match unknown_field_hint(&err) {
+            Some(hint) => format!("failed to parse {path:?}: {hint}"),
+            None if is_yaml(path) => format!("failed to parse {path:?} as yaml"),
+            None => format!("failed to parse {path:?} as toml"),
+        };
+        anyhow::Error::new(err).context(context)
+    })
+}
+
+/// Read the deploy file at path.  The environment variables that it
+/// references are expanded when it is parsed.
+pub fn read_deploy_file(path: &Path) -> anyhow::Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("failed to read {path:?}"))
+}
+
+/// Load stacks from the specified root and/or list of files,
+/// expanding their depends_on patterns, but without ordering them
+/// by their dependencies.
+pub fn find_stacks(root: &str, files: &[PathBuf], opts: &LoadOptions) -> anyhow::Result<StackSet> {
+    let files_specified = !files.is_empty();
+    let files = deploy_file_paths(root, files)?;
+    // An empty override, such as from an unset variable in a compose
+    // file, is treated as though no override was specified
+    let hostname = match opts.hostname.filter(|hostname| !hostname.is_empty()) {
+        Some(hostname) => {
+            log::info!("Using hostname {hostname} from --hostname/STACK_HOSTNAME");
+            hostname.to_string()
+        }
+        None => {
+            let hostname = gethostname::gethostname()
+                .to_str()
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| "localhost".to_string());
+            log::debug!("My hostname is {hostname}");
+            hostname
+        }
     };
-    let hostname = gethostname::gethostname()
-        .to_str()
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| "localhost".to_string());
-    println!("my hostname is {hostname}");
+
+    let defaults = StackDefaults::load(Path::new(root))?;
+    let host_groups = HostGroups::load(Path::new(root))?;
 
     let mut stacks = BTreeMap::new();
+    let mut other_hosts = vec![];
+    // Where each name was first seen, regardless of runs_on
+    let mut all_names: BTreeMap<String, PathBuf> = BTreeMap::new();
 
     for path in files {
-        let toml_text =
-            std::fs::read_to_string(&path).with_context(|| format!("failed to read {path:?}"))?;
-        let deploy: StackDeploy = toml::from_str(&toml_text)
-            .with_context(|| format!("failed to parse {path:?} as toml"))?;
-        println!("{deploy:#?}");
+        let text = read_deploy_file(&path)?;
+        let mut deploy = parse_deploy_file(&path, &text)?;
+        defaults.apply(&mut deploy);
+        log::debug!("{path:?}: {deploy:#?}");
 
-        if deploy.runs_on.contains(&hostname) {
+        match all_names.get(&deploy.name) {
+            Some(first) => {
+                log::warn!(
+                    stack = deploy.name.as_str();
+                    "{path:?} and {first:?} both define a stack named {}. \
+                    depends_on references to it may resolve differently \
+                    depending on the host",
+                    deploy.name
+                );
+            }
+            None => {
+                all_names.insert(deploy.name.clone(), path.to_path_buf());
+            }
+        }
+
+        let runs_here = host_groups
+            .matches(&deploy.runs_on, &hostname)
+            .with_context(|| format!("in {path:?}"))?;
+        if runs_here {
             anyhow::ensure!(
                 !stacks.contains_key(&deploy.name),
                 "multiple stacks have the same name {}",
                 deploy.name
             );
 
-            stacks.insert(
-                deploy.name.to_string(),
-                DeployFile {
-                    path: path.to_path_buf(),
-                    deploy,
-                },
-            );
+            let mut entry = DeployFile {
+                path: path.to_path_buf(),
+                deploy,
+            };
+            crate::deploy::add_host_compose_file(&mut entry, &hostname)
+                .with_context(|| format!("in {path:?}"))?;
+            stacks.insert(entry.deploy.name.to_string(), entry);
         } else {
             log::info!(
+                stack = deploy.name.as_str(), host = hostname.as_str();
                 "Skipping {path:?} because my hostname {hostname} is not in runs_on: {:?}",
                 deploy.runs_on
             );
+            other_hosts.push(DeployFile {
+                path: path.to_path_buf(),
+                deploy,
+            });
         }
     }
 
-    let mut graph = DiGraphMap::new();
-    for (name, entry) in stacks.iter() {
-        graph.add_node(name);
-        for dep in &entry.deploy.depends_on {
-            if !stacks.contains_key(dep) {
-                if files_specified {
-                    anyhow::bail!("{name} depends on {dep}, but {dep} is not present in any of the specified stack deploy files");
+    let mut set = StackSet {
+        stacks,
+        other_hosts,
+        files_specified,
+        order_by_path: opts.order_by_path,
+    };
+    set.expand_dependency_patterns(opts.strict_depends_on);
+    if let Some(context) = opts.docker_context {
+        set.set_docker_context(context);
+    }
+    Ok(set)
+}
+
+/// If err was caused by an unknown field, returns a description of
+/// the problem that suggests the most similar known field name
+fn unknown_field_hint(err: &toml::de::Error) -> Option<String> {
+    let message = err.message();
+    let rest = message.strip_prefix("unknown field `")?;
+    let (field, rest) = rest.split_once('`')?;
+
+    let expected = rest
+        .strip_prefix(", expected one of ")
+        .or_else(|| rest.strip_prefix(", expected "))
+        .unwrap_or_default();
+    // Only suggest names that are reasonably close to the typo,
+    // or that it is an abbreviation of
+    let threshold = (field.len() / 3).max(2);
+    let suggestion = expected
+        .split(", ")
+        .map(|name| name.trim_matches('`'))
+        .filter(|name| !name.is_empty())
+        .map(|name| (strsim::damerau_levenshtein(field, name), name))
+        .filter(|(distance, name)| *distance <= threshold || name.starts_with(field))
+        .min();
+
+    Some(match suggestion {
+        Some((_, name)) => format!("unknown field `{field}`; did you mean `{name}`?"),
+        None => format!("unknown field `{field}`"),
+    })
+}
+
+/// Returns true if text contains glob wildcards
+fn is_pattern(text: &str) -> bool {
+    text.contains(['*', '?'])
+}
+
+/// Returns true if text matches the glob pattern, in which `*` matches
+/// any sequence of characters and `?` matches any single character.
+/// A pattern without wildcards must match text exactly.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // The position in pattern after the most recent `*`, and the
+    // position in text that it has been matched up to
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    // Let the `*` consume one more character
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, t));
                 }
-                anyhow::bail!(
-                    "{name} depends on {dep}, but {dep} is not present in any stack deploy file"
-                );
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Expand the environment variables referenced by each string in value.
+/// This is done after parsing, so that references in comments, and
+/// values that happen to contain quotes, are of no consequence.
+fn interpolate_value(value: &mut toml::Value) -> anyhow::Result<()> {
+    match value {
+        toml::Value::String(text) => *text = interpolate_env(text)?,
+        toml::Value::Array(items) => {
+            for item in items {
+                interpolate_value(item)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, item) in table.iter_mut() {
+                interpolate_value(item)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` references to environment variables.
+/// It is an error to reference an undefined variable without providing
+/// a default.  `$${` produces a literal `${`.  `${secret:PATH}` placeholders
+/// are left for secret_env to resolve.
+fn interpolate_env(text: &str) -> anyhow::Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut undefined = vec![];
+    let mut rest = text;
+
+    while let Some(idx) = rest.find('$') {
+        result.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+
+        if let Some(after) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = after;
+            continue;
+        }
+
+        let Some(after) = rest.strip_prefix("${") else {
+            result.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+
+        if after.starts_with("secret:") {
+            result.push_str("${");
+            rest = after;
+            continue;
+        }
+
+        let end = after
+            .find('}')
+            .ok_or_else(|| anyhow::anyhow!("unterminated ${{ in {:?}", rest))?;
+        let reference = &after[..end];
+        rest = &after[end + 1..];
+
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+
+        // As with `docker compose`, the default is also used when
+        // the variable is set but empty
+        match (std::env::var(name), default) {
+            (Ok(value), None) => result.push_str(&value),
+            (Ok(value), Some(_)) if !value.is_empty() => result.push_str(&value),
+            (_, Some(default)) => result.push_str(default),
+            (Err(_), None) => undefined.push(name.to_string()),
+        }
+    }
+    result.push_str(rest);
+
+    anyhow::ensure!(
+        undefined.is_empty(),
+        "undefined environment variable(s): {}",
+        undefined.join(", ")
+    );
+    Ok(result)
+}
+
+impl StackSet {
+    /// Use context as the docker context of every stack, overriding
+    /// their docker_context fields
+    pub fn set_docker_context(&mut self, context: &str) {
+        for entry in self.stacks.values_mut().chain(self.other_hosts.iter_mut()) {
+            entry.deploy.docker_context = Some(context.to_string());
+        }
+    }
+
+    /// Replace each depends_on entry that is a glob pattern with the
+    /// names of the other stacks in this set that match it.
+    /// A pattern that matches nothing is logged and removed, unless
+    /// strict is true, in which case it is retained so that it will
+    /// be reported by dependency_problems.
+    pub fn expand_dependency_patterns(&mut self, strict: bool) {
+        let names: Vec<String> = self.stacks.keys().cloned().collect();
+        for (name, entry) in self.stacks.iter_mut() {
+            let mut depends_on = vec![];
+            for dep in &entry.deploy.depends_on {
+                if !is_pattern(&dep.stack) {
+                    depends_on.push(dep.clone());
+                    continue;
+                }
+                let matches: Vec<&String> = names
+                    .iter()
+                    .filter(|other| *other != name && glob_matches(&dep.stack, other))
+                    .collect();
+                if matches.is_empty() {
+                    if strict {
+                        depends_on.push(dep.clone());
+                    } else {
+                        log::warn!(
+                            stack = name.as_str();
+                            "{name} depends on {dep}, which does not match any stack"
+                        );
+                    }
+                }
+                for other in matches {
+                    if !depends_on.iter().any(|d: &Dependency| d.stack == *other) {
+                        depends_on.push(Dependency {
+                            stack: other.to_string(),
+                            ..dep.clone()
+                        });
+                    }
+                }
+            }
+            entry.deploy.depends_on = depends_on;
+        }
+    }
+
+    /// Returns a description of each depends_on entry that refers
+    /// to a stack that is not present in this set.
+    pub fn dependency_problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for (name, entry) in self.stacks.iter() {
+            for dep in &entry.deploy.depends_on {
+                if !self.stacks.contains_key(&dep.stack) {
+                    if is_pattern(&dep.stack) {
+                        problems.push(format!(
+                            "{name} depends on {dep}, which does not match any stack"
+                        ));
+                    } else if self.files_specified {
+                        problems.push(format!("{name} depends on {dep}, but {dep} is not present in any of the specified stack deploy files"));
+                    } else {
+                        problems.push(format!(
+                            "{name} depends on {dep}, but {dep} is not present in any stack deploy file"
+                        ));
+                    }
+                }
+            }
+        }
+        problems
+    }
+
+    /// Returns the graph of the stacks in this set, with an edge from
+    /// each stack to each of its dependencies
+    pub fn dependency_graph(&self) -> DiGraphMap<&str, ()> {
+        let mut graph = DiGraphMap::new();
+        for (name, entry) in self.stacks.iter() {
+            graph.add_node(name.as_str());
+            for dep in &entry.deploy.depends_on {
+                graph.add_edge(name.as_str(), dep.stack.as_str(), ());
             }
-            graph.add_edge(name, dep, ());
         }
+        graph
     }
 
-    let mut sorted = petgraph::algo::toposort(&graph, None)
-        .map_err(|err| anyhow::anyhow!("Dependency cycle detected for {}", err.node_id()))?;
+    /// Returns the stacks in dependency order, such that stacks that depend
+    /// on others will be ordered after those dependencies.
+    /// Stacks are grouped by the depth of their dependencies, and within
+    /// each depth, are ordered by their order field.
+    pub fn sorted(&self) -> anyhow::Result<Vec<DeployFile>> {
+        let problems = self.dependency_problems();
+        anyhow::ensure!(problems.is_empty(), "{}", problems.join("\n"));
 
-    // Reverse the order, so that it is sequenced from ~start to finish
+        let graph = self.dependency_graph();
+
+        let mut sorted = petgraph::algo::toposort(&graph, None)
+            .map_err(|err| anyhow::anyhow!("Dependency cycle detected for {}", err.node_id()))?;
+
+        // Reverse the order, so that it is sequenced from ~start to finish
+        sorted.reverse();
+
+        let mut result = vec![];
+        for name in sorted {
+            match self.stacks.get(name).cloned() {
+                Some(entry) => result.push(entry),
+                None if self.files_specified => {
+                    anyhow::bail!("dependency {name} was not found in the list of files provided")
+                }
+                None => {
+                    anyhow::bail!(
+                        "dependency {name} was not found in any of the stack-deploy files"
+                    )
+                }
+            }
+        }
+
+        // Since result is in dependency order, the depth of each of
+        // the dependencies of a stack is known before it is needed
+        let mut depth: BTreeMap<String, usize> = BTreeMap::new();
+        for entry in &result {
+            let d = entry
+                .deploy
+                .depends_on
+                .iter()
+                .filter_map(|dep| depth.get(&dep.stack))
+                .map(|d| d + 1)
+                .max()
+                .unwrap_or(0);
+            depth.insert(entry.deploy.name.clone(), d);
+        }
+        if self.order_by_path {
+            result.sort_by(|a, b| {
+                let key = |entry: &DeployFile| {
+                    (
+                        depth[&entry.deploy.name],
+                        entry.deploy.order,
+                        entry.path.components().count(),
+                    )
+                };
+                key(a).cmp(&key(b)).then_with(|| a.path.cmp(&b.path))
+            });
+        } else {
+            result.sort_by_key(|entry| (depth[&entry.deploy.name], entry.deploy.order));
+        }
+
+        Ok(result)
+    }
+}
+
+/// Returns the order in which to stop a dependency-ordered list of
+/// stacks: the reverse of their deploy order, so that each stack is
+/// stopped before the stacks that it depends upon
+pub fn stop_order(mut sorted: Vec<DeployFile>) -> Vec<DeployFile> {
     sorted.reverse();
+    sorted
+}
+
+/// Filter a dependency-ordered list of stacks, retaining only those whose
+/// deploy files are within scope_dir.  The ordering, which considered
+/// all of the stacks, is preserved.
+pub fn filter_scope(sorted: Vec<DeployFile>, scope_dir: &Path) -> anyhow::Result<Vec<DeployFile>> {
+    let scope_dir = scope_dir
+        .canonicalize()
+        .with_context(|| format!("failed to canonicalize {scope_dir:?}"))?;
+
+    let mut result = vec![];
+    for entry in sorted {
+        let path = entry
+            .path
+            .canonicalize()
+            .with_context(|| format!("failed to canonicalize {:?}", entry.path))?;
+        if path.starts_with(&scope_dir) {
+            result.push(entry);
+        } else {
+            log::info!(
+                stack = entry.deploy.name.as_str();
+                "Skipping {:?} because it is outside of {scope_dir:?}",
+                entry.path
+            );
+        }
+    }
+    Ok(result)
+}
 
+/// Filter a dependency-ordered list of stacks, retaining only those for
+/// which `changed` returns true, along with the stacks that depend upon
+/// them, directly or indirectly.  The ordering is preserved.
+pub fn filter_dependents(
+    sorted: Vec<DeployFile>,
+    changed: impl Fn(&DeployFile) -> bool,
+) -> Vec<DeployFile> {
+    // Since sorted is in dependency order, whether the dependencies
+    // of a stack were selected is known before the stack is considered
+    let mut selected: BTreeSet<String> = BTreeSet::new();
     let mut result = vec![];
-    for name in sorted {
-        match stacks.get(name).cloned() {
-            Some(entry) => result.push(entry),
-            None if files_specified => {
-                anyhow::bail!("dependency {name} was not found in the list of files provided")
+    for entry in sorted {
+        if changed(&entry)
+            || entry
+                .deploy
+                .depends_on
+                .iter()
+                .any(|dep| selected.contains(&dep.stack))
+        {
+            selected.insert(entry.deploy.name.clone());
+            result.push(entry);
+        } else {
+            log::info!(
+                stack = entry.deploy.name.as_str();
+                "Skipping {:?} because neither it nor its dependencies changed",
+                entry.path
+            );
+        }
+    }
+    result
+}
+
+/// Filter a dependency-ordered list of stacks by name.
+/// When `only` is non-empty, just those stacks are retained, along with
+/// the stacks they depend upon, unless `no_deps` is set.
+/// Stacks named in `skip` are then removed.
+pub fn filter_stacks(
+    sorted: Vec<DeployFile>,
+    only: &[String],
+    skip: &[String],
+    no_deps: bool,
+) -> anyhow::Result<Vec<DeployFile>> {
+    let by_name: BTreeMap<&str, &DeployFile> = sorted
+        .iter()
+        .map(|entry| (entry.deploy.name.as_str(), entry))
+        .collect();
+
+    for name in only.iter().chain(skip.iter()) {
+        anyhow::ensure!(
+            by_name.contains_key(name.as_str()),
+            "{name} is not the name of any stack that runs on this host"
+        );
+    }
+
+    let mut selected: BTreeSet<String> = BTreeSet::new();
+    if !only.is_empty() {
+        let mut queue: Vec<&str> = only.iter().map(|s| s.as_str()).collect();
+        while let Some(name) = queue.pop() {
+            if !selected.insert(name.to_string()) || no_deps {
+                continue;
             }
-            None => {
-                anyhow::bail!("dependency {name} was not found in any of the stack-deploy files")
+            if let Some(entry) = by_name.get(name) {
+                queue.extend(entry.deploy.depends_on.iter().map(|dep| dep.stack.as_str()));
             }
         }
     }
+
+    let result = sorted
+        .iter()
+        .filter(|entry| {
+            let name = &entry.deploy.name;
+            if !only.is_empty() && !selected.contains(name) {
+                log::info!(stack = name.as_str(); "Skipping {name} because it was not selected by --only");
+                return false;
+            }
+            if skip.contains(name) {
+                log::info!(stack = name.as_str(); "Skipping {name} because of --skip");
+                return false;
+            }
+            true
+        })
+        .cloned()
+        .collect();
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack_set(deploy_files: &[&str]) -> StackSet {
+        let stacks = deploy_files
+            .iter()
+            .map(|toml_text| {
+                let deploy: StackDeploy = toml::from_str(toml_text).unwrap();
+                let path = PathBuf::from(format!("{}/stack-deploy.toml", deploy.name));
+                (deploy.name.clone(), DeployFile { path, deploy })
+            })
+            .collect();
+        StackSet {
+            stacks,
+            other_hosts: vec![],
+            files_specified: false,
+            order_by_path: false,
+        }
+    }
+
+    fn names(sorted: &[DeployFile]) -> Vec<&str> {
+        sorted
+            .iter()
+            .map(|entry| entry.deploy.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn deploy_order_puts_dependencies_first() {
+        let set = stack_set(&[
+            "name = 'a'\nruns_on = ['vm']\ndepends_on = ['b']",
+            "name = 'b'\nruns_on = ['vm']",
+        ]);
+        assert_eq!(names(&set.sorted().unwrap()), ["b", "a"]);
+    }
+
+    #[test]
+    fn stop_order_puts_dependents_first() {
+        let set = stack_set(&[
+            "name = 'a'\nruns_on = ['vm']\ndepends_on = ['b']",
+            "name = 'b'\nruns_on = ['vm']",
+        ]);
+        assert_eq!(names(&stop_order(set.sorted().unwrap())), ["a", "b"]);
+    }
+
+    #[test]
+    fn order_does_not_override_dependencies() {
+        // a has the lowest order, but must still wait for b and c
+        let set = stack_set(&[
+            "name = 'a'\nruns_on = ['vm']\norder = -10\ndepends_on = ['b']",
+            "name = 'b'\nruns_on = ['vm']\ndepends_on = ['c']",
+            "name = 'c'\nruns_on = ['vm']\norder = 10",
+            "name = 'd'\nruns_on = ['vm']",
+        ]);
+        let sorted = set.sorted().unwrap();
+        assert_eq!(names(&sorted), ["d", "c", "b", "a"]);
+        assert_eq!(names(&stop_order(sorted)), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn order_by_path_puts_shallower_stacks_first() {
+        let mut set = stack_set(&[
+            "name = 'a'\nruns_on = ['vm']",
+            "name = 'b'\nruns_on = ['vm']",
+            "name = 'c'\nruns_on = ['vm']",
+            "name = 'd'\nruns_on = ['vm']\ndepends_on = ['a']",
+        ]);
+        for (name, path) in [
+            ("a", "apps/db/a/stack-deploy.toml"),
+            ("b", "infra/b/stack-deploy.toml"),
+            ("c", "apps/c/stack-deploy.toml"),
+            ("d", "d/stack-deploy.toml"),
+        ] {
+            set.stacks.get_mut(name).unwrap().path = PathBuf::from(path);
+        }
+        assert_eq!(names(&set.sorted().unwrap()), ["a", "b", "c", "d"]);
+        set.order_by_path = true;
+        assert_eq!(names(&set.sorted().unwrap()), ["c", "b", "a", "d"]);
+    }
+
+    #[test]
+    fn interpolates_values_but_not_comments() {
+        let text = r#"
+# runs_on used to be ["${STACK_DEPLOY_TEST_UNDEFINED}"]
+name = "a"
+runs_on = ["${STACK_DEPLOY_TEST_UNDEFINED:-vm}"]
+[secret_env]
+URL = "postgres://${secret:Db/pw}@db $${literal}"
+"#;
+        let deploy = parse_deploy_file(Path::new("a/stack-deploy.toml"), text).unwrap();
+        assert_eq!(deploy.runs_on, ["vm"]);
+        assert_eq!(
+            deploy.secret_env["URL"],
+            "postgres://${secret:Db/pw}@db ${literal}"
+        );
+
+        let yaml = "# ${STACK_DEPLOY_TEST_UNDEFINED}\nname: a\nruns_on: [\"${STACK_DEPLOY_TEST_UNDEFINED:-vm}\"]\n";
+        let deploy = parse_deploy_file(Path::new("a/stack-deploy.yaml"), yaml).unwrap();
+        assert_eq!(deploy.runs_on, ["vm"]);
+
+        let err = parse_deploy_file(
+            Path::new("a/stack-deploy.toml"),
+            "name = 'a'\nruns_on = ['${STACK_DEPLOY_TEST_UNDEFINED}']",
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("STACK_DEPLOY_TEST_UNDEFINED"));
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_matches("web", "web"));
+        assert!(!glob_matches("web", "web2"));
+        assert!(glob_matches("*", "anything"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("web-*", "web-1"));
+        assert!(glob_matches("web-*", "web-"));
+        assert!(!glob_matches("web-*", "db-1"));
+        assert!(glob_matches("web?", "web1"));
+        assert!(!glob_matches("web?", "web"));
+        assert!(glob_matches("a*b*c", "aXXbYYc"));
+        assert!(glob_matches("a*b*c", "abc"));
+        assert!(!glob_matches("a*b*c", "aXXbYY"));
+        assert!(glob_matches("web**", "web"));
+    }
+
+    #[test]
+    fn dependency_patterns_expand_to_other_stacks() {
+        let mut set = stack_set(&[
+            "name = 'web-1'\nruns_on = ['vm']\ndepends_on = [{ stack = 'web-*', healthy = true, service = 'db', timeout = 30 }, 'web-*']",
+            "name = 'web-2'\nruns_on = ['vm']",
+            "name = 'web-3'\nruns_on = ['vm']",
+            "name = 'app'\nruns_on = ['vm']\ndepends_on = ['db-*']",
+        ]);
+        set.expand_dependency_patterns(false);
+
+        // The pattern doesn't match web-1 itself, and each match is
+        // only listed once, with the fields of the first pattern
+        let depends_on = &set.stacks["web-1"].deploy.depends_on;
+        assert_eq!(
+            depends_on,
+            &[
+                Dependency {
+                    stack: "web-2".to_string(),
+                    healthy: true,
+                    service: Some("db".to_string()),
+                    timeout: 30,
+                },
+                Dependency {
+                    stack: "web-3".to_string(),
+                    healthy: true,
+                    service: Some("db".to_string()),
+                    timeout: 30,
+                },
+            ]
+        );
+        // A pattern that matches nothing is dropped
+        assert!(set.stacks["app"].deploy.depends_on.is_empty());
+
+        // unless strict, so that it is reported
+        let mut set = stack_set(&["name = 'app'\nruns_on = ['vm']\ndepends_on = ['db-*']"]);
+        set.expand_dependency_patterns(true);
+        assert_eq!(
+            set.stacks["app"].deploy.depends_on,
+            [Dependency::new("db-*")]
+        );
+        assert_eq!(set.dependency_problems().len(), 1);
+    }
+
+    #[test]
+    fn unknown_fields_suggest_the_closest_name() {
+        let err = toml::from_str::<StackDeploy>("name = 'a'\nruns_onn = ['vm']").unwrap_err();
+        assert_eq!(
+            unknown_field_hint(&err).unwrap(),
+            "unknown field `runs_onn`; did you mean `runs_on`?"
+        );
+
+        let err = toml::from_str::<StackDeploy>("name = 'a'\nxyzzy = 1").unwrap_err();
+        assert_eq!(unknown_field_hint(&err).unwrap(), "unknown field `xyzzy`");
+    }
+
+    #[test]
+    fn filter_stacks_by_name() {
+        let set = stack_set(&[
+            "name = 'a'\nruns_on = ['vm']\ndepends_on = ['b']",
+            "name = 'b'\nruns_on = ['vm']\ndepends_on = ['c']",
+            "name = 'c'\nruns_on = ['vm']",
+            "name = 'd'\nruns_on = ['vm']",
+        ]);
+        let sorted = set.sorted().unwrap();
+        let filter = |only: &[&str], skip: &[&str], no_deps| {
+            let only: Vec<String> = only.iter().map(|s| s.to_string()).collect();
+            let skip: Vec<String> = skip.iter().map(|s| s.to_string()).collect();
+            filter_stacks(sorted.clone(), &only, &skip, no_deps)
+        };
+
+        // --only pulls in the dependencies, transitively
+        assert_eq!(names(&filter(&["a"], &[], false).unwrap()), ["c", "b", "a"]);
+        assert_eq!(names(&filter(&["a"], &[], true).unwrap()), ["a"]);
+        // --skip wins over --only
+        assert_eq!(names(&filter(&["a"], &["b"], false).unwrap()), ["c", "a"]);
+        assert_eq!(names(&filter(&[], &["d"], false).unwrap()), ["c", "b", "a"]);
+
+        let err = filter(&["e"], &[], false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "e is not the name of any stack that runs on this host"
+        );
+    }
+
+    #[test]
+    fn runs_on_host_groups() {
+        let groups: HostGroups = toml::from_str("web = ['web-*', 'edge']\ndb = ['db1']").unwrap();
+        let runs_on = |entries: &[&str], hostname| {
+            let entries: Vec<String> = entries.iter().map(|s| s.to_string()).collect();
+            groups.matches(&entries, hostname)
+        };
+
+        assert!(runs_on(&["@web"], "web-1").unwrap());
+        assert!(runs_on(&["@web"], "edge").unwrap());
+        assert!(!runs_on(&["@web"], "db1").unwrap());
+        assert!(runs_on(&["@web", "@db"], "db1").unwrap());
+        assert!(runs_on(&["vm", "@web"], "vm").unwrap());
+
+        let err = runs_on(&["@cache"], "web-1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("runs_on references @cache, which is not defined in {HOST_GROUPS_FILE}")
+        );
+    }
+}
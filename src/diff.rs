@@ -0,0 +1,177 @@
+//! Compare the images that the compose files of each stack call for
+//! with the images of the containers that are currently running.
+use crate::deploy::{compose_command, resolve_secrets};
+use crate::deploy_file::{DeployFile, DeployMode};
+use crate::json::Value;
+use crate::runtime;
+use crate::secrets::SecretStore;
+use crate::status::{compose_ps, field};
+use crate::watchdog::TrackedCommand;
+use anyhow::Context;
+
+struct Row {
+    stack: String,
+    service: String,
+    change: String,
+}
+
+/// Returns the image id for image, if it is present locally
+fn image_id(image: &str) -> Option<String> {
+    let output = runtime::command()
+        .args(["image", "inspect", "--format", "{{.Id}}", image])
+        .tracked_output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Returns the image id that the container was created from
+fn container_image_id(container_id: &str) -> Option<String> {
+    let output = runtime::command()
+        .args(["inspect", "--format", "{{.Image}}", container_id])
+        .tracked_output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Returns the (service, image) pairs from the resolved compose
+/// configuration of the stack.  image is None for services that
+/// are built locally rather than using an image.
+fn desired_images(
+    db: &dyn SecretStore,
+    entry: &DeployFile,
+) -> anyhow::Result<Vec<(String, Option<String>)>> {
+    let name = &entry.deploy.name;
+    let secrets = resolve_secrets(db, &entry.path, &entry.deploy)?;
+    anyhow::ensure!(
+        secrets.problems.is_empty(),
+        "{}",
+        secrets.problems.join(", ")
+    );
+
+    let mut cmd = compose_command(&entry.path, &entry.deploy)?;
+    cmd.args(["config", "--format", "json"]);
+    cmd.envs(secrets.env.iter().map(|(k, v)| (k, v)));
+    let output = cmd
+        .tracked_output()
+        .with_context(|| format!("failed to run docker compose config for {name}"))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "docker compose config for {name} failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    let config = Value::parse(&String::from_utf8_lossy(&output.stdout))
+        .with_context(|| format!("failed to parse docker compose config output for {name}"))?;
+    let Some(Value::Object(services)) = config.get("services") else {
+        return Ok(vec![]);
+    };
+    Ok(services
+        .iter()
+        .map(|(service, config)| {
+            let image = config.get("image").and_then(Value::as_str);
+            (service.to_string(), image.map(|s| s.to_string()))
+        })
+        .collect())
+}
+
+fn stack_rows(db: &dyn SecretStore, entry: &DeployFile) -> anyhow::Result<Vec<Row>> {
+    let name = &entry.deploy.name;
+    anyhow::ensure!(
+        entry.deploy.mode != DeployMode::Swarm,
+        "diff is not supported for swarm stacks"
+    );
+    let desired = desired_images(db, entry)?;
+    let containers = compose_ps(&entry.path, &entry.deploy)?;
+
+    let row = |service: &str, change: String| Row {
+        stack: name.to_string(),
+        service: service.to_string(),
+        change,
+    };
+
+    let mut rows = vec![];
+    for (service, image) in &desired {
+        let container = containers
+            .iter()
+            .find(|container| field(container, "Service") == *service);
+        let (Some(container), Some(image)) = (container, image) else {
+            rows.push(match (container, image) {
+                (None, _) => row(service, "create".to_string()),
+                _ => row(service, "unknown (built locally)".to_string()),
+            });
+            continue;
+        };
+
+        let running_image = field(container, "Image");
+        if running_image != *image {
+            rows.push(row(
+                service,
+                format!("recreate (image {running_image} -> {image})"),
+            ));
+        } else if field(container, "State") != "running" {
+            rows.push(row(service, "start".to_string()));
+        } else {
+            // The tag may now refer to a newer image than the one
+            // that the container was created from
+            let current = container_image_id(&field(container, "ID"));
+            let wanted = image_id(image);
+            match (current, wanted) {
+                (Some(current), Some(wanted)) if current != wanted => {
+                    rows.push(row(service, format!("recreate (newer {image})")));
+                }
+                (_, None) => rows.push(row(service, format!("pull ({image} not present)"))),
+                _ => rows.push(row(service, "unchanged".to_string())),
+            }
+        }
+    }
+
+    for container in &containers {
+        let service = field(container, "Service");
+        if !desired.iter().any(|(s, _)| *s == service) {
+            rows.push(row(&service, "remove (orphan)".to_string()));
+        }
+    }
+
+    Ok(rows)
+}
+
+/// Print a table showing what deploying each stack would change
+pub fn show_diff(db: &dyn SecretStore, stacks: &[DeployFile]) {
+    let mut rows = vec![Row {
+        stack: "STACK".to_string(),
+        service: "SERVICE".to_string(),
+        change: "CHANGE".to_string(),
+    }];
+
+    for entry in stacks {
+        match stack_rows(db, entry) {
+            Ok(stack_rows) => rows.extend(stack_rows),
+            Err(err) => {
+                log::error!("{}: {err:#}", entry.deploy.name);
+                rows.push(Row {
+                    stack: entry.deploy.name.to_string(),
+                    service: String::new(),
+                    change: "unknown".to_string(),
+                });
+            }
+        }
+    }
+
+    let width = |f: fn(&Row) -> &str| rows.iter().map(|row| f(row).len()).max().unwrap_or(0);
+    let stack_width = width(|row| &row.stack);
+    let service_width = width(|row| &row.service);
+
+    for row in &rows {
+        let line = format!(
+            "{:stack_width$}  {:service_width$}  {}",
+            row.stack, row.service, row.change
+        );
+        println!("{}", line.trim_end());
+    }
+}
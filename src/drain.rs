@@ -0,0 +1,56 @@
+//! A marker file that puts a host into maintenance mode, during which
+//! its stacks are not deployed, such as while it is being rebooted.
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+/// Returns the path of the drain marker for root.  As with the lock
+/// file, it lives in the `.git` directory when root is a git checkout,
+/// so that it survives updates to the repo.
+pub fn marker_path(root: &Path) -> PathBuf {
+    let dot_git = root.join(".git");
+    if dot_git.is_dir() {
+        dot_git.join("stack-deploy-drained")
+    } else {
+        root.join(".stack-deploy-drained")
+    }
+}
+
+/// If root has been drained, returns the reason that was given,
+/// which may be empty
+pub fn drained(root: &Path) -> Option<String> {
+    std::fs::read_to_string(marker_path(root))
+        .ok()
+        .map(|reason| reason.trim().to_string())
+}
+
+/// Write the drain marker for root, recording the reason
+pub fn drain(root: &Path, reason: &str) -> anyhow::Result<()> {
+    let path = marker_path(root);
+    std::fs::write(&path, format!("{reason}\n"))
+        .with_context(|| format!("failed to write drain marker {path:?}"))
+}
+
+/// Remove the drain marker for root.
+/// Returns false if root was not drained.
+pub fn undrain(root: &Path) -> anyhow::Result<bool> {
+    let path = marker_path(root);
+    match std::fs::remove_file(&path) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err).with_context(|| format!("failed to remove drain marker {path:?}")),
+    }
+}
+
+/// Log why deploys from root are being skipped
+pub fn log_skipped(root: &Path, reason: &str) {
+    let reason = if reason.is_empty() {
+        String::new()
+    } else {
+        format!(" ({reason})")
+    };
+    log::warn!(
+        "Not deploying, as this host was drained{reason}. \
+        Run `docker-stack-deploy undrain --root {}` to resume deploys",
+        root.display()
+    );
+}
@@ -0,0 +1,175 @@
+//! Parsing for files of `KEY=VALUE` environment variable assignments,
+//! in the format accepted by the `env_file` option of docker compose.
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Parse the text of an env file.
+/// Blank lines and lines starting with `#` are ignored, as is a leading
+/// `export `.  Values may be enclosed in single or double quotes, which
+/// are removed.
+pub fn parse(text: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut vars = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("line {}: expected KEY=VALUE", idx + 1))?;
+        let key = key.trim();
+        anyhow::ensure!(!key.is_empty(), "line {}: empty variable name", idx + 1);
+
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|&quote| {
+                value
+                    .strip_prefix(quote)
+                    .and_then(|v| v.strip_suffix(quote))
+            })
+            .unwrap_or(value);
+
+        vars.push((key.to_string(), value.to_string()));
+    }
+    Ok(vars)
+}
+
+/// Read and parse the env file at path
+pub fn load(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("failed to read {path:?}"))?;
+    parse(&text).with_context(|| format!("failed to parse {path:?}"))
+}
+
+/// Format vars as the text of an env file that docker compose will
+/// read back verbatim.  Values are single quoted, which compose takes
+/// literally, unless they contain a single quote or a newline, in which
+/// case they are double quoted with the special characters escaped.
+pub fn format(vars: &[(String, String)]) -> String {
+    let mut text = String::new();
+    for (k, v) in vars {
+        if !v.contains(['\'', '\n']) {
+            text.push_str(&format!("{k}='{v}'\n"));
+            continue;
+        }
+        let mut quoted = String::new();
+        for c in v.chars() {
+            match c {
+                '\\' | '"' | '$' => {
+                    quoted.push('\\');
+                    quoted.push(c);
+                }
+                '\n' => quoted.push_str("\\n"),
+                c => quoted.push(c),
+            }
+        }
+        text.push_str(&format!("{k}=\"{quoted}\"\n"));
+    }
+    text
+}
+
+/// The environment of a stack, assembled from its sources
+#[derive(Debug)]
+pub struct LayeredEnv {
+    /// Each variable of env_file and secret_env, with the value from
+    /// the source with the highest precedence that defines it.  Those of
+    /// `.env` are not included, as compose loads that file itself.
+    pub vars: Vec<(String, String)>,
+    /// The names of the variables defined by both `.env` and secret_env
+    pub dot_env_conflicts: Vec<String>,
+}
+
+/// Combine the variables from the env_file of the stack and its
+/// secret_env, in increasing order of precedence, noting those of
+/// secret_env that are also defined by the `.env` file in the stack
+/// directory.
+pub fn layer(
+    dot_env: Vec<(String, String)>,
+    env_file: Vec<(String, String)>,
+    secret_env: Vec<(String, String)>,
+) -> LayeredEnv {
+    let mut vars = BTreeMap::new();
+    let mut dot_env_conflicts = vec![];
+
+    let dot_env_names: Vec<String> = dot_env.into_iter().map(|(k, _)| k).collect();
+    for (k, v) in env_file {
+        vars.insert(k, v);
+    }
+    for (k, v) in secret_env {
+        if dot_env_names.contains(&k) && !dot_env_conflicts.contains(&k) {
+            dot_env_conflicts.push(k.clone());
+        }
+        vars.insert(k, v);
+    }
+
+    LayeredEnv {
+        vars: vars.into_iter().collect(),
+        dot_env_conflicts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn precedence() {
+        let env = layer(
+            vars(&[("A", "dot"), ("B", "dot"), ("C", "dot")]),
+            vars(&[("B", "env_file"), ("C", "env_file"), ("D", "env_file")]),
+            vars(&[("C", "secret"), ("E", "secret")]),
+        );
+        assert_eq!(
+            env.vars,
+            vars(&[
+                ("B", "env_file"),
+                ("C", "secret"),
+                ("D", "env_file"),
+                ("E", "secret"),
+            ])
+        );
+        assert_eq!(env.dot_env_conflicts, vec!["C".to_string()]);
+    }
+
+    #[test]
+    fn env_file_overriding_dot_env_is_not_a_conflict() {
+        let env = layer(vars(&[("A", "dot")]), vars(&[("A", "env_file")]), vec![]);
+        assert_eq!(env.vars, vars(&[("A", "env_file")]));
+        assert!(env.dot_env_conflicts.is_empty());
+    }
+
+    #[test]
+    fn dot_env_is_left_to_compose() {
+        let env = layer(vars(&[("A", "${B}")]), vec![], vars(&[("B", "secret")]));
+        assert_eq!(env.vars, vars(&[("B", "secret")]));
+    }
+
+    #[test]
+    fn parse_quotes_and_comments() {
+        let parsed = parse("# comment\n\nexport A=1\nB=\"two words\"\nC='x'\n").unwrap();
+        assert_eq!(parsed, vars(&[("A", "1"), ("B", "two words"), ("C", "x")]));
+    }
+
+    #[test]
+    fn format_quotes_values() {
+        let text = format(&vars(&[
+            ("A", "plain $HOME"),
+            ("B", "it's"),
+            ("C", "two\nlines \\ \"q\" $x"),
+        ]));
+        assert_eq!(
+            text,
+            "A='plain $HOME'\nB=\"it's\"\nC=\"two\\nlines \\\\ \\\"q\\\" \\$x\"\n"
+        );
+    }
+}
@@ -0,0 +1,557 @@
+//! Cloning and updating the repo that `run` deploys from, and the
+//! git queries that decide what to deploy from it.
+use crate::deploy::{get_repo_commit_hash, input_fingerprint};
+use crate::watchdog::TrackedCommand;
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+/// Quote s so that it is interpreted literally by a POSIX shell
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Returns the paths, relative to the top of the repo, of the files
+/// that differ between the commits before and after
+pub(crate) fn changed_files(
+    repo_dir: &str,
+    before: &str,
+    after: &str,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let output = std::process::Command::new("git")
+        .args(["diff", "--name-only", before, after])
+        .current_dir(repo_dir)
+        .tracked_output()
+        .with_context(|| format!("failed to run git diff in {repo_dir}"))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "git diff {before} {after} failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(PathBuf::from)
+        .collect())
+}
+
+fn getenv(name: &str) -> anyhow::Result<String> {
+    std::env::var(name).with_context(|| format!("env var {name} not found"))
+}
+
+/// Read the git setting GIT_{suffix} from the environment, falling
+/// back to GITHUB_{suffix}, which is what earlier versions used.
+/// Empty values are ignored, as the deployer compose.yml passes
+/// through both names, whether or not they are set.
+fn git_env(suffix: &str) -> anyhow::Result<String> {
+    let name = format!("GIT_{suffix}");
+    [name.clone(), format!("GITHUB_{suffix}")]
+        .iter()
+        .find_map(|name| getenv(name).ok().filter(|value| !value.is_empty()))
+        .with_context(|| format!("env var {name} not found"))
+}
+
+/// How clone_or_update or check_local_dir found the repo, with the
+/// commits, or for a local directory the fingerprints, involved
+#[derive(Debug)]
+pub enum RepoUpdateStatus {
+    Cloned(String),
+    Updated { before: String, after: String },
+    Same(String),
+}
+
+impl RepoUpdateStatus {
+    pub fn updated(&self) -> bool {
+        match self {
+            Self::Cloned(_) | Self::Updated { .. } => true,
+            Self::Same(_) => false,
+        }
+    }
+}
+
+/// Check whether the files that the stacks in repo_dir are deployed
+/// from have changed since fingerprint was taken, for a repo_dir that
+/// isn't cloned from a URL, updating fingerprint to match them.
+pub fn check_local_dir(
+    repo_dir: &str,
+    fingerprint: &mut Option<String>,
+) -> anyhow::Result<RepoUpdateStatus> {
+    let current = input_fingerprint(repo_dir)?;
+    let status = match fingerprint.replace(current.clone()) {
+        Some(before) if before != current => RepoUpdateStatus::Updated {
+            before,
+            after: current,
+        },
+        _ => RepoUpdateStatus::Same(current),
+    };
+    Ok(status)
+}
+
+/// Returns true if the URL is an ssh URL, either in the explicit
+/// `ssh://` form or the scp-like `user@host:path` form
+fn is_ssh_url(repo_url: &str) -> bool {
+    if repo_url.starts_with("ssh://") {
+        return true;
+    }
+    if repo_url.contains("://") {
+        return false;
+    }
+    match (repo_url.find('@'), repo_url.find(':')) {
+        (Some(at), Some(colon)) => at < colon,
+        _ => false,
+    }
+}
+
+/// Options that influence how the repo is cloned and updated
+#[derive(Clone, Default)]
+pub struct GitOptions<'a> {
+    /// The private key to use with an ssh URL
+    pub ssh_key: Option<&'a Path>,
+    /// The token to use with an https URL, rather than $GIT_TOKEN
+    pub token: Option<String>,
+    /// Fetch and hard reset rather than pull --rebase
+    pub reset_on_conflict: bool,
+    /// The branch, tag or commit to check out, rather than the
+    /// default branch of the repo
+    pub branch: Option<&'a str>,
+    /// Limit the history that is fetched to this many commits
+    pub depth: Option<u32>,
+    /// The `--filter` to use for a partial clone, such as `blob:none`
+    pub filter: Option<&'a str>,
+    /// How long to allow each clone or fetch to run
+    pub timeout: Option<std::time::Duration>,
+}
+
+impl GitOptions<'_> {
+    /// Whether to update by resetting to the upstream branch rather
+    /// than rebasing onto it.  A rebase needs the history that
+    /// connects the two, which a shallow clone may not have.
+    fn reset_to_upstream(&self) -> bool {
+        self.reset_on_conflict || self.depth.is_some()
+    }
+
+    /// The `--depth` argument for a clone or fetch, if any
+    fn depth_arg(&self) -> Option<String> {
+        self.depth.map(|depth| format!("--depth={depth}"))
+    }
+}
+
+/// Prepare a git command that is configured to authenticate with
+/// the remote repo
+fn git_command(repo_url: &str, opts: &GitOptions) -> anyhow::Result<std::process::Command> {
+    let mut cmd = std::process::Command::new("git");
+    if is_ssh_url(repo_url) {
+        // Authentication is handled by ssh rather than the credential helper.
+        // git runs GIT_SSH_COMMAND with the shell, so the path is quoted.
+        if let Some(key) = opts.ssh_key {
+            cmd.env(
+                "GIT_SSH_COMMAND",
+                format!(
+                    "ssh -i {} -o IdentitiesOnly=yes",
+                    shell_quote(&key.to_string_lossy())
+                ),
+            );
+        }
+    } else {
+        let username = git_env("USERNAME")?;
+        let password = match &opts.token {
+            Some(token) => token.clone(),
+            None => git_env("TOKEN")?,
+        };
+
+        // We want to avoid baking the PAT from the time we clone the repo
+        // into the repo so that we can update the token over time.
+        // These ad-hoc config overrides facilitate passing in the creds
+        // <https://stackoverflow.com/a/77199818/149111>
+        cmd.args(["-c", &format!("credential.username={username}")]);
+        cmd.args([
+            "-c",
+            "credential.helper=!f(){ test \"$1\" = get && echo \"password=${GIT_TOKEN}\"; }; f",
+        ]);
+        cmd.env("GIT_TOKEN", password);
+    }
+    Ok(cmd)
+}
+
+/// Run a git command in repo_dir, returning an error if it fails
+fn run_git(cmd: &mut std::process::Command, repo_dir: &str) -> anyhow::Result<()> {
+    cmd.current_dir(repo_dir);
+    let status = cmd
+        .tracked_status()
+        .with_context(|| format!("failed to run git in {repo_dir}"))?;
+    anyhow::ensure!(status.success(), "exit status is {status:?}");
+    Ok(())
+}
+
+/// Run a git command that talks to the remote, such as a clone or fetch,
+/// in repo_dir.  It is killed if it runs for longer than the timeout
+/// in opts, so that a hung connection doesn't stall updates forever.
+fn run_remote_git(
+    cmd: &mut std::process::Command,
+    repo_dir: &str,
+    opts: &GitOptions,
+) -> anyhow::Result<()> {
+    let status = remote_git_status(cmd, repo_dir, opts)?;
+    anyhow::ensure!(status.success(), "exit status is {status:?}");
+    Ok(())
+}
+
+/// Run a git command that talks to the remote as run_remote_git does,
+/// returning its exit status rather than requiring it to succeed
+fn remote_git_status(
+    cmd: &mut std::process::Command,
+    repo_dir: &str,
+    opts: &GitOptions,
+) -> anyhow::Result<std::process::ExitStatus> {
+    cmd.current_dir(repo_dir);
+    let mut child = cmd
+        .tracked_spawn()
+        .with_context(|| format!("failed to run git in {repo_dir}"))?;
+    let mut wait = || -> anyhow::Result<std::process::ExitStatus> {
+        let Some(timeout) = opts.timeout else {
+            return Ok(child.wait()?);
+        };
+        let start = std::time::Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            if start.elapsed() >= timeout {
+                if let Err(err) = child.kill() {
+                    log::warn!("Failed to kill timed out git: {err:#}");
+                }
+                let _ = child.wait();
+                anyhow::bail!("git timed out after {} seconds", timeout.as_secs());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    };
+    let status = wait();
+    // Whether it exited, timed out or could not be waited for, the
+    // child no longer needs to be killed if the deadline is exceeded
+    crate::watchdog::child_exited(child.id());
+    status
+}
+
+/// Run a git command in repo_dir, returning true if it succeeds.
+/// Its output is discarded.
+fn git_succeeds(repo_dir: &str, args: &[&str]) -> bool {
+    std::process::Command::new("git")
+        .args(args)
+        .current_dir(repo_dir)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .tracked_status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// A private GNUPGHOME into which trusted keys are imported, so that
+/// only they are used to verify signatures.  It is removed when dropped.
+struct GnupgHome {
+    path: PathBuf,
+}
+
+impl GnupgHome {
+    fn import(keys: &Path) -> anyhow::Result<Self> {
+        use std::os::unix::fs::DirBuilderExt;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        // Each call gets a directory of its own, as the repos polled by
+        // `run` are verified concurrently.  One that is left over from
+        // an earlier process with the same pid is skipped, rather than
+        // removed, as it isn't ours.
+        let home = loop {
+            let path = std::env::temp_dir().join(format!(
+                "stack-deploy-gnupg-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            match std::fs::DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => break Self { path },
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => {
+                    return Err(err).with_context(|| format!("failed to create {path:?}"));
+                }
+            }
+        };
+
+        let output = std::process::Command::new("gpg")
+            .args(["--batch", "--quiet", "--import"])
+            .arg(keys)
+            .env("GNUPGHOME", &home.path)
+            .tracked_output()
+            .context("failed to run gpg --import")?;
+        anyhow::ensure!(
+            output.status.success(),
+            "failed to import {keys:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(home)
+    }
+}
+
+impl Drop for GnupgHome {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.path) {
+            log::warn!("Failed to remove {:?}: {err:#}", self.path);
+        }
+    }
+}
+
+/// Check that the HEAD commit of repo_dir has a good signature, made
+/// by one of trusted_keys if specified.  That may be an ssh allowed
+/// signers file, or a file of GPG public keys, in either armored or
+/// binary form.
+pub fn verify_head_signature(repo_dir: &str, trusted_keys: Option<&Path>) -> anyhow::Result<()> {
+    let mut cmd = std::process::Command::new("git");
+    let mut gnupg_home = None;
+    if let Some(keys) = trusted_keys {
+        let content = std::fs::read(keys).with_context(|| format!("failed to read {keys:?}"))?;
+        match std::str::from_utf8(&content) {
+            Ok(text) if !text.contains("-----BEGIN PGP PUBLIC KEY BLOCK-----") => {
+                cmd.arg("-c")
+                    .arg(format!("gpg.ssh.allowedSignersFile={}", keys.display()));
+            }
+            _ => {
+                let home = GnupgHome::import(keys)?;
+                cmd.env("GNUPGHOME", &home.path);
+                gnupg_home = Some(home);
+            }
+        }
+    }
+    cmd.args(["verify-commit", "HEAD"]);
+    cmd.current_dir(repo_dir);
+    let output = cmd
+        .tracked_output()
+        .context("failed to run git verify-commit")?;
+    drop(gnupg_home);
+    anyhow::ensure!(
+        output.status.success(),
+        "HEAD does not have a good signature from a trusted key: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+/// Log a warning if resetting the checkout to target will lose
+/// something, which is the case when the local checkout is not simply
+/// behind target, or when it has been modified
+fn warn_if_diverged(repo_dir: &str, target: &str) {
+    // The history of a shallow clone may not connect HEAD to target,
+    // even when target is a descendant of HEAD
+    let shallow = Path::new(repo_dir).join(".git/shallow").exists();
+    if (!shallow && !git_succeeds(repo_dir, &["merge-base", "--is-ancestor", "HEAD", target]))
+        || !git_succeeds(repo_dir, &["diff-index", "--quiet", "HEAD", "--"])
+    {
+        log::warn!(
+            "Local checkout {repo_dir} has diverged from {target}; \
+            resetting it to match"
+        );
+    }
+}
+
+/// Returns true if git_ref looks like an abbreviated or full commit hash.
+/// A branch or tag may also be named like this, so this is only a hint;
+/// see is_remote_ref.
+fn is_commit_hash(git_ref: &str) -> bool {
+    (7..=40).contains(&git_ref.len()) && git_ref.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Returns true if git_ref is the name of a branch or a tag in the
+/// repository at repo_url
+fn is_remote_ref(repo_url: &str, git_ref: &str, opts: &GitOptions) -> anyhow::Result<bool> {
+    let mut cmd = git_command(repo_url, opts)?;
+    cmd.args(["ls-remote", "--exit-code", "--heads", "--tags", repo_url]);
+    cmd.args([
+        format!("refs/heads/{git_ref}"),
+        format!("refs/tags/{git_ref}"),
+    ]);
+    cmd.stdout(std::process::Stdio::null());
+    let status = remote_git_status(&mut cmd, ".", opts)
+        .with_context(|| format!("failed to list the refs of {repo_url}"))?;
+    // --exit-code makes ls-remote exit with 2 when nothing matches
+    match status.code() {
+        Some(0) => Ok(true),
+        Some(2) => Ok(false),
+        _ => anyhow::bail!("failed to list the refs of {repo_url}: exit status is {status:?}"),
+    }
+}
+
+/// Fetch the upstream branch and hard reset the checkout to it
+fn fetch_and_reset(repo_url: &str, repo_dir: &str, opts: &GitOptions) -> anyhow::Result<()> {
+    let mut fetch = git_command(repo_url, opts)?;
+    fetch.arg("fetch");
+    fetch.args(opts.depth_arg());
+    run_remote_git(&mut fetch, repo_dir, opts)
+        .with_context(|| format!("failed to fetch git repo {repo_dir} from {repo_url}"))?;
+
+    warn_if_diverged(repo_dir, "@{upstream}");
+
+    let mut reset = std::process::Command::new("git");
+    reset.args(["reset", "--hard", "@{upstream}"]);
+    run_git(&mut reset, repo_dir)
+        .with_context(|| format!("failed to reset git repo {repo_dir} to its upstream branch"))
+}
+
+/// Fetch from the remote and update the checkout to git_ref, which may
+/// be the name of a branch or a tag, or a commit hash
+fn update_to_ref(
+    repo_url: &str,
+    repo_dir: &str,
+    git_ref: &str,
+    opts: &GitOptions,
+) -> anyhow::Result<()> {
+    // Tags are forced so that a tag that was moved upstream is updated
+    // here too, rather than failing the fetch
+    let mut fetch = git_command(repo_url, opts)?;
+    fetch.args(["fetch", "--tags", "--force"]);
+    fetch.args(opts.depth_arg());
+    fetch.arg("origin");
+    run_remote_git(&mut fetch, repo_dir, opts)
+        .with_context(|| format!("failed to fetch git repo {repo_dir} from {repo_url}"))?;
+
+    // A branch or tag takes precedence over a commit hash that its
+    // name happens to look like
+    let remote_branch = format!("refs/remotes/origin/{git_ref}");
+    let tag = format!("refs/tags/{git_ref}");
+    let is_branch = git_succeeds(
+        repo_dir,
+        &["rev-parse", "--verify", "--quiet", &remote_branch],
+    );
+    let is_tag = !is_branch && git_succeeds(repo_dir, &["rev-parse", "--verify", "--quiet", &tag]);
+    let target = if is_tag { tag.as_str() } else { git_ref };
+    if !is_branch && !is_tag && is_commit_hash(git_ref) {
+        fetch_commit(repo_url, repo_dir, git_ref, opts)?;
+    }
+
+    let mut steps: Vec<Vec<&str>> = vec![];
+    if is_branch {
+        if opts.reset_to_upstream() {
+            warn_if_diverged(repo_dir, &remote_branch);
+            steps.push(vec!["checkout", "--force", "-B", git_ref, &remote_branch]);
+        } else {
+            steps.push(vec!["checkout", git_ref]);
+            steps.push(vec!["rebase", &remote_branch]);
+        }
+    } else if opts.reset_to_upstream() {
+        // A tag or a commit hash
+        steps.push(vec!["checkout", "--force", "--detach", target]);
+    } else {
+        steps.push(vec!["checkout", "--detach", target]);
+    }
+
+    for step in steps {
+        let mut cmd = std::process::Command::new("git");
+        cmd.args(&step);
+        run_git(&mut cmd, repo_dir)
+            .with_context(|| format!("failed to update git repo {repo_dir} to {git_ref}"))?;
+    }
+    Ok(())
+}
+
+/// Ensure that commit is present in a shallow clone, in which it may
+/// not be reachable from the fetched branches within the depth limit
+fn fetch_commit(
+    repo_url: &str,
+    repo_dir: &str,
+    commit: &str,
+    opts: &GitOptions,
+) -> anyhow::Result<()> {
+    if opts.depth.is_none()
+        || git_succeeds(
+            repo_dir,
+            &["cat-file", "-e", &format!("{commit}^{{commit}}")],
+        )
+    {
+        return Ok(());
+    }
+    let mut fetch = git_command(repo_url, opts)?;
+    fetch.arg("fetch");
+    fetch.args(opts.depth_arg());
+    fetch.args(["origin", commit]);
+    run_remote_git(&mut fetch, repo_dir, opts)
+        .with_context(|| format!("failed to fetch {commit} from {repo_url}"))
+}
+
+/// Clone repo_url into repo_dir, or update the existing clone there,
+/// as opts directs, returning how its commit changed
+pub fn clone_or_update(
+    repo_url: &str,
+    repo_dir: &str,
+    opts: &GitOptions,
+) -> anyhow::Result<RepoUpdateStatus> {
+    let dot_git = format!("{repo_dir}/.git");
+
+    let recreate = match std::fs::metadata(&dot_git) {
+        Ok(meta) => !meta.is_dir(),
+        Err(err) => {
+            log::warn!("Error getting metadata for {dot_git}: {err:#}");
+            true
+        }
+    };
+
+    let mut hash_before = None;
+
+    if recreate {
+        if let Err(err) = std::fs::remove_dir_all(repo_dir) {
+            log::warn!("Error removing {repo_dir}: {err:#}");
+        }
+
+        let mut cmd = git_command(repo_url, opts)?;
+        cmd.arg("clone");
+        // `git clone --branch` accepts branches and tags, but not commits,
+        // which are checked out once the clone is complete
+        let commit = match opts.branch {
+            Some(git_ref)
+                if is_commit_hash(git_ref) && !is_remote_ref(repo_url, git_ref, opts)? =>
+            {
+                Some(git_ref)
+            }
+            _ => None,
+        };
+        if let Some(branch) = opts.branch.filter(|_| commit.is_none()) {
+            cmd.args(["--branch", branch]);
+        }
+        cmd.args(opts.depth_arg());
+        if let Some(filter) = opts.filter {
+            cmd.arg(format!("--filter={filter}"));
+        }
+        cmd.args([repo_url, repo_dir]);
+        if let Err(err) = run_remote_git(&mut cmd, ".", opts) {
+            // Don't leave a partial clone behind, as it would be
+            // mistaken for a complete one by the next attempt
+            let _ = std::fs::remove_dir_all(repo_dir);
+            return Err(err)
+                .with_context(|| format!("failed to clone git repo {repo_dir} from {repo_url}"));
+        }
+
+        if let Some(commit) = commit {
+            fetch_commit(repo_url, repo_dir, commit, opts)?;
+            let mut cmd = std::process::Command::new("git");
+            cmd.args(["checkout", "--detach", commit]);
+            run_git(&mut cmd, repo_dir)
+                .with_context(|| format!("failed to check out {commit} in {repo_dir}"))?;
+        }
+    } else {
+        hash_before = get_repo_commit_hash(repo_dir).ok();
+
+        if let Some(git_ref) = opts.branch {
+            update_to_ref(repo_url, repo_dir, git_ref, opts)?;
+        } else if opts.reset_to_upstream() {
+            fetch_and_reset(repo_url, repo_dir, opts)?;
+        } else {
+            let mut cmd = git_command(repo_url, opts)?;
+            cmd.args(["pull", "--rebase"]);
+            run_remote_git(&mut cmd, repo_dir, opts)
+                .with_context(|| format!("failed to update git repo {repo_dir} from {repo_url}"))?;
+        }
+    }
+
+    let hash_after = get_repo_commit_hash(repo_dir)?;
+
+    Ok(match (hash_before, hash_after) {
+        (Some(before), after) if before == after => RepoUpdateStatus::Same(after),
+        (Some(before), after) => RepoUpdateStatus::Updated { before, after },
+        (None, after) => RepoUpdateStatus::Cloned(after),
+    })
+}
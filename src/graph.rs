@@ -0,0 +1,108 @@
+//! Rendering of the dependency graph of the stacks, for
+//! visualization with Graphviz or Mermaid.
+use crate::deploy_file::StackSet;
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT, which can be rendered with `dot -Tpng`
+    Dot,
+    /// A Mermaid flowchart, for embedding in markdown
+    Mermaid,
+}
+
+struct Node<'a> {
+    id: String,
+    name: &'a str,
+    in_scope: bool,
+}
+
+/// Print the dependency graph, with an edge from each stack to each of
+/// its dependencies.  The stacks that run on other hosts are included,
+/// but styled differently from those that are in scope for this host.
+pub fn show_graph(set: &StackSet, format: GraphFormat) {
+    let mut nodes: Vec<Node> = set
+        .stacks
+        .keys()
+        .enumerate()
+        .map(|(idx, name)| Node {
+            id: format!("s{idx}"),
+            name,
+            in_scope: true,
+        })
+        .collect();
+    nodes.extend(set.other_hosts.iter().enumerate().map(|(idx, entry)| Node {
+        id: format!("o{idx}"),
+        name: &entry.deploy.name,
+        in_scope: false,
+    }));
+
+    let in_scope_id = |name: &str| {
+        nodes
+            .iter()
+            .find(|node| node.in_scope && node.name == name)
+            .map(|node| node.id.clone())
+    };
+
+    let mut edges = vec![];
+    for (name, dep, _) in set.dependency_graph().all_edges() {
+        if let (Some(from), Some(to)) = (in_scope_id(name), in_scope_id(dep)) {
+            edges.push((from, to));
+        }
+    }
+    // Names need only be unique per host, so resolve the dependencies
+    // of the stacks from other hosts among those stacks first
+    for (idx, entry) in set.other_hosts.iter().enumerate() {
+        for dep in &entry.deploy.depends_on {
+            let to = nodes
+                .iter()
+                .find(|node| !node.in_scope && node.name == dep.stack)
+                .or_else(|| nodes.iter().find(|node| node.name == dep.stack));
+            if let Some(to) = to {
+                edges.push((format!("o{idx}"), to.id.clone()));
+            }
+        }
+    }
+
+    match format {
+        GraphFormat::Dot => {
+            println!("digraph stacks {{");
+            println!("  node [shape=box, style=filled];");
+            for node in &nodes {
+                let color = if node.in_scope {
+                    "palegreen"
+                } else {
+                    "lightgrey"
+                };
+                println!(
+                    "  {} [label=\"{}\", fillcolor={color}];",
+                    node.id,
+                    node.name.replace('\\', "\\\\").replace('"', "\\\"")
+                );
+            }
+            for (from, to) in &edges {
+                println!("  {from} -> {to};");
+            }
+            println!("}}");
+        }
+        GraphFormat::Mermaid => {
+            println!("graph TD");
+            for node in &nodes {
+                println!("  {}[\"{}\"]", node.id, node.name.replace('"', "#quot;"));
+            }
+            for (from, to) in &edges {
+                println!("  {from} --> {to}");
+            }
+            println!("  classDef inScope fill:#98fb98");
+            println!("  classDef otherHost fill:#d3d3d3");
+            for node in &nodes {
+                let class = if node.in_scope {
+                    "inScope"
+                } else {
+                    "otherHost"
+                };
+                println!("  class {} {class}", node.id);
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! The core of docker-stack-deploy, which can be embedded in other
+//! programs as well as being used by its command line interface.
+//!
+//! Stacks are found with [deploy_file::load_stacks], which returns them
+//! in dependency order, ready to be passed to a [deploy::Deployer]
+//! along with the [secrets::SecretStore] that holds their secrets:
+//!
+//! ```no_run
+//! use docker_stack_deploy::deploy::{DeployOptions, Deployer};
+//! use docker_stack_deploy::deploy_file::{load_stacks, LoadOptions};
+//! use docker_stack_deploy::secrets::KeePassDB;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let db = KeePassDB::open_with_key("infra/.secrets.kdbx", Some("passphrase"), None)?;
+//! let plan = load_stacks("infra", &[], &LoadOptions::default())?;
+//! let deployer = Deployer::new(&db, DeployOptions::default());
+//! for (entry, status) in deployer.deploy(&plan) {
+//!     println!("{}: {status:?}", entry.deploy.name);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A git checkout of the stacks is deployed as `run` does it with
+//! [deploy::Deployer::deploy_repo], and kept up to date and redeployed
+//! as it changes with [poll::poll_repo].
+mod attachments;
+mod backoff;
+pub mod bitwarden;
+pub mod deploy;
+pub mod deploy_file;
+pub mod drain;
+mod env_file;
+pub mod git;
+pub mod json;
+pub mod lock;
+pub mod metrics;
+pub mod notify;
+mod output;
+pub mod poll;
+pub mod repo;
+pub mod report;
+pub mod runtime;
+pub mod secrets;
+pub mod shutdown;
+pub mod sops;
+mod stackignore;
+mod state;
+mod totp;
+pub mod vault;
+pub mod watchdog;
+mod yaml;
+
+// The implementations of the subcommands of the command line
+// interface, which are not intended for use by other programs
+#[doc(hidden)]
+pub mod browse;
+#[doc(hidden)]
+pub mod check_secrets;
+#[doc(hidden)]
+pub mod completions;
+#[doc(hidden)]
+pub mod diff;
+#[doc(hidden)]
+pub mod graph;
+#[doc(hidden)]
+pub mod list;
+#[doc(hidden)]
+pub mod schema;
+#[doc(hidden)]
+pub mod self_update;
+#[doc(hidden)]
+pub mod status;
+#[doc(hidden)]
+pub mod watch;
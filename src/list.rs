@@ -0,0 +1,52 @@
+use crate::deploy_file::DeployFile;
+use crate::json::Value;
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    /// One line per stack
+    Text,
+    /// A JSON array with an object per stack
+    Json,
+}
+
+fn strings<T: std::fmt::Display>(items: &[T]) -> Value {
+    Value::Array(items.iter().map(|s| s.to_string().into()).collect())
+}
+
+/// Print the deploy plan.
+/// `sorted` are the stacks that would be deployed on this host, in
+/// dependency order, and `other_hosts` are stacks that are out of scope
+/// for this host, which are listed after them.
+pub fn show_list(sorted: &[DeployFile], other_hosts: &[DeployFile], format: ListFormat) {
+    let plan = sorted
+        .iter()
+        .map(|entry| (entry, true))
+        .chain(other_hosts.iter().map(|entry| (entry, false)));
+
+    match format {
+        ListFormat::Text => {
+            for (entry, in_scope) in plan {
+                let note = if in_scope { "" } else { " (other host)" };
+                println!("{} {}{note}", entry.deploy.name, entry.path.display());
+            }
+        }
+        ListFormat::Json => {
+            let stacks = plan
+                .map(|(entry, in_scope)| {
+                    Value::Object(vec![
+                        ("name".to_string(), entry.deploy.name.as_str().into()),
+                        (
+                            "path".to_string(),
+                            entry.path.to_string_lossy().to_string().into(),
+                        ),
+                        ("depends_on".to_string(), strings(&entry.deploy.depends_on)),
+                        ("runs_on".to_string(), strings(&entry.deploy.runs_on)),
+                        ("in_scope".to_string(), Value::Bool(in_scope)),
+                    ])
+                })
+                .collect();
+            println!("{}", Value::Array(stacks));
+        }
+    }
+}
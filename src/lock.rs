@@ -0,0 +1,59 @@
+//! A lock file that prevents concurrent deploys from the same repo,
+//! such as a manual `stack-deploy` racing with the `run` poller.
+use anyhow::Context;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Holds the lock until it is dropped
+pub struct DeployLock {
+    _file: std::fs::File,
+}
+
+/// Returns the path of the lock file for root.  It lives in the `.git`
+/// directory when root is a git checkout, so that it doesn't appear
+/// as an untracked file.
+fn lock_path(root: &Path) -> PathBuf {
+    let dot_git = root.join(".git");
+    if dot_git.is_dir() {
+        dot_git.join("stack-deploy.lock")
+    } else {
+        root.join(".stack-deploy.lock")
+    }
+}
+
+/// Acquire the deploy lock for root, waiting up to timeout for
+/// another deploy to release it
+pub fn acquire(root: &Path, timeout: Duration) -> anyhow::Result<DeployLock> {
+    let path = lock_path(root);
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("failed to open lock file {path:?}"))?;
+
+    let start = Instant::now();
+    let mut logged = false;
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(DeployLock { _file: file });
+        }
+
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(err).with_context(|| format!("failed to lock {path:?}"));
+        }
+
+        anyhow::ensure!(
+            start.elapsed() < timeout,
+            "timed out after {} seconds waiting for another deploy to release {path:?}",
+            timeout.as_secs()
+        );
+        if !logged {
+            log::info!("Waiting for another deploy to release {path:?}");
+            logged = true;
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
@@ -1,40 +1,254 @@
-use crate::deploy_file::*;
-use crate::secrets::*;
 use anyhow::Context;
-use clap::Parser;
+use clap::{CommandFactory, Parser, ValueEnum};
+use docker_stack_deploy::bitwarden::BitwardenStore;
+use docker_stack_deploy::deploy::*;
+use docker_stack_deploy::deploy_file::*;
+use docker_stack_deploy::git::shell_quote;
+use docker_stack_deploy::secrets::*;
+use docker_stack_deploy::watchdog::TrackedCommand;
+use docker_stack_deploy::{
+    browse, check_secrets, completions, diff, drain, git, graph, json, list, lock, metrics, notify,
+    poll, repo, report, runtime, schema, self_update, shutdown, sops, status, vault, watch,
+    watchdog,
+};
 use log::LevelFilter;
 use std::path::{Path, PathBuf};
 
-mod deploy_file;
-mod secrets;
-
 #[derive(Parser)]
 struct Args {
-    /// Path to a KeePass .kdbx file containing secrets
+    /// Path to a KeePass .kdbx file containing secrets.
+    /// Can be used multiple times, in which case the first element
+    /// of a secret path selects the database with that root group name
     #[arg(long)]
-    kdbx: Option<String>,
+    kdbx: Vec<String>,
 
     /// Password that can be used to decrypt the kdbx file
     #[arg(long)]
     password: Option<String>,
 
+    /// Path to a file containing the password that can be used to
+    /// decrypt the kdbx file.  It is read each time the kdbx file is
+    /// opened, so that `run` picks up a rotated password without
+    /// needing to be restarted.
+    #[arg(long, env = "STACK_KDBX_PASS_FILE")]
+    password_file: Option<String>,
+
+    /// Path to a KeePass key file that is part of the composite key
+    /// used to decrypt the kdbx file
+    #[arg(long)]
+    keyfile: Option<PathBuf>,
+
+    /// The age identity file used to decrypt the `secret.env.age`
+    /// files of stacks
+    #[arg(long, env = "STACK_AGE_IDENTITY")]
+    age_identity: Option<PathBuf>,
+
+    /// Which secret store to use
+    #[arg(long, value_enum, default_value_t = SecretBackend::Keepass)]
+    secret_backend: SecretBackend,
+
+    /// Session key for the Bitwarden CLI.
+    /// If not specified, $BW_SESSION will be used, or the vault
+    /// will be unlocked using the password from $BW_PASSWORD
+    #[arg(long)]
+    bw_session: Option<String>,
+
+    /// Path to the sops-encrypted file containing secrets
+    #[arg(long)]
+    sops_file: Option<PathBuf>,
+
+    /// Path to the age key file used to decrypt the sops file.
+    /// If not specified, sops will use its usual configuration,
+    /// such as $SOPS_AGE_KEY_FILE
+    #[arg(long)]
+    age_key_file: Option<PathBuf>,
+
+    /// The address of the Vault server
+    #[arg(long, env = "VAULT_ADDR")]
+    vault_addr: Option<String>,
+
+    /// The token to use to authenticate with Vault
+    #[arg(long, env = "VAULT_TOKEN", hide_env_values = true)]
+    vault_token: Option<String>,
+
+    /// The AppRole role id to use to log in to Vault, when no
+    /// token is specified
+    #[arg(long, env = "VAULT_ROLE_ID")]
+    vault_role_id: Option<String>,
+
+    /// The AppRole secret id to use to log in to Vault, when no
+    /// token is specified
+    #[arg(long, env = "VAULT_SECRET_ID", hide_env_values = true)]
+    vault_secret_id: Option<String>,
+
     /// Prompt for missing information
     #[arg(long)]
     interactive: bool,
 
+    /// URL to which a JSON summary will be POSTed after deploying
+    #[arg(long)]
+    notify_webhook: Option<String>,
+
+    /// Path of a file to which a JSON report of each deploy is written,
+    /// replacing the report of the previous deploy
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// How to format log output
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Only log warnings and errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Also log debugging information, such as the parsed deploy files
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// The hostname to match against the runs_on list of the stacks,
+    /// instead of the hostname of the machine.  Useful when running in a
+    /// container, whose hostname is not that of the docker host.
+    #[arg(long, env = "STACK_HOSTNAME")]
+    hostname: Option<String>,
+
+    /// How many seconds to wait for another deploy from the same
+    /// repo to finish before giving up
+    #[arg(long, default_value = "600")]
+    lock_timeout: u64,
+
+    /// Treat a depends_on pattern that doesn't match any stack as an
+    /// error, rather than logging a warning
+    #[arg(long)]
+    strict_depends_on: bool,
+
+    /// Among the stacks whose position isn't decided by depends_on and
+    /// order, deploy those in shallower directories first, and then in
+    /// order of their paths, rather than of their names
+    #[arg(long, env = "STACK_ORDER_BY_PATH")]
+    order_by_path: bool,
+
+    /// The docker context to use for every stack, overriding the
+    /// docker_context field of their deploy files
+    #[arg(long)]
+    docker_context: Option<String>,
+
+    /// Pass the environment of each stack to docker compose via a
+    /// temporary env file, which is removed once compose has run,
+    /// rather than via its environment, where it can be read
+    /// from /proc by other processes
+    #[arg(long, env = "STACK_SECURE_SECRETS")]
+    secure_secrets: bool,
+
+    /// Don't pass --wait to docker compose up, so that stacks are
+    /// considered deployed as soon as their containers are started,
+    /// without verifying that they are healthy
+    #[arg(long)]
+    no_wait: bool,
+
+    /// Run docker compose config for each stack before bringing it up,
+    /// so that a malformed compose file, or one that interpolates a
+    /// variable that isn't set, fails the deploy of that stack
+    #[arg(long)]
+    check_config: bool,
+
+    /// Label every container that is deployed with the name of its
+    /// stack, as com.stackdeploy.name, and the commit of the repo
+    /// from which it was deployed, as com.stackdeploy.commit
+    #[arg(long, env = "STACK_STAMP_LABELS")]
+    stamp_labels: bool,
+
+    /// The longest, in seconds, that a deploy may take as a whole,
+    /// including waiting for the lock.  When it is exceeded, the
+    /// remaining stacks are abandoned, the docker commands that are
+    /// still running are killed, and the process exits with status 124.
+    /// For `run`, this applies to each deploy in turn, and exceeding it
+    /// fails that deploy rather than exiting.
+    #[arg(long, value_name = "SECONDS", env = "STACK_MAX_RUNTIME")]
+    max_runtime: Option<u64>,
+
+    /// The container runtime whose compose implementation is used
+    #[arg(long, value_enum, env = "DOCKER_STACK_RUNTIME", default_value_t = runtime::Runtime::Docker)]
+    runtime: runtime::Runtime,
+
     #[command(subcommand)]
     cmd: Command,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SecretBackend {
+    /// A KeePass database
+    Keepass,
+    /// A Bitwarden or Vaultwarden vault, via the bw CLI
+    Bitwarden,
+    /// A sops-encrypted YAML, JSON or dotenv file, via the sops CLI
+    Sops,
+    /// The KV v2 secrets engine of HashiCorp Vault
+    Vault,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human readable text
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SecretFormat {
+    /// Print each value on its own line
+    Raw,
+    /// Print `export NAME=value` lines, suitable for use with `eval`.
+    /// Each path must be given in the form NAME=PATH
+    Export,
+}
+
 #[derive(Parser)]
 enum Command {
     GetSecret {
-        path: String,
+        /// The paths of the secrets to print
+        #[arg(required = true)]
+        paths: Vec<String>,
+
+        #[arg(long, value_enum, default_value = "raw")]
+        format: SecretFormat,
+    },
+    /// Print `export NAME=value` lines for each NAME=PATH, suitable
+    /// for use with `eval`.  This is equivalent to
+    /// `get-secret --format export`
+    GetEnv {
+        #[arg(required = true)]
+        vars: Vec<String>,
+    },
+    /// Discover the paths of the secrets in the KeePass databases.
+    /// With --interactive, navigate the groups and entries, and print
+    /// the path of the chosen field.
+    BrowseSecrets {
+        /// Print every group/.../entry/field path, rather than
+        /// navigating interactively
+        #[arg(long)]
+        list_paths: bool,
+    },
+    /// Resolve each secret_env, secret_file and registry_login secret of
+    /// the specified deploy files, and print whether it could be found,
+    /// without printing its value.  Exits with a non-zero status if any
+    /// could not be found.
+    CheckSecrets {
+        /// The root of the project, whose defaults file applies to
+        /// the deploy files
+        #[arg(long, default_value = ".")]
+        root: String,
+
+        /// The deploy files to check.  Can be used multiple times.
+        /// They are checked whichever hosts their stacks run on.
+        #[arg(long = "file", required = true)]
+        files: Vec<PathBuf>,
     },
     StackDeploy {
         /// Path to the root of the project.
         /// This path will be recursively searched for stack-deploy.toml
-        /// files
+        /// (or .yaml) files
         #[arg(long, default_value = ".")]
         root: String,
 
@@ -42,11 +256,67 @@ enum Command {
         /// Can be used multiple times
         #[arg(long = "file")]
         files: Vec<PathBuf>,
+
+        /// How many independent stacks may be deployed concurrently.
+        /// Stacks whose dependencies fail to deploy are skipped
+        /// when this is greater than 1.
+        #[arg(long, default_value = "1")]
+        parallel: usize,
+
+        /// Show the stacks and docker compose commands that would
+        /// be used, without running docker
+        #[arg(long)]
+        dry_run: bool,
+
+        /// If any stack fails to deploy, stop deploying and
+        /// run docker compose down for the stacks that were
+        /// deployed earlier in this run, in reverse order
+        #[arg(long)]
+        rollback_on_failure: bool,
+
+        /// If any stack fails to deploy, stop deploying the
+        /// remaining stacks
+        #[arg(long)]
+        fail_fast: bool,
+
+        /// Only deploy the named stack, along with the stacks that it
+        /// depends upon.  Can be used multiple times
+        #[arg(long)]
+        only: Vec<String>,
+
+        /// Don't deploy the named stack.  Can be used multiple times
+        #[arg(long)]
+        skip: Vec<String>,
+
+        /// When used together with --only, don't also deploy
+        /// the dependencies of the named stacks
+        #[arg(long)]
+        no_deps: bool,
+
+        /// Only act on the stacks whose deploy files are within this
+        /// directory.  All of the stacks under --root are still
+        /// considered when resolving dependencies.
+        #[arg(long)]
+        scope_dir: Option<PathBuf>,
+
+        /// Deploy the service with the specified image, rather than the
+        /// one in its compose file, such as `--set-image web=nginx:1.27`.
+        /// The override only applies to this deploy, and requires that
+        /// a single stack is being deployed.  Can be used multiple times
+        #[arg(long, value_name = "SERVICE=IMAGE", value_parser = parse_set_image)]
+        set_image: Vec<(String, String)>,
+
+        /// Rather than deploying, resolve the secrets of each of the
+        /// selected stacks and print those that could not be resolved,
+        /// grouped by stack.  Exits with a non-zero status if any are
+        /// missing.  docker is not run.
+        #[arg(long, conflicts_with_all = ["dry_run", "set_image"])]
+        list_missing_secrets: bool,
     },
     StackStop {
         /// Path to the root of the project.
         /// This path will be recursively searched for stack-deploy.toml
-        /// files
+        /// (or .yaml) files
         #[arg(long, default_value = ".")]
         root: String,
 
@@ -54,19 +324,303 @@ enum Command {
         /// Can be used multiple times
         #[arg(long = "file")]
         files: Vec<PathBuf>,
+
+        /// Only act on the stacks whose deploy files are within this
+        /// directory.  All of the stacks under --root are still
+        /// considered when resolving dependencies.
+        #[arg(long)]
+        scope_dir: Option<PathBuf>,
     },
-    Run {
-        /// Local path into which the repo should be cloned
+    /// Show the state of the containers of each stack
+    Status {
+        /// Path to the root of the project.
+        /// This path will be recursively searched for stack-deploy.toml
+        /// (or .yaml) files
+        #[arg(long, default_value = ".")]
+        root: String,
+
+        /// Instead of searching for a deploy file, specify its path.
+        /// Can be used multiple times
+        #[arg(long = "file")]
+        files: Vec<PathBuf>,
+    },
+    /// Show the logs of the containers of a stack
+    Logs {
+        /// The name of the stack
+        name: String,
+
+        /// Path to the root of the project.
+        /// This path will be recursively searched for stack-deploy.toml
+        /// (or .yaml) files
+        #[arg(long, default_value = ".")]
+        root: String,
+
+        /// Instead of searching for a deploy file, specify its path.
+        /// Can be used multiple times
+        #[arg(long = "file")]
+        files: Vec<PathBuf>,
+
+        /// Keep following the log output
+        #[arg(long, short)]
+        follow: bool,
+
+        /// How many lines to show from the end of the logs of each container
         #[arg(long)]
-        repo_dir: String,
+        tail: Option<usize>,
+    },
+    /// Restart the containers of a stack, without deploying it
+    Restart {
+        /// The name of the stack
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        name: Option<String>,
 
-        /// URL from which the repo should be cloned
+        /// Restart every stack that runs on this host, in dependency order
         #[arg(long)]
-        repo_url: String,
+        all: bool,
+
+        /// Path to the root of the project.
+        /// This path will be recursively searched for stack-deploy.toml
+        /// (or .yaml) files
+        #[arg(long, default_value = ".")]
+        root: String,
+
+        /// Instead of searching for a deploy file, specify its path.
+        /// Can be used multiple times
+        #[arg(long = "file")]
+        files: Vec<PathBuf>,
+    },
+    /// Put this host into maintenance mode: stop all of the stacks
+    /// that run on it, and skip deploying them until `undrain` is run
+    Drain {
+        /// Path to the root of the project.
+        /// This path will be recursively searched for stack-deploy.toml
+        /// (or .yaml) files
+        #[arg(long, default_value = ".")]
+        root: String,
+
+        /// Instead of searching for a deploy file, specify its path.
+        /// Can be used multiple times
+        #[arg(long = "file")]
+        files: Vec<PathBuf>,
+
+        /// Why the host is being drained, which is included in the
+        /// messages logged while deploys are skipped
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Take this host out of maintenance mode, so that its stacks
+    /// are deployed again
+    Undrain {
+        /// Path to the root of the project
+        #[arg(long, default_value = ".")]
+        root: String,
+    },
+    /// Deploy the stacks from a local directory, and then watch it,
+    /// redeploying each stack whenever its deploy file, compose files
+    /// or env files change.  This is intended for local development,
+    /// without needing to commit and push each change.
+    Watch {
+        /// Path to the root of the project.
+        /// This path will be recursively searched for stack-deploy.toml
+        /// (or .yaml) files
+        #[arg(long, default_value = ".")]
+        root: String,
+    },
+    /// Print the dependency graph of the stacks, including those
+    /// that run on other hosts
+    Graph {
+        /// Path to the root of the project.
+        /// This path will be recursively searched for stack-deploy.toml
+        /// (or .yaml) files
+        #[arg(long, default_value = ".")]
+        root: String,
+
+        /// Instead of searching for a deploy file, specify its path.
+        /// Can be used multiple times
+        #[arg(long = "file")]
+        files: Vec<PathBuf>,
+
+        #[arg(long, value_enum, default_value_t = graph::GraphFormat::Dot)]
+        format: graph::GraphFormat,
+    },
+    /// Print the stacks in the order that they would be deployed
+    List {
+        /// Path to the root of the project.
+        /// This path will be recursively searched for stack-deploy.toml
+        /// (or .yaml) files
+        #[arg(long, default_value = ".")]
+        root: String,
+
+        /// Instead of searching for a deploy file, specify its path.
+        /// Can be used multiple times
+        #[arg(long = "file")]
+        files: Vec<PathBuf>,
+
+        #[arg(long, value_enum, default_value = "text")]
+        format: list::ListFormat,
+
+        /// Also list the stacks that don't run on this host
+        #[arg(long)]
+        all_hosts: bool,
+    },
+    /// Show which services would be created, recreated or removed
+    /// by deploying, by comparing the images in the compose files
+    /// with those of the running containers
+    Diff {
+        /// Path to the root of the project.
+        /// This path will be recursively searched for stack-deploy.toml
+        /// (or .yaml) files
+        #[arg(long, default_value = ".")]
+        root: String,
+
+        /// Instead of searching for a deploy file, specify its path.
+        /// Can be used multiple times
+        #[arg(long = "file")]
+        files: Vec<PathBuf>,
+    },
+    /// Show the commit from which each stack was last deployed, and
+    /// whether that is the current commit of the repo
+    Versions {
+        /// Path to the root of the project, which must be the top
+        /// of a git checkout
+        #[arg(long, default_value = ".")]
+        root: String,
+    },
+    /// Check the deploy files, their secrets and their compose files
+    /// without deploying anything
+    Validate {
+        /// Path to the root of the project.
+        /// This path will be recursively searched for stack-deploy.toml
+        /// (or .yaml) files
+        #[arg(long, default_value = ".")]
+        root: String,
+
+        /// Instead of searching for a deploy file, specify its path.
+        /// Can be used multiple times
+        #[arg(long = "file")]
+        files: Vec<PathBuf>,
+
+        /// Only check that each deploy file matches the schema,
+        /// without resolving secrets or running docker.  Every deploy
+        /// file is checked, whichever hosts its stack runs on.
+        #[arg(long)]
+        schema_only: bool,
+    },
+    /// Print the JSON Schema of the stack-deploy.toml file, for use
+    /// with editors that can complete and check TOML against a schema
+    Schema,
+    Run {
+        /// Local path into which the repo should be cloned.
+        /// Can be used multiple times, once for each --repo-url
+        #[arg(long, required = true)]
+        repo_dir: Vec<String>,
+
+        /// URL from which the repo should be cloned.
+        /// Can be used multiple times to deploy from several repos,
+        /// each of which is polled independently.  When omitted, each
+        /// --repo-dir is used as it is, such as when it is kept up to
+        /// date by rsync, and its stacks are deployed whenever the
+        /// files that they are deployed from change.
+        #[arg(long)]
+        repo_url: Vec<String>,
 
         /// How many seconds to wait between checking the repo for updates
         #[arg(long, default_value = "300")]
         poll_interval: u64,
+
+        /// When using an ssh git URL, the private key that should be
+        /// used to authenticate.  If not specified, any GIT_SSH_COMMAND
+        /// in the environment will be respected.
+        #[arg(long)]
+        ssh_key: Option<PathBuf>,
+
+        /// Path to a secret, in the secrets file of the checked out repo,
+        /// that holds the token to use when pulling the repo.  This allows
+        /// the token to be rotated via the repo itself.  $GIT_TOKEN is
+        /// used if the repo has not been cloned yet, or if the secret
+        /// cannot be resolved.
+        #[arg(long)]
+        git_token_secret: Option<String>,
+
+        /// Rather than `git pull --rebase`, fetch and then hard reset
+        /// to the upstream branch, discarding any local changes or
+        /// commits.  This allows recovering from conflicts and force
+        /// pushes without intervention.
+        #[arg(long)]
+        git_reset_on_conflict: bool,
+
+        /// The branch, tag or commit of the repo to deploy, rather
+        /// than its default branch
+        #[arg(long)]
+        branch: Option<String>,
+
+        /// Make a shallow clone of the repo, with only this many commits
+        /// of history, and fetch only that much when updating it.
+        /// Updates hard reset to the upstream branch, as with
+        /// --git-reset-on-conflict, since rebasing needs the full history.
+        #[arg(long)]
+        git_depth: Option<u32>,
+
+        /// Make a partial clone of the repo using this filter, such as
+        /// `blob:none`, which fetches the content of files as they
+        /// are checked out, rather than that of every version
+        #[arg(long)]
+        git_filter: Option<String>,
+
+        /// How many seconds to allow each clone, fetch or pull to take
+        /// before killing it.  The default is to wait indefinitely.
+        #[arg(long)]
+        git_timeout: Option<u64>,
+
+        /// Run docker compose down for stacks that were deployed
+        /// by an earlier run, but that have since been removed from
+        /// the repo, or that no longer run on this host
+        #[arg(long)]
+        prune_removed: bool,
+
+        /// When the repo is updated, only deploy the stacks whose
+        /// directories contain files that changed, the stacks that
+        /// depend upon them, and any that were not deployed successfully
+        /// from the previous commit.  Every stack is deployed on the
+        /// first run.
+        #[arg(long)]
+        changed_only: bool,
+
+        /// Only deploy a commit of the repo if it has a good signature.
+        /// An unsigned commit, or one signed by an untrusted key, is
+        /// logged as an error and is not deployed.
+        #[arg(long)]
+        require_signed_commits: bool,
+
+        /// The keys that are trusted to sign commits: either a file of
+        /// GPG public keys, or an ssh allowed signers file.  Defaults
+        /// to the GPG keyring of the user and the git configuration.
+        #[arg(long, requires = "require_signed_commits")]
+        trusted_keys: Option<PathBuf>,
+
+        /// Address, such as 0.0.0.0:9100, on which to serve metrics
+        /// about the deploys in the Prometheus text format
+        #[arg(long)]
+        metrics_addr: Option<String>,
+
+        /// After a deploy fails, or the repo fails to update, wait this
+        /// many times longer before each successive retry.  A failed
+        /// deploy is first retried after --poll-interval seconds, unless
+        /// the repo is updated sooner.
+        #[arg(long, default_value = "2")]
+        backoff_factor: f64,
+
+        /// The longest that the backoff may grow to, in seconds
+        #[arg(long, default_value = "3600")]
+        max_backoff: u64,
+
+        /// Keep the secrets that were opened for a deploy in memory for
+        /// this many seconds, and reuse them for later deploys, rather
+        /// than opening them again on every deploy.  They are reopened
+        /// sooner if the secrets file is modified.  The default of 0
+        /// opens them for every deploy.
+        #[arg(long, default_value = "0", env = "STACK_SECRET_CACHE_TTL")]
+        secret_cache_ttl: u64,
     },
     Bootstrap {
         /// Where to place the compose.yml and .env
@@ -84,184 +638,907 @@ enum Command {
         /// How many seconds between git pulls
         #[arg(long, default_value = "300")]
         poll_interval: u32,
+
+        /// The branch, tag or commit of the repo to deploy, rather
+        /// than its default branch
+        #[arg(long)]
+        branch: Option<String>,
+    },
+    /// Replace this binary with the one from the latest GitHub release,
+    /// if that is newer, after verifying its sha256 checksum
+    SelfUpdate {
+        /// Only report whether a newer release is available
+        #[arg(long)]
+        check_only: bool,
+    },
+    /// Print a completion script for the specified shell
+    Completions {
+        #[arg(value_enum)]
+        shell: completions::Shell,
     },
 }
 
 impl Args {
+    /// Open the configured secret store if any of stacks set
+    /// secrets_on_down, as bringing them down requires their secrets
+    fn open_secrets_for_down(
+        &self,
+        stacks: &[DeployFile],
+    ) -> anyhow::Result<Option<Box<dyn SecretStore + Send>>> {
+        if stacks.iter().any(|entry| entry.deploy.secrets_on_down) {
+            self.open_secret_store(None).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Open the configured secret store.
+    /// For the keepass backend, `kdbx_path` overrides the `--kdbx` option.
+    fn open_secret_store(
+        &self,
+        kdbx_path: Option<&str>,
+    ) -> anyhow::Result<Box<dyn SecretStore + Send>> {
+        match self.secret_backend {
+            SecretBackend::Keepass => match kdbx_path {
+                Some(path) => Ok(Box::new(self.open_kdbx_path(path)?)),
+                None => Ok(Box::new(self.open_kdbx()?)),
+            },
+            SecretBackend::Bitwarden => {
+                let session = self
+                    .bw_session
+                    .clone()
+                    .or_else(|| std::env::var("BW_SESSION").ok());
+                let password = if session.is_some() {
+                    None
+                } else if let Ok(s) = std::env::var("BW_PASSWORD") {
+                    Some(s)
+                } else if self.interactive {
+                    Some(rpassword::prompt_password("Bitwarden Master Password:")?)
+                } else {
+                    None
+                };
+                Ok(Box::new(BitwardenStore::open(session, password)?))
+            }
+            SecretBackend::Sops => {
+                let file = self
+                    .sops_file
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("no --sops-file was specified"))?;
+                Ok(Box::new(sops::SopsStore::open(
+                    file,
+                    self.age_key_file.as_deref(),
+                )?))
+            }
+            SecretBackend::Vault => {
+                let addr = self.vault_addr.as_deref().ok_or_else(|| {
+                    anyhow::anyhow!("no --vault-addr or $VAULT_ADDR was specified")
+                })?;
+                let approle = self.vault_role_id.clone().zip(self.vault_secret_id.clone());
+                Ok(Box::new(vault::VaultStore::open(
+                    addr,
+                    self.vault_token.clone(),
+                    approle,
+                )?))
+            }
+        }
+    }
+
     fn open_kdbx(&self) -> anyhow::Result<KeePassDB> {
-        let kdbx = self
-            .kdbx
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("no --kdbx file was specified"))?;
-        self.open_kdbx_path(&kdbx)
+        anyhow::ensure!(!self.kdbx.is_empty(), "no --kdbx file was specified");
+        self.open_kdbx_paths(&self.kdbx)
     }
 
     fn open_kdbx_path(&self, path: &str) -> anyhow::Result<KeePassDB> {
-        let password = if let Some(pwd) = self.password.as_ref().map(Clone::clone) {
-            pwd
-        } else if let Ok(s) = std::env::var("STACK_KDBX_PASS") {
-            s
+        self.open_kdbx_paths(&[path.to_string()])
+    }
+
+    /// Returns the password from --password, --password-file
+    /// or $STACK_KDBX_PASS, if any of them was specified
+    fn configured_password(&self) -> anyhow::Result<Option<String>> {
+        // An empty path, such as from an unset variable in a compose
+        // file, is treated as though no file was specified
+        let password_file = self
+            .password_file
+            .as_deref()
+            .filter(|path| !path.is_empty());
+
+        if let Some(pwd) = self.password.clone() {
+            Ok(Some(pwd))
+        } else if let Some(path) = password_file {
+            let pwd = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read password file {path}"))?;
+            Ok(Some(pwd.trim_end_matches(['\r', '\n']).to_string()))
+        } else {
+            Ok(std::env::var("STACK_KDBX_PASS").ok())
+        }
+    }
+
+    fn open_kdbx_paths(&self, paths: &[String]) -> anyhow::Result<KeePassDB> {
+        let password = if let Some(pwd) = self.configured_password()? {
+            Some(pwd)
+        } else if self.keyfile.is_some() {
+            // The key file alone is sufficient to unlock the database
+            None
         } else if self.interactive {
-            rpassword::prompt_password("Password:")?
+            Some(rpassword::prompt_password("Password:")?)
         } else {
             anyhow::bail!(
-                "Missing --password and $STACK_KDBX_PASS env var value \
-                and --interactive is not set"
+                "Missing --password, --password-file, --keyfile and $STACK_KDBX_PASS \
+                env var value and --interactive is not set"
             );
         };
 
-        KeePassDB::open_with_password(path, &password)
+        KeePassDB::open_all(paths, password.as_deref(), self.keyfile.as_deref())
+    }
+
+    /// The default options for deploying stacks, with the
+    /// secure_secrets, no_wait, check_config and stamp_labels options,
+    /// prompting for missing secrets when --interactive is set,
+    /// and the keys for decrypting encrypted env files.  The password
+    /// of the database is reused as the passphrase of those files.
+    fn deploy_options(&self) -> anyhow::Result<DeployOptions> {
+        Ok(DeployOptions {
+            secure_secrets: self.secure_secrets,
+            no_wait: self.no_wait,
+            check_config: self.check_config,
+            stamp_labels: self.stamp_labels,
+            passphrase: self.configured_password()?,
+            age_identity: self.age_identity.clone(),
+            prompt_missing_secrets: self.interactive,
+            ..DeployOptions::default()
+        })
+    }
+
+    /// Arm the --max-runtime deadline for a deploy, if it was specified
+    fn deadline(&self, on_expiry: watchdog::OnExpiry) -> Option<watchdog::Deadline> {
+        self.max_runtime
+            .map(|seconds| watchdog::arm(std::time::Duration::from_secs(seconds), on_expiry))
+    }
+
+    /// The options for loading stacks, from the hostname, depends_on,
+    /// docker context and ordering options
+    fn load_options(&self) -> LoadOptions<'_> {
+        LoadOptions {
+            hostname: self.hostname.as_deref(),
+            strict_depends_on: self.strict_depends_on,
+            docker_context: self.docker_context.as_deref(),
+            order_by_path: self.order_by_path,
+        }
+    }
+
+    /// Find the stacks in root and/or files, without ordering them
+    fn find_stacks(&self, root: &str, files: &[PathBuf]) -> anyhow::Result<StackSet> {
+        find_stacks(root, files, &self.load_options())
+    }
+
+    /// Load the stacks in root and/or files, in dependency order
+    fn load_stacks(&self, root: &str, files: &[PathBuf]) -> anyhow::Result<Vec<DeployFile>> {
+        load_stacks(root, files, &self.load_options())
+    }
+
+    /// Acquire the lock that prevents concurrent deploys from root
+    fn lock(&self, root: &str) -> anyhow::Result<lock::DeployLock> {
+        lock::acquire(
+            Path::new(root),
+            std::time::Duration::from_secs(self.lock_timeout),
+        )
+    }
+
+    /// Look up the git token from the secrets file in the checked out repo
+    fn git_token_from_secrets(
+        &self,
+        cache: &SecretCache,
+        repo_dir: &str,
+        secret: &str,
+    ) -> Option<String> {
+        let secrets_path = format!("{repo_dir}/.secrets.kdbx");
+        if self.secret_backend == SecretBackend::Keepass && !Path::new(&secrets_path).exists() {
+            return None;
+        }
+        match cache.open(self, &secrets_path) {
+            Ok(db) => {
+                let token = db.resolve_value(secret);
+                if token.is_none() {
+                    log::warn!("git_token_secret {secret} was not found, using $GIT_TOKEN");
+                }
+                token
+            }
+            Err(err) => {
+                log::warn!("Failed to open {secrets_path} to look up the git token: {err:#}");
+                None
+            }
+        }
+    }
+
+    /// Send the summary of a deploy to the notification webhook,
+    /// if one was configured.  Failure to do so is logged but is
+    /// otherwise ignored.
+    fn notify(&self, repo_dir: &str, outcomes: &[(&DeployFile, StackStatus)]) {
+        let Some(url) = &self.notify_webhook else {
+            return;
+        };
+        let commit = get_repo_commit_hash(repo_dir).ok();
+        if let Err(err) = notify::send_webhook(url, commit.as_deref(), outcomes) {
+            log::error!("Failed to send deploy notification to {url}: {err:#}");
+        }
+    }
+
+    /// Write the report of a deploy that started at started_at to the
+    /// --report file, if one was specified.  Failure to do so is logged
+    /// but is otherwise ignored.
+    fn write_report(
+        &self,
+        repo_dir: &str,
+        started_at: std::time::SystemTime,
+        deployer: &Deployer,
+        outcomes: &[(&DeployFile, StackStatus)],
+    ) {
+        let Some(path) = &self.report else {
+            return;
+        };
+        let commit = get_repo_commit_hash(repo_dir).ok();
+        let report = report::DeployReport::new(started_at, commit, deployer, outcomes);
+        if let Err(err) = report.write(path) {
+            log::error!("Failed to write the deploy report: {err:#}");
+        }
     }
 }
 
-fn do_compose_down(path: &Path) -> anyhow::Result<()> {
-    let mut cmd = std::process::Command::new("docker");
-    cmd.args(["compose", "down", "--remove-orphans"]);
-    cmd.current_dir(
-        path.parent()
-            .ok_or_else(|| anyhow::anyhow!("path {path:?} has no parent!?"))?,
-    );
+/// Deploy the stacks under root, and then redeploy each of them
+/// as its input files change, until shutdown is requested
+fn watch_stacks(args: &Args, root: &str) -> anyhow::Result<()> {
+    shutdown::install();
+    let mut watcher = watch::Watcher::new(Path::new(root))?;
+    let db = args.open_secret_store(None)?;
+    let deployer = Deployer::new(&*db, args.deploy_options()?);
+    log::info!("Watching {root} for changes");
+
+    let mut changed: Option<Vec<PathBuf>> = None;
+    loop {
+        // The stacks are reloaded each time, so that edits to their
+        // deploy files, and newly added stacks, are picked up
+        match args.load_stacks(root, &[]) {
+            Ok(sorted) => {
+                let sorted = match &changed {
+                    Some(changed) => sorted
+                        .into_iter()
+                        .filter(|entry| {
+                            input_files(entry).iter().any(|file| changed.contains(file))
+                        })
+                        .collect(),
+                    None => sorted,
+                };
+                if !sorted.is_empty() {
+                    let _lock = args.lock(root)?;
+                    deployer.deploy(&sorted);
+                }
+            }
+            Err(err) => log::error!("Failed to load the stacks: {err:#}"),
+        }
 
-    let status = cmd
-        .status()
-        .with_context(|| format!("failed to run docker compose down in directory of {path:?}"))?;
-    anyhow::ensure!(status.success(), "exit status is {status:?}");
+        match watcher.wait_for_changes(std::time::Duration::from_millis(500))? {
+            Some(paths) => {
+                log::debug!("Changed: {paths:?}");
+                changed = Some(paths);
+            }
+            None => break,
+        }
+    }
+    log::info!("Exiting");
     Ok(())
 }
 
-fn do_compose_up(db: &KeePassDB, path: &Path, deploy: &StackDeploy) -> anyhow::Result<()> {
-    let mut cmd = std::process::Command::new("docker");
-    cmd.args(["compose", "up", "--remove-orphans", "--detach", "--wait"]);
-    cmd.current_dir(
-        path.parent()
-            .ok_or_else(|| anyhow::anyhow!("path {path:?} has no parent!?"))?,
-    );
+/// A secret store that was opened by `run`
+struct CachedStore {
+    store: std::sync::Arc<dyn SecretStore + Send>,
+    opened: std::time::Instant,
+    /// The modification time of the file that the store was opened from
+    modified: Option<std::time::SystemTime>,
+}
 
-    let mut failed = false;
-    for (k, v) in deploy.secret_env.iter() {
-        match db.resolve_value(&v) {
-            Some(v) => {
-                cmd.env(k, v);
-            }
-            None => {
-                log::error!("secret_env {k}: {v} was not found in database");
-                failed = true;
+/// The secret stores opened by `run`, keyed by the path of the secrets
+/// file of each repo, so that each poll doesn't need to open them again.
+/// A store is reopened once ttl has passed, or when its file is modified.
+struct SecretCache {
+    ttl: std::time::Duration,
+    stores: std::sync::Mutex<std::collections::BTreeMap<String, CachedStore>>,
+}
+
+impl SecretCache {
+    fn new(ttl: std::time::Duration) -> Self {
+        Self {
+            ttl,
+            stores: Default::default(),
+        }
+    }
+
+    /// Returns the store for the secrets file at kdbx_path, opening it
+    /// if it isn't cached, or the cached store has expired
+    fn open(
+        &self,
+        args: &Args,
+        kdbx_path: &str,
+    ) -> anyhow::Result<std::sync::Arc<dyn SecretStore + Send>> {
+        if self.ttl.is_zero() {
+            return Ok(args.open_secret_store(Some(kdbx_path))?.into());
+        }
+        let file = match args.secret_backend {
+            SecretBackend::Keepass => Some(Path::new(kdbx_path)),
+            SecretBackend::Sops => args.sops_file.as_deref(),
+            SecretBackend::Bitwarden | SecretBackend::Vault => None,
+        };
+        let modified = file.and_then(|file| file.metadata().and_then(|m| m.modified()).ok());
+
+        let mut stores = self.stores.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(cached) = stores.get(kdbx_path) {
+            if cached.modified != modified {
+                log::info!("Reopening the secrets, as {kdbx_path} was modified");
+            } else if cached.opened.elapsed() >= self.ttl {
+                log::debug!("Reopening the secrets, as those cached from {kdbx_path} expired");
+            } else {
+                return Ok(cached.store.clone());
             }
         }
+        let store: std::sync::Arc<dyn SecretStore + Send> =
+            args.open_secret_store(Some(kdbx_path))?.into();
+        stores.insert(
+            kdbx_path.to_string(),
+            CachedStore {
+                store: store.clone(),
+                opened: std::time::Instant::now(),
+                modified,
+            },
+        );
+        Ok(store)
     }
+}
+
+/// Deploy the stacks in repo_dir.  When `since` is set, only the stacks
+/// affected by the changes since that commit are deployed.
+/// Returns false if any of the stacks failed to deploy.
+fn run_deploy(
+    args: &Args,
+    cache: &SecretCache,
+    repo_dir: &str,
+    prune: bool,
+    since: Option<&str>,
+) -> anyhow::Result<bool> {
+    // Exceeding the deadline abandons this deploy, rather than stopping
+    // the poller
+    let _deadline = args.deadline(watchdog::OnExpiry::Abandon);
+    let _lock = args.lock(repo_dir)?;
 
-    anyhow::ensure!(
-        !failed,
-        "Cannot deploy {path:?} because of the errors above"
+    let secrets_path = format!("{repo_dir}/.secrets.kdbx");
+    let db = cache.open(args, &secrets_path)?;
+
+    let sorted = args.load_stacks(repo_dir, &[])?;
+    check_docker(&sorted)?;
+    let started_at = std::time::SystemTime::now();
+    let deployer = Deployer::new(&*db, args.deploy_options()?);
+    let outcomes = deployer.deploy_repo(repo_dir, &sorted, since);
+    args.write_report(repo_dir, started_at, &deployer, &outcomes);
+    metrics::METRICS.deployed(
+        outcomes
+            .iter()
+            .filter(|(_, status)| status.is_failed())
+            .map(|(entry, _)| entry.deploy.name.as_str()),
     );
+    args.notify(repo_dir, &outcomes);
 
-    let status = cmd
-        .status()
-        .with_context(|| format!("failed to run docker compose up in directory of {path:?}"))?;
-    anyhow::ensure!(status.success(), "exit status is {status:?}");
-    Ok(())
+    if prune {
+        deployer.prune_removed(repo_dir, &sorted)?;
+    }
+
+    Ok(outcomes.iter().all(|(_, status)| !status.is_failed()))
 }
 
-fn run_deploy(args: &Args, repo_dir: &str) -> anyhow::Result<()> {
-    let secrets_path = format!("{repo_dir}/.secrets.kdbx");
-    let db = args.open_kdbx_path(&secrets_path)?;
+fn init_logging(format: LogFormat, level: LevelFilter) {
+    let mut builder = env_logger::builder();
+    builder.filter_level(level);
 
-    let sorted = load_stacks(repo_dir, &[])?;
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            use std::io::Write;
 
-    for entry in sorted {
-        match do_compose_up(&db, &entry.path, &entry.deploy) {
-            Ok(()) => {
-                log::info!("Deployed {:?}!", entry.path);
+            struct Fields(Vec<(String, json::Value)>);
+            impl<'kvs> log::kv::VisitSource<'kvs> for Fields {
+                fn visit_pair(
+                    &mut self,
+                    key: log::kv::Key<'kvs>,
+                    value: log::kv::Value<'kvs>,
+                ) -> Result<(), log::kv::Error> {
+                    self.0.push((key.to_string(), value.to_string().into()));
+                    Ok(())
+                }
             }
-            Err(err) => {
-                log::error!("Failed to deploy {:?}: {err:#}", entry.path);
+
+            let mut fields = Fields(vec![
+                ("timestamp".to_string(), buf.timestamp().to_string().into()),
+                ("level".to_string(), record.level().as_str().into()),
+                ("message".to_string(), record.args().to_string().into()),
+            ]);
+            if let Err(err) = record.key_values().visit(&mut fields) {
+                fields
+                    .0
+                    .push(("kv_error".to_string(), err.to_string().into()));
             }
-        }
+
+            writeln!(buf, "{}", json::Value::Object(fields.0))
+        });
     }
 
-    Ok(())
+    builder.init();
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let level = if args.quiet {
+        LevelFilter::Warn
+    } else if args.verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    init_logging(args.log_format, level);
+    args.runtime.init();
 
     match &args.cmd {
-        Command::GetSecret { path } => {
+        Command::GetSecret { paths, format } => {
+            print_secrets(&args, paths, *format)?;
+        }
+        Command::GetEnv { vars } => {
+            print_secrets(&args, vars, SecretFormat::Export)?;
+        }
+        Command::CheckSecrets { root, files } => {
+            let db = args.open_secret_store(None)?;
+            let set = args.find_stacks(root, files)?;
+            let entries: Vec<&DeployFile> = set.stacks.values().chain(&set.other_hosts).collect();
+            if !check_secrets::check_secrets(&*db, &entries) {
+                std::process::exit(1);
+            }
+        }
+        Command::BrowseSecrets { list_paths } => {
+            anyhow::ensure!(
+                args.secret_backend == SecretBackend::Keepass,
+                "browse-secrets only supports the keepass secret backend"
+            );
+            anyhow::ensure!(
+                *list_paths || args.interactive,
+                "pass --list-paths, or --interactive to navigate the secrets"
+            );
             let db = args.open_kdbx()?;
-            match db.resolve_value(&path) {
-                Some(v) => {
-                    println!("{v}");
+            if *list_paths {
+                for path in browse::list_paths(&db) {
+                    println!("{path}");
                 }
-                None => {
-                    log::error!("{path} not found in {:?}", args.kdbx);
+            } else if let Some(path) = browse::browse(&db)? {
+                println!("{path}");
+            }
+        }
+        Command::StackDeploy {
+            root,
+            files,
+            parallel,
+            dry_run,
+            rollback_on_failure,
+            fail_fast,
+            only,
+            skip,
+            no_deps,
+            scope_dir,
+            set_image,
+            list_missing_secrets,
+        } => {
+            if *list_missing_secrets {
+                let mut sorted = args.load_stacks(root, files)?;
+                if let Some(scope_dir) = scope_dir {
+                    sorted = filter_scope(sorted, scope_dir)?;
+                }
+                let sorted = filter_stacks(sorted, only, skip, *no_deps)?;
+                let db = args.open_secret_store(None)?;
+                let missing =
+                    Deployer::new(&*db, args.deploy_options()?).missing_secrets(&sorted)?;
+                for (entry, problems) in &missing {
+                    println!("{}:", entry.deploy.name);
+                    for problem in problems {
+                        println!("  {problem}");
+                    }
+                }
+                if !missing.is_empty() {
                     std::process::exit(1);
                 }
+                log::info!(
+                    "All of the secrets of {} stack(s) were resolved",
+                    sorted.len()
+                );
+                return Ok(());
+            }
+            if let Some(reason) = drain::drained(Path::new(root)) {
+                if !*dry_run {
+                    drain::log_skipped(Path::new(root), &reason);
+                    return Ok(());
+                }
+                log::warn!("This host is drained, so a real deploy would be skipped");
+            }
+            let deadline = args.deadline(watchdog::OnExpiry::Exit);
+            let _lock = if *dry_run {
+                None
+            } else {
+                Some(args.lock(root)?)
+            };
+            let mut sorted = args.load_stacks(root, files)?;
+            if let Some(scope_dir) = scope_dir {
+                sorted = filter_scope(sorted, scope_dir)?;
+            }
+            let mut sorted = filter_stacks(sorted, only, skip, *no_deps)?;
+            if !*dry_run {
+                check_docker(&sorted)?;
+            }
+            let _image_override = match &mut sorted[..] {
+                _ if set_image.is_empty() => None,
+                [entry] => Some(override_images(entry, set_image, *dry_run)?),
+                _ => anyhow::bail!(
+                    "--set-image requires that a single stack is deployed, \
+                    but {} were selected; use --only NAME --no-deps, or --file",
+                    sorted.len()
+                ),
+            };
+            let db = args.open_secret_store(None)?;
+            let deployer = Deployer::new(
+                &*db,
+                DeployOptions {
+                    parallel: *parallel,
+                    dry_run: *dry_run,
+                    rollback_on_failure: *rollback_on_failure,
+                    fail_fast: *fail_fast,
+                    ..args.deploy_options()?
+                },
+            );
+            let started_at = std::time::SystemTime::now();
+            let outcomes = deployer.deploy_repo(root, &sorted, None);
+            // If the deadline was exceeded, this waits for it to exit
+            drop(deadline);
+            if !*dry_run {
+                args.write_report(root, started_at, &deployer, &outcomes);
+                args.notify(root, &outcomes);
+            }
+            let failed = outcomes
+                .iter()
+                .filter(|(_, status)| !status.is_deployed())
+                .count();
+            if failed > 0 {
+                log::error!("{failed} of {} stack(s) failed to deploy", outcomes.len());
+                std::process::exit(1);
             }
         }
-        Command::StackDeploy { root, files } => {
-            let db = args.open_kdbx()?;
-            let sorted = load_stacks(root, files)?;
+        Command::StackStop {
+            root,
+            files,
+            scope_dir,
+        } => {
+            let mut sorted = args.load_stacks(root, files)?;
+            if let Some(scope_dir) = scope_dir {
+                sorted = filter_scope(sorted, scope_dir)?;
+            }
+            let db = args.open_secrets_for_down(&sorted)?;
+            let db = db.as_deref().map(|db| db as &dyn SecretStore);
 
-            for entry in sorted {
-                match do_compose_up(&db, &entry.path, &entry.deploy) {
+            for entry in stop_order(sorted) {
+                match do_compose_down(db, &entry.path, &entry.deploy) {
                     Ok(()) => {
-                        log::info!("Deployed {:?}!", entry.path);
+                        log::info!("Stopped {:?}", entry.path);
                     }
                     Err(err) => {
-                        log::error!("Failed to deploy {:?}: {err:#}", entry.path);
+                        log::error!("Failed to stop {:?}: {err:#}", entry.path);
                     }
                 }
             }
         }
-        Command::StackStop { root, files } => {
-            let mut sorted = load_stacks(root, files)?;
-            // Go in reverse order when stopping
-            sorted.reverse();
+        Command::Status { root, files } => {
+            let sorted = args.load_stacks(root, files)?;
+            status::show_status(&sorted);
+        }
+        Command::Logs {
+            name,
+            root,
+            files,
+            follow,
+            tail,
+        } => {
+            let set = args.find_stacks(root, files)?;
+            let entry = find_stack(&set, name)?;
+            anyhow::ensure!(
+                entry.deploy.mode != DeployMode::Swarm,
+                "logs is not supported for swarm stacks; use docker service logs \
+                 for the services of {}",
+                entry.deploy.project_name()
+            );
 
-            for entry in sorted {
-                match do_compose_down(&entry.path) {
+            let mut cmd = compose_command(&entry.path, &entry.deploy)?;
+            cmd.arg("logs");
+            if *follow {
+                cmd.arg("--follow");
+            }
+            if let Some(tail) = tail {
+                cmd.args(["--tail", &tail.to_string()]);
+            }
+            let err = std::os::unix::process::CommandExt::exec(&mut cmd);
+            return Err(err).context("failed to run docker compose logs");
+        }
+        Command::Restart {
+            name,
+            // Implied by the absence of name
+            all: _,
+            root,
+            files,
+        } => {
+            let _lock = args.lock(root)?;
+            let set = args.find_stacks(root, files)?;
+            let sorted = match name {
+                Some(name) => vec![find_stack(&set, name)?.clone()],
+                None => set.sorted()?,
+            };
+            check_docker(&sorted)?;
+            let db = args.open_secret_store(None)?;
+            let opts = args.deploy_options()?;
+
+            let mut failed = 0;
+            for entry in &sorted {
+                match do_compose_restart(&*db, &entry.path, &entry.deploy, &opts) {
+                    Ok(()) => {
+                        log::info!(stack = entry.deploy.name.as_str(); "Restarted {:?}", entry.path);
+                    }
+                    Err(err) => {
+                        log::error!(
+                            stack = entry.deploy.name.as_str();
+                            "Failed to restart {:?}: {err:#}", entry.path
+                        );
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::List {
+            root,
+            files,
+            format,
+            all_hosts,
+        } => {
+            let set = args.find_stacks(root, files)?;
+            let sorted = set.sorted()?;
+            let other_hosts = if *all_hosts {
+                &set.other_hosts[..]
+            } else {
+                &[]
+            };
+            list::show_list(&sorted, other_hosts, *format);
+        }
+        Command::Drain {
+            root,
+            files,
+            reason,
+        } => {
+            let _lock = args.lock(root)?;
+            // Write the marker first, so that a deploy can't start
+            // up the stacks again while they are being stopped
+            drain::drain(Path::new(root), reason.as_deref().unwrap_or_default())?;
+            let sorted = args.load_stacks(root, files)?;
+            let db = args.open_secrets_for_down(&sorted)?;
+            let db = db.as_deref().map(|db| db as &dyn SecretStore);
+
+            let mut failed = 0;
+            for entry in stop_order(sorted) {
+                let name = &entry.deploy.name;
+                match do_compose_down(db, &entry.path, &entry.deploy) {
                     Ok(()) => {
-                        log::info!("Deployed {:?}!", entry.path);
+                        log::info!(stack = name.as_str(); "[{name}] Stopped");
                     }
                     Err(err) => {
-                        log::error!("Failed to deploy {:?}: {err:#}", entry.path);
+                        log::error!(stack = name.as_str(); "[{name}] Failed to stop: {err:#}");
+                        failed += 1;
                     }
                 }
             }
+            if failed > 0 {
+                log::error!("{failed} stack(s) failed to stop");
+                std::process::exit(1);
+            }
+            log::info!(
+                "Drained. Run `docker-stack-deploy undrain --root {root}` to resume deploys"
+            );
+        }
+        Command::Undrain { root } => {
+            if drain::undrain(Path::new(root))? {
+                log::info!(
+                    "Undrained. Run stack-deploy to start the stacks now, \
+                    or `run` will deploy them when it next polls the repo"
+                );
+            } else {
+                log::info!("This host was not drained");
+            }
+        }
+        Command::Watch { root } => watch_stacks(&args, root)?,
+        Command::Graph {
+            root,
+            files,
+            format,
+        } => {
+            let set = args.find_stacks(root, files)?;
+            graph::show_graph(&set, *format);
+        }
+        Command::Diff { root, files } => {
+            let db = args.open_secret_store(None)?;
+            let sorted = args.load_stacks(root, files)?;
+            diff::show_diff(&*db, &sorted);
+        }
+        Command::Versions { root } => {
+            repo::show_versions(root)?;
+        }
+        Command::Validate {
+            root,
+            files,
+            schema_only: true,
+        } => {
+            let shape = schema::trace::<StackDeploy>()?;
+            let mut problems = vec![];
+            for path in deploy_file_paths(root, files)? {
+                let value =
+                    read_deploy_file(&path).and_then(|text| parse_deploy_value(&path, &text));
+                match value {
+                    Ok(value) => {
+                        for problem in schema::check(&value, &shape) {
+                            problems.push(format!("{}: {problem}", path.display()));
+                        }
+                    }
+                    Err(err) => problems.push(format!("{err:#}")),
+                }
+            }
+            if !problems.is_empty() {
+                for problem in &problems {
+                    log::error!("{problem}");
+                }
+                log::error!("Found {} problem(s)", problems.len());
+                std::process::exit(1);
+            }
+            log::info!("No problems found");
+        }
+        Command::Schema => {
+            println!("{}", schema::stack_deploy_schema()?.to_pretty_string());
+        }
+        Command::Validate { root, files, .. } => {
+            let db = args.open_secret_store(None)?;
+            let set = args.find_stacks(root, files)?;
+            let problems = Deployer::new(&*db, args.deploy_options()?).validate(&set)?;
+            if !problems.is_empty() {
+                for problem in &problems {
+                    log::error!("{problem}");
+                }
+                log::error!("Found {} problem(s)", problems.len());
+                std::process::exit(1);
+            }
+            log::info!("No problems found");
         }
         Command::Run {
             repo_dir,
             repo_url,
             poll_interval,
+            ssh_key,
+            git_token_secret,
+            git_reset_on_conflict,
+            branch,
+            git_depth,
+            git_filter,
+            git_timeout,
+            prune_removed,
+            changed_only,
+            require_signed_commits,
+            trusted_keys,
+            metrics_addr,
+            backoff_factor,
+            max_backoff,
+            secret_cache_ttl,
         } => {
+            anyhow::ensure!(
+                *backoff_factor >= 1.0,
+                "--backoff-factor must be at least 1"
+            );
+            let repo_urls: Vec<Option<&str>> = if repo_url.is_empty() {
+                anyhow::ensure!(
+                    !*changed_only && !*require_signed_commits,
+                    "--changed-only and --require-signed-commits need the history \
+                    of a git repo, so they require --repo-url"
+                );
+                vec![None; repo_dir.len()]
+            } else {
+                anyhow::ensure!(
+                    repo_url.len() == repo_dir.len(),
+                    "each --repo-url must have a corresponding --repo-dir"
+                );
+                repo_url.iter().map(|url| Some(url.as_str())).collect()
+            };
+            if let Some(addr) = metrics_addr {
+                metrics::serve(addr)?;
+            }
+
             let interval = std::time::Duration::from_secs(*poll_interval);
-            let mut first_run = true;
-
-            loop {
-                let hash = clone_or_update(repo_url, repo_dir)?;
-                log::debug!("hash is {hash:?}");
-                if hash.updated() || first_run {
-                    log::info!("Running a deploy {hash:?}");
-                    if let Err(err) = run_deploy(&args, repo_dir) {
-                        log::error!("Error running deploy: {err:#}");
+            // Repos are fetched concurrently, but only one of them
+            // is deployed at a time
+            let deploying = std::sync::Mutex::new(());
+            let secret_cache = SecretCache::new(std::time::Duration::from_secs(*secret_cache_ttl));
+            shutdown::install();
+
+            let opts = poll::PollOptions {
+                interval,
+                backoff_factor: *backoff_factor,
+                max_backoff: std::time::Duration::from_secs(*max_backoff),
+                changed_only: *changed_only,
+                require_signed_commits: *require_signed_commits,
+                trusted_keys: trusted_keys.as_deref(),
+                // When several repos are being polled, a repo that fails to
+                // update is retried at the next poll rather than ending the run
+                isolate_errors: repo_dir.len() > 1,
+                git: git::GitOptions {
+                    ssh_key: ssh_key.as_deref(),
+                    token: None,
+                    reset_on_conflict: *git_reset_on_conflict,
+                    branch: branch.as_deref(),
+                    depth: *git_depth,
+                    filter: git_filter.as_deref(),
+                    timeout: git_timeout.map(std::time::Duration::from_secs),
+                },
+            };
+            let poll_repo = |repo_url: Option<&str>, repo_dir: &str| {
+                poll::poll_repo(
+                    repo_url,
+                    repo_dir,
+                    &opts,
+                    &deploying,
+                    || {
+                        git_token_secret.as_deref().and_then(|secret| {
+                            args.git_token_from_secrets(&secret_cache, repo_dir, secret)
+                        })
+                    },
+                    |since| run_deploy(&args, &secret_cache, repo_dir, *prune_removed, since),
+                )
+            };
+
+            if let ([repo_url], [repo_dir]) = (&repo_urls[..], &repo_dir[..]) {
+                poll_repo(*repo_url, repo_dir)?;
+            } else {
+                // Each repo is polled on its own thread, so that a slow
+                // or failing repo doesn't hold up the others
+                std::thread::scope(|scope| -> anyhow::Result<()> {
+                    let threads: Vec<_> = repo_urls
+                        .iter()
+                        .zip(repo_dir)
+                        .map(|(repo_url, repo_dir)| {
+                            let poll_repo = &poll_repo;
+                            scope.spawn(move || poll_repo(*repo_url, repo_dir))
+                        })
+                        .collect();
+                    for thread in threads {
+                        thread.join().expect("repo poller panicked")?;
                     }
-                }
-                first_run = false;
-                std::thread::sleep(interval);
+                    Ok(())
+                })?;
             }
+            log::info!("Exiting");
         }
         Command::Bootstrap {
             project_dir,
             git_url,
             git_username,
             poll_interval,
+            branch,
         } => {
             std::fs::create_dir_all(project_dir)
                 .with_context(|| format!("failed to create_dir_all {project_dir}"))?;
 
-            let github_token = rpassword::prompt_password("Github Token:")?;
+            let git_token = rpassword::prompt_password("Git Token:")?;
             let db_password = rpassword::prompt_password("KeePass Passphrase:")?;
 
             let compose_yml = include_str!("../compose.yml");
@@ -269,123 +1546,88 @@ fn main() -> anyhow::Result<()> {
             std::fs::write(&compose_file, compose_yml)
                 .with_context(|| format!("failed to write {compose_file}"))?;
             let env_file = format!("{project_dir}/.env");
-            std::fs::write(
-                &env_file,
-                format!(
-                    "GITHUB_URL=\"{git_url}\"\n\
-                    GITHUB_USERNAME=\"{git_username}\"\n\
-                    GITHUB_TOKEN=\"{github_token}\"\n\
-                    STACK_KDBX_PASS=\"{db_password}\"\n\
-                    POLL_INTERVAL=\"{poll_interval}\"\n"
-                ),
-            )
-            .with_context(|| format!("failed to write {env_file}"))?;
+            let mut env = format!(
+                "GIT_URL=\"{git_url}\"\n\
+                GIT_USERNAME=\"{git_username}\"\n\
+                GIT_TOKEN=\"{git_token}\"\n\
+                STACK_KDBX_PASS=\"{db_password}\"\n\
+                POLL_INTERVAL=\"{poll_interval}\"\n"
+            );
+            if let Some(branch) = branch {
+                env.push_str(&format!("GIT_BRANCH=\"{branch}\"\n"));
+            }
+            std::fs::write(&env_file, env)
+                .with_context(|| format!("failed to write {env_file}"))?;
 
             let mut cmd = std::process::Command::new("docker");
             cmd.args(["compose", "up", "--remove-orphans", "--detach", "--wait"]);
             cmd.current_dir(project_dir);
 
             let status = cmd
-                .status()
+                .tracked_status()
                 .with_context(|| format!("failed to run docker compose up in {project_dir}"))?;
             anyhow::ensure!(status.success(), "exit status is {status:?}");
         }
+        Command::SelfUpdate { check_only } => {
+            self_update::self_update(*check_only)?;
+        }
+        Command::Completions { shell } => {
+            completions::print_completions(*shell, Args::command());
+        }
     }
 
     Ok(())
 }
 
-fn getenv(name: &str) -> anyhow::Result<String> {
-    std::env::var(name).with_context(|| format!("env var {name} not found"))
-}
-
-#[derive(Debug)]
-#[allow(unused)]
-enum RepoUpdateStatus {
-    Cloned(String),
-    Updated(String),
-    Same(String),
-}
-
-impl RepoUpdateStatus {
-    pub fn updated(&self) -> bool {
-        match self {
-            Self::Cloned(_) | Self::Updated(_) => true,
-            Self::Same(_) => false,
+/// Parse the SERVICE=IMAGE value of --set-image
+fn parse_set_image(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((service, image)) if !service.is_empty() && !image.is_empty() => {
+            Ok((service.to_string(), image.to_string()))
         }
+        _ => Err(format!("expected SERVICE=IMAGE, but got {value:?}")),
     }
 }
 
-fn get_repo_commit_hash(repo_dir: &str) -> anyhow::Result<String> {
-    let mut cmd = std::process::Command::new("git");
-    cmd.current_dir(repo_dir);
-    cmd.args(["rev-parse", "HEAD"]);
-    let output = cmd
-        .output()
-        .with_context(|| format!("failed to get current commit hash of git repo {repo_dir}"))?;
-    anyhow::ensure!(
-        output.status.success(),
-        "exit status is {:?}",
-        output.status
-    );
-
-    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
-}
-
-fn clone_or_update(repo_url: &str, repo_dir: &str) -> anyhow::Result<RepoUpdateStatus> {
-    let dot_git = format!("{repo_dir}/.git");
-
-    let recreate = match std::fs::metadata(&dot_git) {
-        Ok(meta) => !meta.is_dir(),
-        Err(err) => {
-            log::warn!("Error getting metadata for {dot_git}: {err:#}");
-            true
-        }
-    };
-
-    let mut cmd = std::process::Command::new("git");
-    // TODO: if we have the repo checked out, we could try to read current
-    // versions of these creds from the secrets file, which would allow
-    // managing token expiration without redeploying the redeployer.
-    let username = getenv("GITHUB_USERNAME")?;
-    let password = getenv("GITHUB_TOKEN")?;
-
-    // We want to avoid baking the PAT from the time we clone the repo
-    // into the repo so that we can update the token over time.
-    // These ad-hoc config overrides facilitate passing in the creds
-    // <https://stackoverflow.com/a/77199818/149111>
-    cmd.args(["-c", &format!("credential.username={username}")]);
-    cmd.args([
-        "-c",
-        "credential.helper=!f(){ test \"$1\" = get && echo \"password=${GITHUB_TOKEN}\"; }; f",
-    ]);
-    cmd.env("GITHUB_TOKEN", password);
+/// Resolve and print the specified secrets.  Nothing is printed
+/// if any of them cannot be resolved, so that a partial set of
+/// variables isn't exported.
+fn print_secrets(args: &Args, paths: &[String], format: SecretFormat) -> anyhow::Result<()> {
+    let mut lines = vec![];
+    let mut missing = false;
+    let db = args.open_secret_store(None)?;
 
-    let mut hash_before = None;
-
-    if recreate {
-        if let Err(err) = std::fs::remove_dir_all(&repo_dir) {
-            log::warn!("Error removing {repo_dir}: {err:#}");
+    for arg in paths {
+        let (name, path) = match format {
+            SecretFormat::Raw => (None, arg.as_str()),
+            SecretFormat::Export => {
+                let (name, path) = arg
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("expected NAME=PATH, but got {arg}"))?;
+                anyhow::ensure!(
+                    !name.is_empty()
+                        && !name.starts_with(|c: char| c.is_ascii_digit())
+                        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                    "{name} is not a valid environment variable name"
+                );
+                (Some(name), path)
+            }
+        };
+        match (db.resolve_value(path), name) {
+            (Some(v), None) => lines.push(v),
+            (Some(v), Some(name)) => lines.push(format!("export {name}={}", shell_quote(&v))),
+            (None, _) => {
+                log::error!("{path} not found in the secret store");
+                missing = true;
+            }
         }
-
-        cmd.args(["clone", &repo_url, repo_dir]);
-    } else {
-        hash_before = get_repo_commit_hash(repo_dir).ok();
-
-        cmd.current_dir(repo_dir);
-        cmd.args(["pull", "--rebase"]);
     }
 
-    let status = cmd
-        .status()
-        .with_context(|| format!("failed to update git repo {repo_dir} from {repo_url}"))?;
-    anyhow::ensure!(status.success(), "exit status is {status:?}");
-
-    let hash_after = get_repo_commit_hash(repo_dir)?;
-
-    Ok(match (hash_before, hash_after) {
-        (Some(before), after) if before == after => RepoUpdateStatus::Same(after),
-        (Some(_before), after) => RepoUpdateStatus::Updated(after),
-        (None, after) => RepoUpdateStatus::Cloned(after),
-    })
+    if missing {
+        std::process::exit(1);
+    }
+    for line in lines {
+        println!("{line}");
+    }
+    Ok(())
 }
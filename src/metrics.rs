@@ -0,0 +1,149 @@
+//! A minimal HTTP server that exposes counters about the deploys
+//! performed by `run` in the Prometheus text format.
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Connections are handled one at a time, so a client that stalls, or
+/// that never stops sending, must not hold up the others for long
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_BYTES: u64 = 16 * 1024;
+
+pub struct Metrics {
+    deploys_total: AtomicU64,
+    git_poll_total: AtomicU64,
+    last_deploy_timestamp: AtomicU64,
+    deploy_failures_total: Mutex<BTreeMap<String, u64>>,
+}
+
+pub static METRICS: Metrics = Metrics {
+    deploys_total: AtomicU64::new(0),
+    git_poll_total: AtomicU64::new(0),
+    last_deploy_timestamp: AtomicU64::new(0),
+    deploy_failures_total: Mutex::new(BTreeMap::new()),
+};
+
+impl Metrics {
+    /// Record that the repo was polled for changes
+    pub fn git_polled(&self) {
+        self.git_poll_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a deploy has completed, along with the names
+    /// of the stacks that failed to deploy
+    pub fn deployed<'a>(&self, failed: impl IntoIterator<Item = &'a str>) {
+        self.deploys_total.fetch_add(1, Ordering::Relaxed);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.last_deploy_timestamp.store(now, Ordering::Relaxed);
+
+        let mut failures = self.deploy_failures_total.lock().unwrap();
+        for name in failed {
+            *failures.entry(name.to_string()).or_default() += 1;
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, u64)>| {
+            text.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+            for (labels, value) in values {
+                text.push_str(&format!("{name}{labels} {value}\n"));
+            }
+        };
+
+        metric(
+            "deploys_total",
+            "counter",
+            "Number of deploys that have been run",
+            vec![(String::new(), self.deploys_total.load(Ordering::Relaxed))],
+        );
+        metric(
+            "deploy_failures_total",
+            "counter",
+            "Number of times each stack has failed to deploy",
+            self.deploy_failures_total
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(stack, count)| {
+                    let stack = stack.replace('\\', "\\\\").replace('"', "\\\"");
+                    (format!("{{stack=\"{stack}\"}}"), *count)
+                })
+                .collect(),
+        );
+        metric(
+            "last_deploy_timestamp",
+            "gauge",
+            "Unix time at which the most recent deploy completed",
+            vec![(
+                String::new(),
+                self.last_deploy_timestamp.load(Ordering::Relaxed),
+            )],
+        );
+        metric(
+            "git_poll_total",
+            "counter",
+            "Number of times the repo has been polled for changes",
+            vec![(String::new(), self.git_poll_total.load(Ordering::Relaxed))],
+        );
+        text
+    }
+}
+
+fn handle_connection(stream: TcpStream) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Consume the headers; we don't need any of them
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (status, body) = match path {
+        "/" | "/metrics" => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+        Content-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+/// Start serving the metrics on addr in a background thread
+pub fn serve(addr: &str) -> anyhow::Result<()> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("failed to listen on {addr}"))?;
+    log::info!("Serving metrics on http://{addr}/metrics");
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .context("failed to accept connection")
+                .and_then(handle_connection);
+            if let Err(err) = result {
+                log::warn!("Error serving metrics: {err:#}");
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use crate::deploy::StackStatus;
+use crate::deploy_file::DeployFile;
+use crate::json::Value;
+use crate::watchdog::TrackedCommand;
+use anyhow::Context;
+use std::io::Write;
+use std::process::Stdio;
+
+/// Produce the JSON summary of a deploy.
+/// In addition to the structured per-stack information, the summary
+/// includes a human readable description in both the `text` and `content`
+/// fields, so that it renders when sent to a Slack or Discord webhook.
+fn summary(commit: Option<&str>, outcomes: &[(&DeployFile, StackStatus)]) -> Value {
+    let mut lines = vec![];
+    let mut stacks = vec![];
+
+    for (entry, status) in outcomes {
+        let name = entry.deploy.name.as_str();
+        let (label, error) = (status.label(), status.reason());
+        let error = error.as_deref();
+        match error {
+            Some(error) => lines.push(format!("{name}: {label}: {error}")),
+            None => lines.push(format!("{name}: {label}")),
+        }
+        stacks.push(Value::Object(vec![
+            ("name".to_string(), name.into()),
+            ("status".to_string(), label.into()),
+            ("error".to_string(), error.into()),
+        ]));
+    }
+
+    let text = format!(
+        "docker-stack-deploy on {} at {}\n{}",
+        gethostname::gethostname().to_string_lossy(),
+        commit.unwrap_or("unknown commit"),
+        lines.join("\n")
+    );
+
+    Value::Object(vec![
+        ("commit".to_string(), commit.into()),
+        ("stacks".to_string(), Value::Array(stacks)),
+        ("text".to_string(), text.clone().into()),
+        ("content".to_string(), text.into()),
+    ])
+}
+
+/// POST the summary of a deploy to the specified webhook URL
+pub fn send_webhook(
+    url: &str,
+    commit: Option<&str>,
+    outcomes: &[(&DeployFile, StackStatus)],
+) -> anyhow::Result<()> {
+    let body = summary(commit, outcomes).to_string();
+
+    let mut child = std::process::Command::new("curl")
+        .args([
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--max-time",
+            "30",
+            "-X",
+            "POST",
+            "-H",
+            "Content-Type: application/json",
+            "--data-binary",
+            "@-",
+            url,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .tracked_spawn()
+        .context("failed to run curl")?;
+
+    let written = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(body.as_bytes()),
+        None => Ok(()),
+    };
+    // Wait in any case, so that the child is reaped
+    let output = crate::watchdog::wait_with_output(child).context("failed to wait for curl")?;
+    written.context("failed to write request body to curl")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "curl exit status is {:?}",
+        output.status
+    );
+    Ok(())
+}
@@ -0,0 +1,138 @@
+//! Running commands with each line of their output prefixed by the
+//! name of the stack, so that the output of stacks that are deployed
+//! concurrently can be told apart.
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the rest of the output of a process that has
+/// exited to be copied.  Its pipes may have been inherited by a process
+/// that it started, which can hold them open indefinitely.
+const COPY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A child process whose stdout and stderr are being copied,
+/// with a prefix, to our own stdout and stderr
+pub struct PrefixedChild {
+    child: Child,
+    copiers: Vec<JoinHandle<()>>,
+}
+
+/// Copy lines from reader to stdout or stderr, prefixing each one.
+/// Each line is written with a single call, so that lines from
+/// different processes are not interleaved.
+fn copy_lines(
+    reader: impl Read + Send + 'static,
+    prefix: String,
+    to_stderr: bool,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        let mut line = vec![];
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let text = String::from_utf8_lossy(&line);
+            let text = format!("[{prefix}] {}\n", text.trim_end_matches(['\r', '\n']));
+            // There is nowhere to report a failure to write our own output
+            let _ = if to_stderr {
+                std::io::stderr().lock().write_all(text.as_bytes())
+            } else {
+                std::io::stdout().lock().write_all(text.as_bytes())
+            };
+        }
+    })
+}
+
+impl PrefixedChild {
+    pub fn spawn(cmd: &mut Command, prefix: &str) -> std::io::Result<Self> {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = cmd.spawn()?;
+        crate::watchdog::child_started(child.id());
+        let mut copiers = vec![];
+        if let Some(stdout) = child.stdout.take() {
+            copiers.push(copy_lines(stdout, prefix.to_string(), false));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            copiers.push(copy_lines(stderr, prefix.to_string(), true));
+        }
+        Ok(Self { child, copiers })
+    }
+
+    /// Wait for the output to be copied, for up to COPY_TIMEOUT.  The
+    /// copiers that are still running after that are left to finish
+    /// in the background.
+    fn join_copiers(&mut self) {
+        let deadline = Instant::now() + COPY_TIMEOUT;
+        for copier in self.copiers.drain(..) {
+            while !copier.is_finished() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            if copier.is_finished() {
+                let _ = copier.join();
+            } else {
+                log::debug!(
+                    "Not waiting for the rest of the output of pid {}",
+                    self.child.id()
+                );
+            }
+        }
+    }
+
+    /// Returns the exit status if the process has exited, once
+    /// all of its output has been copied
+    pub fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        let status = self.child.try_wait()?;
+        if status.is_some() {
+            crate::watchdog::child_exited(self.child.id());
+            self.join_copiers();
+        }
+        Ok(status)
+    }
+
+    /// Wait for the process to exit, and for all of its output
+    /// to be copied
+    pub fn wait(mut self) -> std::io::Result<ExitStatus> {
+        let status = self.child.wait()?;
+        crate::watchdog::child_exited(self.child.id());
+        self.join_copiers();
+        Ok(status)
+    }
+
+    /// Kill the process and wait for it to exit.  Any remaining output
+    /// is not waited for, as the pipes may have been inherited by other
+    /// processes that are still running.
+    pub fn kill(&mut self) -> std::io::Result<()> {
+        let result = self.child.kill();
+        self.child.wait()?;
+        crate::watchdog::child_exited(self.child.id());
+        result
+    }
+}
+
+/// Run cmd to completion, prefixing each line of its output
+pub fn run(cmd: &mut Command, prefix: &str) -> std::io::Result<ExitStatus> {
+    PrefixedChild::spawn(cmd, prefix)?.wait()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_held_open_by_a_grandchild() {
+        // The background sleep inherits the pipes, and holds them open
+        // after the shell has exited
+        let start = Instant::now();
+        let status = run(
+            Command::new("sh").args(["-c", "sleep 15 & echo started"]),
+            "test",
+        )
+        .unwrap();
+        assert!(status.success());
+        assert!(start.elapsed() < COPY_TIMEOUT + Duration::from_secs(5));
+    }
+}
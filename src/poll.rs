@@ -0,0 +1,153 @@
+//! The loop with which `run` polls a repo: updating it, and deploying
+//! it whenever it changes, or when a deploy that failed is due to be
+//! retried.
+use crate::backoff::Backoff;
+use crate::git::{
+    check_local_dir, clone_or_update, verify_head_signature, GitOptions, RepoUpdateStatus,
+};
+use crate::{drain, metrics, shutdown};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How [poll_repo] polls a repo
+pub struct PollOptions<'a> {
+    /// How long to wait between polls
+    pub interval: Duration,
+    /// The factor by which the delay before retrying a failed update
+    /// or deploy grows with each failure in a row, and its upper bound
+    pub backoff_factor: f64,
+    pub max_backoff: Duration,
+    /// Deploy only the stacks affected by the changes since the last
+    /// deploy, rather than all of them
+    pub changed_only: bool,
+    /// Only deploy commits with a good signature, made by one of
+    /// trusted_keys if that is set
+    pub require_signed_commits: bool,
+    pub trusted_keys: Option<&'a Path>,
+    /// Retry an update that fails at the next poll, rather than
+    /// returning its error
+    pub isolate_errors: bool,
+    /// How the repo is cloned and updated.  Its token is replaced by
+    /// the one returned for each poll.
+    pub git: GitOptions<'a>,
+}
+
+/// Poll the repo at repo_url, cloned into repo_dir, or the local
+/// directory repo_dir if there is no repo_url, until shutdown is
+/// requested.  Each poll, the repo is updated with the git token that
+/// token returns, and if it has changed, deploy is called to deploy it.
+/// That is passed the commit since which only the changes need deploying,
+/// if opts.changed_only allows it, and returns false if any stack failed.
+/// deploying is held while deploying, so that when several repos are
+/// polled, only one of them is deployed at a time.
+pub fn poll_repo(
+    repo_url: Option<&str>,
+    repo_dir: &str,
+    opts: &PollOptions,
+    deploying: &Mutex<()>,
+    token: impl Fn() -> Option<String>,
+    deploy: impl Fn(Option<&str>) -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    // Remains set while the host is drained, so that every stack
+    // is deployed once it is undrained
+    let mut first_run = true;
+    // The input fingerprint of a local repo_dir, as of the last poll
+    let mut fingerprint = None;
+    let backoff = || Backoff::new(opts.interval, opts.backoff_factor, opts.max_backoff);
+    let mut update_backoff = backoff();
+    let mut deploy_backoff = backoff();
+    // When to retry a failed deploy of the same commit
+    let mut retry_at = None;
+    while !shutdown::requested() {
+        shutdown::set_busy(true);
+        let updated = match repo_url {
+            Some(repo_url) => {
+                let git = GitOptions {
+                    token: token(),
+                    ..opts.git.clone()
+                };
+                metrics::METRICS.git_polled();
+                clone_or_update(repo_url, repo_dir, &git)
+            }
+            None => check_local_dir(repo_dir, &mut fingerprint),
+        };
+        let hash = match updated {
+            Ok(hash) => {
+                update_backoff.succeeded();
+                hash
+            }
+            Err(err) if opts.isolate_errors => {
+                let delay = update_backoff.failed();
+                log::error!(
+                    "Error updating {repo_dir}: {err:#}. Retrying in {} seconds",
+                    delay.as_secs()
+                );
+                shutdown::set_busy(false);
+                if shutdown::sleep(delay) {
+                    break;
+                }
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        log::debug!("hash is {hash:?}");
+        let retry_due = !hash.updated() && retry_at.is_some_and(|at| Instant::now() >= at);
+        if hash.updated() || first_run || retry_due {
+            let verified = if opts.require_signed_commits {
+                verify_head_signature(repo_dir, opts.trusted_keys)
+            } else {
+                Ok(())
+            };
+            if let Some(reason) = drain::drained(Path::new(repo_dir)) {
+                drain::log_skipped(Path::new(repo_dir), &reason);
+            } else if let Err(err) = verified {
+                log::error!("Not deploying {hash:?}: {err:#}");
+                first_run = false;
+            } else {
+                let _deploying = deploying.lock().unwrap_or_else(|err| err.into_inner());
+                if retry_due {
+                    log::info!(
+                        "Retrying the deploy of {repo_dir} {hash:?}, \
+                        which has failed {} time(s) in a row",
+                        deploy_backoff.failures()
+                    );
+                } else {
+                    log::info!("Running a deploy of {repo_dir} {hash:?}");
+                }
+                let since = match &hash {
+                    RepoUpdateStatus::Updated { before, .. } if opts.changed_only && !first_run => {
+                        Some(before.as_str())
+                    }
+                    // Only the stacks that failed need to be retried
+                    RepoUpdateStatus::Same(head) if opts.changed_only && !first_run => {
+                        Some(head.as_str())
+                    }
+                    _ => None,
+                };
+                let deployed = deploy(since).unwrap_or_else(|err| {
+                    log::error!("Error running deploy of {repo_dir}: {err:#}");
+                    false
+                });
+                if deployed {
+                    deploy_backoff.succeeded();
+                    retry_at = None;
+                } else {
+                    let delay = deploy_backoff.failed();
+                    log::warn!(
+                        "The deploy of {repo_dir} will be retried in {} seconds, \
+                        unless the repo is updated sooner",
+                        delay.as_secs()
+                    );
+                    retry_at = Some(Instant::now() + delay);
+                }
+                first_run = false;
+            }
+        }
+        shutdown::set_busy(false);
+        if shutdown::sleep(opts.interval) {
+            break;
+        }
+    }
+    Ok(())
+}
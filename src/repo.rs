@@ -0,0 +1,275 @@
+//! Deploying the stacks of a repo checkout, as `run` does: choosing
+//! which of them need deploying, recording the commit that each was
+//! deployed from, and bringing down those that have been removed.
+use crate::deploy::{
+    docker_command, get_repo_commit_hash, input_files, stack_dir, Deployer, StackStatus,
+};
+use crate::deploy_file::{
+    filter_dependents, DeployFile, DeployMode, DEFAULTS_FILE, HOST_GROUPS_FILE,
+};
+use crate::git::changed_files;
+use crate::{output, stackignore, state};
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+
+impl Deployer<'_> {
+    /// Deploy the stacks of plan, which are those of the repo checkout
+    /// at repo_dir, recording the commit that each was deployed from
+    /// so that it can be shown with [show_versions], unless this is a
+    /// dry run.  When since is set, only the stacks affected by the
+    /// changes since that commit, or that weren't deployed successfully
+    /// from it, are deployed.  Returns the status of each stack that
+    /// was deployed, in the same order as plan.
+    pub fn deploy_repo<'p>(
+        &self,
+        repo_dir: &str,
+        plan: &'p [DeployFile],
+        since: Option<&str>,
+    ) -> Vec<(&'p DeployFile, StackStatus)> {
+        let selected = match since {
+            Some(since) => select_changed(repo_dir, since, plan).unwrap_or_else(|err| {
+                log::warn!(
+                    "Deploying every stack, as the changes could not be determined: {err:#}"
+                );
+                plan.to_vec()
+            }),
+            None => plan.to_vec(),
+        };
+        // The outcomes refer to the entries of plan, rather than to
+        // the copies that were selected from it
+        let outcomes: Vec<(&DeployFile, StackStatus)> = self
+            .deploy(&selected)
+            .into_iter()
+            .filter_map(|(entry, status)| {
+                plan.iter()
+                    .find(|planned| planned.deploy.name == entry.deploy.name)
+                    .map(|planned| (planned, status))
+            })
+            .collect();
+
+        // The stacks that select_changed left out are up to date with head
+        let unchanged: Vec<&DeployFile> = if since.is_some() {
+            plan.iter()
+                .filter(|entry| {
+                    !selected
+                        .iter()
+                        .any(|selected| selected.deploy.name == entry.deploy.name)
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+        if !self.opts.dry_run {
+            record_deployed_commit(repo_dir, &outcomes, &unchanged);
+        }
+        outcomes
+    }
+
+    /// Bring down the stacks that were deployed from repo_dir by an
+    /// earlier run, but which are no longer in plan, then record the
+    /// stacks that are in it now
+    pub fn prune_removed(&self, repo_dir: &str, plan: &[DeployFile]) -> anyhow::Result<()> {
+        let mut state = state::DeployState::load(repo_dir)?;
+
+        let present: BTreeSet<&str> = plan
+            .iter()
+            .map(|entry| entry.deploy.name.as_str())
+            .collect();
+        let removed: Vec<String> = state
+            .stacks
+            .keys()
+            .filter(|name| !present.contains(name.as_str()))
+            .cloned()
+            .collect();
+
+        for name in removed {
+            let stack = &state.stacks[&name];
+            let project = &stack.project;
+            let mut cmd = docker_command(stack.docker_context.as_deref());
+            match stack.mode {
+                DeployMode::Compose => {
+                    log::info!(stack = name.as_str(); "Stack {name} has been removed; bringing down compose project {project}");
+                    cmd.args([
+                        "compose",
+                        "--project-name",
+                        project,
+                        "down",
+                        "--remove-orphans",
+                    ]);
+                }
+                DeployMode::Swarm => {
+                    log::info!(stack = name.as_str(); "Stack {name} has been removed; removing swarm stack {project}");
+                    cmd.args(["stack", "rm", project]);
+                }
+            }
+            let status = output::run(&mut cmd, &name);
+            match status {
+                Ok(status) if status.success() => {
+                    state.stacks.remove(&name);
+                }
+                Ok(status) => {
+                    log::error!(stack = name.as_str(); "Failed to bring down {name}: exit status is {status:?}");
+                }
+                Err(err) => {
+                    log::error!(stack = name.as_str(); "Failed to bring down {name}: {err:#}");
+                }
+            }
+        }
+
+        for entry in plan {
+            state.update(entry);
+        }
+
+        state.save(repo_dir)
+    }
+}
+
+/// Print a table of the commit from which each stack in repo_dir was
+/// last deployed, and whether that is the current commit of the repo
+pub fn show_versions(repo_dir: &str) -> anyhow::Result<()> {
+    let state = state::DeployState::load(repo_dir)?;
+    let head = get_repo_commit_hash(repo_dir).ok();
+    state.show_versions(head.as_deref());
+    Ok(())
+}
+
+/// Record the current commit of repo_dir as the one from which each
+/// of the successfully deployed stacks was deployed, so that it can
+/// be shown by `versions`.  The stacks in unchanged were not deployed,
+/// as nothing that affects them changed since they last were, so what
+/// is running is also what the current commit would deploy.
+fn record_deployed_commit(
+    repo_dir: &str,
+    outcomes: &[(&DeployFile, StackStatus)],
+    unchanged: &[&DeployFile],
+) {
+    // A directory that isn't in a git checkout has no commit to record
+    let commit = match get_repo_commit_hash(repo_dir) {
+        Ok(commit) => commit,
+        Err(err) => {
+            log::debug!("Not recording the deployed commit of {repo_dir}: {err:#}");
+            return;
+        }
+    };
+    let result = (|| {
+        let mut state = state::DeployState::load(repo_dir)?;
+        let deployed = outcomes
+            .iter()
+            .filter(|(_, status)| status.is_deployed())
+            .map(|(entry, _)| *entry);
+        for entry in deployed.chain(unchanged.iter().copied()) {
+            state.update(entry).commit = Some(commit.clone());
+        }
+        state.save(repo_dir)
+    })();
+    if let Err(err) = result {
+        log::warn!("Failed to record the deployed commit: {err:#}");
+    }
+}
+
+/// Returns path relative to repo_dir, resolving any `..` components,
+/// or None if it is not inside repo_dir
+fn repo_relative(repo_dir: &str, path: &Path) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.strip_prefix(repo_dir).ok()?.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            Component::ParentDir if relative.pop() => {}
+            _ => return None,
+        }
+    }
+    Some(relative)
+}
+
+/// Returns true if any of the changed files, relative to repo_dir,
+/// are in the directory of the stack or are inputs of it, such as an
+/// env_file that is shared with other stacks
+fn stack_changed(repo_dir: &str, entry: &DeployFile, changed: &[PathBuf]) -> bool {
+    let Some(dir) = stack_dir(&entry.path)
+        .ok()
+        .and_then(|dir| repo_relative(repo_dir, dir))
+    else {
+        return true;
+    };
+    let inputs: Vec<PathBuf> = input_files(entry)
+        .iter()
+        .filter_map(|path| repo_relative(repo_dir, path))
+        .collect();
+    changed
+        .iter()
+        .any(|path| path.starts_with(&dir) || inputs.contains(path))
+}
+
+/// Select the stacks that need to be deployed because their directories
+/// or input files contain files that changed since the commit `since`, or because they
+/// weren't deployed successfully from that commit, along with the stacks
+/// that depend upon them.  Changes to the files that affect every stack
+/// select all of them.
+fn select_changed(
+    repo_dir: &str,
+    since: &str,
+    sorted: &[DeployFile],
+) -> anyhow::Result<Vec<DeployFile>> {
+    let head = get_repo_commit_hash(repo_dir)?;
+    let changed = changed_files(repo_dir, since, &head)?;
+
+    let shared = [
+        ".secrets.kdbx",
+        DEFAULTS_FILE,
+        HOST_GROUPS_FILE,
+        stackignore::STACK_IGNORE_FILE,
+    ];
+    if let Some(path) = changed
+        .iter()
+        .find(|path| shared.iter().any(|name| path.as_path() == Path::new(name)))
+    {
+        log::info!("{path:?} changed, so every stack will be deployed");
+        return Ok(sorted.to_vec());
+    }
+
+    let state = state::DeployState::load(repo_dir)?;
+    Ok(filter_dependents(sorted.to_vec(), |entry| {
+        let deployed_since = state
+            .stacks
+            .get(&entry.deploy.name)
+            .and_then(|stack| stack.commit.as_deref())
+            == Some(since);
+        if !deployed_since {
+            return true;
+        }
+        stack_changed(repo_dir, entry, &changed)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deploy_file::parse_deploy_file;
+
+    #[test]
+    fn shared_input_files_select_the_stack() {
+        let path = Path::new("/repo/web/stack-deploy.toml");
+        let deploy = parse_deploy_file(
+            path,
+            "name = \"web\"\nruns_on = [\"vm\"]\nenv_file = \"../shared.env\"\n",
+        )
+        .unwrap();
+        let entry = DeployFile {
+            path: path.to_path_buf(),
+            deploy,
+        };
+        let changed = |paths: &[&str]| {
+            stack_changed(
+                "/repo",
+                &entry,
+                &paths.iter().map(PathBuf::from).collect::<Vec<_>>(),
+            )
+        };
+
+        assert!(changed(&["web/compose.yml"]));
+        assert!(changed(&["shared.env"]));
+        assert!(!changed(&["other/compose.yml", "other.env"]));
+        assert!(!changed(&[]));
+    }
+}
@@ -0,0 +1,164 @@
+//! The machine readable report of a deploy, written by `--report`, so
+//! that deploys can be audited, or shipped to a dashboard.
+//!
+//! The report is a JSON object with these fields:
+//!
+//! * `version`: the version of the schema, currently `1`
+//! * `started_at`, `finished_at`: RFC 3339 UTC timestamps, such as
+//!   `2024-05-01T12:00:00Z`
+//! * `commit`: the git commit that was deployed, or `null` if the stacks
+//!   are not in a git checkout
+//! * `hostname`: the name of the host that performed the deploy
+//! * `stacks`: one object per stack, in deploy order, with the fields
+//!   `name`, `path`, `status` (`deployed`, `failed` or `skipped`),
+//!   `duration_seconds` (`null` if the stack was not attempted) and
+//!   `error` (`null` unless it failed or was skipped)
+//!
+//! Fields may be added in later versions, but existing fields will not
+//! be renamed, removed or change their type without a new version.
+use crate::deploy::{Deployer, StackStatus};
+use crate::deploy_file::DeployFile;
+use crate::json::Value;
+use anyhow::Context;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const VERSION: f64 = 1.0;
+
+/// The outcome of one stack of a deploy
+pub struct StackReport {
+    pub name: String,
+    pub path: String,
+    pub status: &'static str,
+    pub duration_seconds: Option<f64>,
+    pub error: Option<String>,
+}
+
+pub struct DeployReport {
+    pub started_at: SystemTime,
+    pub finished_at: SystemTime,
+    pub commit: Option<String>,
+    pub hostname: String,
+    pub stacks: Vec<StackReport>,
+}
+
+impl DeployReport {
+    /// The report of a deploy that started at started_at, and that has
+    /// just finished with outcomes
+    pub fn new(
+        started_at: SystemTime,
+        commit: Option<String>,
+        deployer: &Deployer,
+        outcomes: &[(&DeployFile, StackStatus)],
+    ) -> Self {
+        let stacks = outcomes
+            .iter()
+            .map(|(entry, status)| StackReport {
+                name: entry.deploy.name.clone(),
+                path: entry.path.display().to_string(),
+                status: status.label(),
+                duration_seconds: deployer
+                    .duration(&entry.deploy.name)
+                    .map(|duration| duration.as_secs_f64()),
+                error: status.reason(),
+            })
+            .collect();
+        Self {
+            started_at,
+            finished_at: SystemTime::now(),
+            commit,
+            hostname: gethostname::gethostname().to_string_lossy().to_string(),
+            stacks,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let stacks = self
+            .stacks
+            .iter()
+            .map(|stack| {
+                Value::Object(vec![
+                    ("name".to_string(), stack.name.as_str().into()),
+                    ("path".to_string(), stack.path.as_str().into()),
+                    ("status".to_string(), stack.status.into()),
+                    (
+                        "duration_seconds".to_string(),
+                        stack.duration_seconds.into(),
+                    ),
+                    ("error".to_string(), stack.error.as_deref().into()),
+                ])
+            })
+            .collect();
+        Value::Object(vec![
+            ("version".to_string(), VERSION.into()),
+            (
+                "started_at".to_string(),
+                format_timestamp(self.started_at).into(),
+            ),
+            (
+                "finished_at".to_string(),
+                format_timestamp(self.finished_at).into(),
+            ),
+            ("commit".to_string(), self.commit.as_deref().into()),
+            ("hostname".to_string(), self.hostname.as_str().into()),
+            ("stacks".to_string(), Value::Array(stacks)),
+        ])
+    }
+
+    /// Write the report to path.  It is written to a temporary file
+    /// that is then renamed over path, so that a reader never sees a
+    /// partially written report.
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("{path:?} has no file name"))?
+            .to_string_lossy();
+        let temp = path.with_file_name(format!(".{file_name}.tmp"));
+        let text = format!("{}\n", self.to_json().to_pretty_string());
+        std::fs::write(&temp, text).with_context(|| format!("failed to write {temp:?}"))?;
+        std::fs::rename(&temp, path).with_context(|| format!("failed to replace {path:?}"))
+    }
+}
+
+/// Format time as an RFC 3339 UTC timestamp, with a precision of seconds
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Convert the number of days since the epoch to a civil date; see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn timestamps() {
+        let at = |secs| format_timestamp(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(at(1_714_564_800), "2024-05-01T12:00:00Z");
+        assert_eq!(at(1_735_689_599), "2024-12-31T23:59:59Z");
+    }
+}
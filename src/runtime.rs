@@ -0,0 +1,68 @@
+//! Selection of the container runtime whose CLI is used to run
+//! compose and to inspect containers and images.
+use clap::ValueEnum;
+use std::process::Command;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Runtime {
+    /// `docker compose`
+    Docker,
+    /// `podman compose`, which runs either docker-compose or
+    /// podman-compose against podman
+    Podman,
+}
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+impl Runtime {
+    /// Select the runtime for the remainder of the process.
+    /// Only the first call has any effect.
+    pub fn init(self) {
+        let _ = RUNTIME.set(self);
+    }
+
+    /// Returns the selected runtime
+    pub fn get() -> Self {
+        RUNTIME.get().copied().unwrap_or(Self::Docker)
+    }
+
+    /// The name of the CLI program
+    pub fn program(self) -> &'static str {
+        match self {
+            Self::Docker => "docker",
+            Self::Podman => "podman",
+        }
+    }
+
+    /// The global option that selects a remote host: a docker
+    /// context, or a podman system connection
+    pub fn context_option(self) -> &'static str {
+        match self {
+            Self::Docker => "--context",
+            Self::Podman => "--connection",
+        }
+    }
+
+    /// The `info --format` template that prints the server version
+    pub fn server_version_format(self) -> &'static str {
+        match self {
+            Self::Docker => "{{.ServerVersion}}",
+            Self::Podman => "{{.Version.Version}}",
+        }
+    }
+
+    /// Whether `compose up` supports `--wait` and `--wait-timeout`,
+    /// which podman-compose does not
+    pub fn supports_wait(self) -> bool {
+        match self {
+            Self::Docker => true,
+            Self::Podman => false,
+        }
+    }
+}
+
+/// Returns a command that runs the CLI of the selected runtime
+pub fn command() -> Command {
+    Command::new(Runtime::get().program())
+}
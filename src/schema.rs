@@ -0,0 +1,722 @@
+//! A JSON Schema for `stack-deploy.toml`, and validation of deploy files
+//! against it.  The schema is derived from the serde definition of
+//! [StackDeploy], by tracing the type hints that its Deserialize
+//! implementation gives to the deserializer, so that it always matches
+//! the fields that are actually accepted.
+use crate::deploy_file::{DependencyTable, StackDeploy, WaitForTable};
+use crate::json::Value;
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The shape of a value, as traced from its Deserialize implementation
+#[derive(Debug, Clone, Default)]
+pub enum Shape {
+    #[default]
+    Any,
+    Bool,
+    Integer {
+        unsigned: bool,
+    },
+    Float,
+    String,
+    Optional(Box<Shape>),
+    Array(Box<Shape>),
+    /// A table with arbitrary keys, whose values have this shape
+    Map(Box<Shape>),
+    /// A table with a fixed set of fields; unknown fields are rejected
+    Struct(Vec<Field>),
+    /// One of a set of strings
+    Enum(Vec<&'static str>),
+    /// A value that matches any of these shapes
+    OneOf(Vec<Shape>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: &'static str,
+    pub shape: Shape,
+    pub required: bool,
+}
+
+/// Untagged enums try each of their variants against a buffered copy
+/// of the value, so their shapes can't be traced.  The shapes of those
+/// used by the deploy file are described here, keyed by path.
+fn untagged(path: &str) -> Option<Shape> {
+    match path {
+        "compose_file" => Some(Shape::OneOf(vec![
+            Shape::String,
+            Shape::Array(Box::new(Shape::String)),
+        ])),
+        "depends_on[]" => Some(Shape::OneOf(vec![
+            Shape::String,
+            trace::<DependencyTable>().ok()?,
+        ])),
+        "wait_for[]" => Some(Shape::OneOf(vec![
+            Shape::String,
+            trace::<WaitForTable>().ok()?,
+        ])),
+        _ => None,
+    }
+}
+
+/// Trace the shape of T
+pub fn trace<T: DeserializeOwned>() -> anyhow::Result<Shape> {
+    let mut shape = trace_omitting::<T>(None)
+        .map_err(|err| anyhow::anyhow!("failed to trace the shape of the type: {err}"))?;
+
+    // A field is required if deserializing fails without it
+    let mut paths = vec![];
+    struct_field_paths(&shape, "", &mut paths);
+    let required: Vec<String> = paths
+        .into_iter()
+        .filter(|path| trace_omitting::<T>(Some(path.clone())).is_err())
+        .collect();
+    mark_required(&mut shape, "", &required);
+    Ok(shape)
+}
+
+fn trace_omitting<T: DeserializeOwned>(omit: Option<String>) -> Result<Shape, TraceError> {
+    let tracer = Tracer {
+        slot: Slot::default(),
+        path: String::new(),
+        omit: Rc::new(omit),
+    };
+    let slot = tracer.slot.clone();
+    T::deserialize(tracer)?;
+    let shape = slot.take();
+    Ok(shape)
+}
+
+fn field_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{parent}.{name}")
+    }
+}
+
+fn struct_field_paths(shape: &Shape, path: &str, paths: &mut Vec<String>) {
+    match shape {
+        Shape::Struct(fields) => {
+            for field in fields {
+                let path = field_path(path, field.name);
+                struct_field_paths(&field.shape, &path, paths);
+                paths.push(path);
+            }
+        }
+        Shape::Optional(inner) => struct_field_paths(inner, path, paths),
+        Shape::Array(inner) => struct_field_paths(inner, &format!("{path}[]"), paths),
+        Shape::Map(inner) => struct_field_paths(inner, &format!("{path}{{}}"), paths),
+        _ => {}
+    }
+}
+
+fn mark_required(shape: &mut Shape, path: &str, required: &[String]) {
+    match shape {
+        Shape::Struct(fields) => {
+            for field in fields {
+                let path = field_path(path, field.name);
+                field.required = required.contains(&path);
+                mark_required(&mut field.shape, &path, required);
+            }
+        }
+        Shape::Optional(inner) => mark_required(inner, path, required),
+        Shape::Array(inner) => mark_required(inner, &format!("{path}[]"), required),
+        Shape::Map(inner) => mark_required(inner, &format!("{path}{{}}"), required),
+        _ => {}
+    }
+}
+
+/// Returns the JSON Schema for `stack-deploy.toml`
+pub fn stack_deploy_schema() -> anyhow::Result<Value> {
+    let shape = trace::<StackDeploy>()?;
+    let Value::Object(mut fields) = to_json_schema(&shape) else {
+        anyhow::bail!("StackDeploy is not a struct");
+    };
+    fields.insert(
+        0,
+        (
+            "$schema".to_string(),
+            "https://json-schema.org/draft/2020-12/schema".into(),
+        ),
+    );
+    fields.insert(1, ("title".to_string(), "stack-deploy.toml".into()));
+    Ok(Value::Object(fields))
+}
+
+fn object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Object(
+        fields
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+}
+
+fn to_json_schema(shape: &Shape) -> Value {
+    match shape {
+        Shape::Any => object(vec![]),
+        Shape::Bool => object(vec![("type", "boolean".into())]),
+        Shape::Integer { unsigned: true } => {
+            object(vec![("type", "integer".into()), ("minimum", 0.0.into())])
+        }
+        Shape::Integer { unsigned: false } => object(vec![("type", "integer".into())]),
+        Shape::Float => object(vec![("type", "number".into())]),
+        Shape::String => object(vec![("type", "string".into())]),
+        Shape::Optional(inner) => to_json_schema(inner),
+        Shape::Array(inner) => object(vec![
+            ("type", "array".into()),
+            ("items", to_json_schema(inner)),
+        ]),
+        Shape::Map(inner) => object(vec![
+            ("type", "object".into()),
+            ("additionalProperties", to_json_schema(inner)),
+        ]),
+        Shape::Struct(fields) => object(vec![
+            ("type", "object".into()),
+            (
+                "properties",
+                Value::Object(
+                    fields
+                        .iter()
+                        .map(|field| (field.name.to_string(), to_json_schema(&field.shape)))
+                        .collect(),
+                ),
+            ),
+            (
+                "required",
+                Value::Array(
+                    fields
+                        .iter()
+                        .filter(|field| field.required)
+                        .map(|field| field.name.into())
+                        .collect(),
+                ),
+            ),
+            ("additionalProperties", Value::Bool(false)),
+        ]),
+        Shape::Enum(variants) => object(vec![
+            ("type", "string".into()),
+            (
+                "enum",
+                Value::Array(variants.iter().map(|&v| v.into()).collect()),
+            ),
+        ]),
+        Shape::OneOf(shapes) => object(vec![(
+            "anyOf",
+            Value::Array(shapes.iter().map(to_json_schema).collect()),
+        )]),
+    }
+}
+
+/// A description of what a value of shape must be, for error messages
+fn describe(shape: &Shape) -> String {
+    match shape {
+        Shape::Any => "any value".to_string(),
+        Shape::Bool => "a boolean".to_string(),
+        Shape::Integer { unsigned: true } => "a non-negative integer".to_string(),
+        Shape::Integer { unsigned: false } => "an integer".to_string(),
+        Shape::Float => "a number".to_string(),
+        Shape::String => "a string".to_string(),
+        Shape::Optional(inner) => describe(inner),
+        Shape::Array(inner) => match &**inner {
+            Shape::String => "an array of strings".to_string(),
+            inner => format!("an array, each item of which is {}", describe(inner)),
+        },
+        Shape::Map(_) | Shape::Struct(_) => "a table".to_string(),
+        Shape::Enum(variants) => format!("one of {}", variants.join(", ")),
+        Shape::OneOf(shapes) => shapes.iter().map(describe).collect::<Vec<_>>().join(" or "),
+    }
+}
+
+/// Check value against shape, returning a description of each problem,
+/// prefixed by the path of the value within the document
+pub fn check(value: &toml::Value, shape: &Shape) -> Vec<String> {
+    let mut problems = vec![];
+    check_value(value, shape, "", &mut problems);
+    problems
+}
+
+fn check_value(value: &toml::Value, shape: &Shape, path: &str, problems: &mut Vec<String>) {
+    let display_path = if path.is_empty() { "(root)" } else { path };
+    let ok = match (shape, value) {
+        (Shape::Any, _) => true,
+        (Shape::Bool, toml::Value::Boolean(_)) => true,
+        (Shape::Integer { unsigned }, toml::Value::Integer(i)) => !unsigned || *i >= 0,
+        (Shape::Float, toml::Value::Float(_) | toml::Value::Integer(_)) => true,
+        (Shape::String, toml::Value::String(_)) => true,
+        (Shape::Optional(inner), _) => {
+            check_value(value, inner, path, problems);
+            true
+        }
+        (Shape::Array(inner), toml::Value::Array(items)) => {
+            for (idx, item) in items.iter().enumerate() {
+                check_value(item, inner, &format!("{path}[{idx}]"), problems);
+            }
+            true
+        }
+        (Shape::Map(inner), toml::Value::Table(table)) => {
+            for (key, item) in table {
+                check_value(item, inner, &field_path(path, key), problems);
+            }
+            true
+        }
+        (Shape::Struct(fields), toml::Value::Table(table)) => {
+            for key in table.keys() {
+                if !fields.iter().any(|field| field.name == key) {
+                    problems.push(format!("{}: unknown field", field_path(path, key)));
+                }
+            }
+            for field in fields {
+                match table.get(field.name) {
+                    Some(item) => {
+                        check_value(item, &field.shape, &field_path(path, field.name), problems)
+                    }
+                    None if field.required => problems.push(format!(
+                        "{display_path}: missing required field {}",
+                        field.name
+                    )),
+                    None => {}
+                }
+            }
+            true
+        }
+        (Shape::Enum(variants), toml::Value::String(s)) => variants.contains(&s.as_str()),
+        (Shape::OneOf(shapes), _) => {
+            // If only one of the shapes is of the right kind, such as when
+            // the value is a table, report the problems with it in detail
+            let candidates: Vec<&Shape> = shapes
+                .iter()
+                .filter(|shape| same_kind(shape, value))
+                .collect();
+            match candidates[..] {
+                [shape] => {
+                    check_value(value, shape, path, problems);
+                    true
+                }
+                _ => shapes.iter().any(|shape| check(value, shape).is_empty()),
+            }
+        }
+        _ => false,
+    };
+    if !ok {
+        problems.push(format!("{display_path}: expected {}", describe(shape)));
+    }
+}
+
+/// Whether value is of the kind of TOML value, such as a table or an
+/// array, that shape describes, whether or not it otherwise matches
+fn same_kind(shape: &Shape, value: &toml::Value) -> bool {
+    matches!(
+        (shape, value),
+        (Shape::Any, _)
+            | (Shape::Bool, toml::Value::Boolean(_))
+            | (Shape::Integer { .. }, toml::Value::Integer(_))
+            | (
+                Shape::Float,
+                toml::Value::Float(_) | toml::Value::Integer(_)
+            )
+            | (Shape::String | Shape::Enum(_), toml::Value::String(_))
+            | (Shape::Array(_), toml::Value::Array(_))
+            | (Shape::Map(_) | Shape::Struct(_), toml::Value::Table(_))
+    ) || match shape {
+        Shape::Optional(inner) => same_kind(inner, value),
+        Shape::OneOf(shapes) => shapes.iter().any(|shape| same_kind(shape, value)),
+        _ => false,
+    }
+}
+
+type Slot = Rc<RefCell<Shape>>;
+
+#[derive(Debug)]
+struct TraceError(String);
+
+impl std::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl de::Error for TraceError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+/// A deserializer that records the shape that is requested of it into
+/// slot, and then produces a placeholder value of that shape: an empty
+/// string, zero or false.  Deserialize implementations must accept those,
+/// so checks of the values themselves belong after parsing.
+struct Tracer {
+    slot: Slot,
+    /// The path of the value within the document
+    path: String,
+    /// The path of a struct field to leave out
+    omit: Rc<Option<String>>,
+}
+
+impl Tracer {
+    fn child(&self, path: String) -> Self {
+        Self {
+            slot: Slot::default(),
+            path,
+            omit: self.omit.clone(),
+        }
+    }
+
+    fn set(&self, shape: Shape) {
+        *self.slot.borrow_mut() = shape;
+    }
+}
+
+macro_rules! trace_as {
+    ($($method:ident => $shape:expr, $visit:ident($($value:expr)?);)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.set($shape);
+                visitor.$visit($($value)?)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Tracer {
+    type Error = TraceError;
+
+    trace_as! {
+        deserialize_bool => Shape::Bool, visit_bool(false);
+        deserialize_i8 => Shape::Integer { unsigned: false }, visit_i64(0);
+        deserialize_i16 => Shape::Integer { unsigned: false }, visit_i64(0);
+        deserialize_i32 => Shape::Integer { unsigned: false }, visit_i64(0);
+        deserialize_i64 => Shape::Integer { unsigned: false }, visit_i64(0);
+        deserialize_u8 => Shape::Integer { unsigned: true }, visit_u64(0);
+        deserialize_u16 => Shape::Integer { unsigned: true }, visit_u64(0);
+        deserialize_u32 => Shape::Integer { unsigned: true }, visit_u64(0);
+        deserialize_u64 => Shape::Integer { unsigned: true }, visit_u64(0);
+        deserialize_f32 => Shape::Float, visit_f64(0.0);
+        deserialize_f64 => Shape::Float, visit_f64(0.0);
+        deserialize_char => Shape::String, visit_char('x');
+        deserialize_str => Shape::String, visit_str("");
+        deserialize_string => Shape::String, visit_str("");
+        deserialize_identifier => Shape::String, visit_str("");
+        deserialize_bytes => Shape::Any, visit_bytes(&[]);
+        deserialize_byte_buf => Shape::Any, visit_bytes(&[]);
+        deserialize_unit => Shape::Any, visit_unit();
+        deserialize_ignored_any => Shape::Any, visit_unit();
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        // Each of the untagged enums accepts a string
+        self.set(untagged(&self.path).unwrap_or_default());
+        visitor.visit_str("")
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let child = self.child(self.path.clone());
+        let slot = child.slot.clone();
+        let value = visitor.visit_some(child)?;
+        self.set(Shape::Optional(Box::new(slot.take())));
+        Ok(value)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let child = self.child(format!("{}[]", self.path));
+        let slot = child.slot.clone();
+        let value = visitor.visit_seq(OneItem(Some(child)))?;
+        self.set(Shape::Array(Box::new(slot.take())));
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let child = self.child(format!("{}{{}}", self.path));
+        let slot = child.slot.clone();
+        let value = visitor.visit_map(Fields {
+            names: vec![""],
+            tracers: vec![child],
+        })?;
+        self.set(Shape::Map(Box::new(slot.take())));
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        let names: Vec<&'static str> = fields.to_vec();
+        let tracers: Vec<Tracer> = names
+            .iter()
+            .map(|name| self.child(field_path(&self.path, name)))
+            .collect();
+        let slots: Vec<Slot> = tracers.iter().map(|t| t.slot.clone()).collect();
+        let (names, tracers): (Vec<_>, Vec<_>) = names
+            .into_iter()
+            .zip(tracers)
+            .filter(|(_, tracer)| self.omit.as_deref() != Some(tracer.path.as_str()))
+            .unzip();
+        let value = visitor.visit_map(Fields {
+            names: names.into_iter().rev().collect(),
+            tracers: tracers.into_iter().rev().collect(),
+        })?;
+        self.set(Shape::Struct(
+            fields
+                .iter()
+                .zip(slots)
+                .map(|(name, slot)| Field {
+                    name,
+                    shape: slot.take(),
+                    required: false,
+                })
+                .collect(),
+        ));
+        Ok(value)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.set(Shape::Enum(variants.to_vec()));
+        let variant = variants.first().copied().unwrap_or_default();
+        visitor.visit_enum(IntoDeserializer::<TraceError>::into_deserializer(variant))
+    }
+}
+
+/// Produces a sequence of a single traced item
+struct OneItem(Option<Tracer>);
+
+impl<'de> de::SeqAccess<'de> for OneItem {
+    type Error = TraceError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        match self.0.take() {
+            Some(tracer) => seed.deserialize(tracer).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Produces a map of the named fields, whose values are traced.
+/// Both lists are in reverse order, so that they can be popped.
+struct Fields {
+    names: Vec<&'static str>,
+    tracers: Vec<Tracer>,
+}
+
+impl<'de> de::MapAccess<'de> for Fields {
+    type Error = TraceError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        match self.names.pop() {
+            Some(name) => seed
+                .deserialize(IntoDeserializer::<TraceError>::into_deserializer(name))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let tracer = self
+            .tracers
+            .pop()
+            .ok_or_else(|| TraceError("value requested before key".to_string()))?;
+        seed.deserialize(tracer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(text: &str) -> Vec<String> {
+        let shape = trace::<StackDeploy>().unwrap();
+        check(&toml::from_str(text).unwrap(), &shape)
+    }
+
+    /// Finds the paths within shape at which the shape is unknown
+    fn any_paths(shape: &Shape, path: &str, paths: &mut Vec<String>) {
+        match shape {
+            Shape::Any => paths.push(path.to_string()),
+            Shape::Struct(fields) => {
+                for field in fields {
+                    any_paths(&field.shape, &field_path(path, field.name), paths);
+                }
+            }
+            Shape::Optional(inner) => any_paths(inner, path, paths),
+            Shape::Array(inner) => any_paths(inner, &format!("{path}[]"), paths),
+            Shape::Map(inner) => any_paths(inner, &format!("{path}{{}}"), paths),
+            Shape::OneOf(shapes) => {
+                for shape in shapes {
+                    any_paths(shape, path, paths);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn traces_stack_deploy() {
+        let shape = trace::<StackDeploy>().unwrap();
+        let Shape::Struct(fields) = &shape else {
+            panic!("StackDeploy traced as {shape:?}");
+        };
+        let required: Vec<&str> = fields
+            .iter()
+            .filter(|field| field.required)
+            .map(|field| field.name)
+            .collect();
+        assert_eq!(required, ["name", "runs_on"]);
+        stack_deploy_schema().unwrap();
+    }
+
+    #[test]
+    fn every_untagged_field_is_described() {
+        // An untagged enum that is missing from untagged() traces as Any
+        let mut paths = vec![];
+        any_paths(&trace::<StackDeploy>().unwrap(), "", &mut paths);
+        assert_eq!(paths, Vec::<String>::new());
+    }
+
+    #[test]
+    fn valid_file() {
+        let text = r#"
+            name = "app"
+            runs_on = ["vm", "@group"]
+            depends_on = ["db"]
+            order = -1
+            wait_for = ["tcp://db:5432"]
+            pull_policy = "always"
+            deploy_timeout = 30
+
+            [secret_env]
+            DB_PASSWORD = "Database/DB/password"
+
+            [healthcheck]
+            retries = 3
+
+            [[registry_login]]
+            registry = "ghcr.io"
+            username = "me"
+            password = "Database/ghcr/password"
+        "#;
+        assert_eq!(problems(text), Vec::<String>::new());
+    }
+
+    #[test]
+    fn invalid_file() {
+        let text = r#"
+            runs_on = "vm"
+            order = "first"
+            pull_policy = "sometimes"
+            retries = -1
+            colour = "blue"
+
+            [healthcheck]
+            retries = 3
+            interval = 5
+        "#;
+        let mut problems = problems(text);
+        problems.sort();
+        assert_eq!(
+            problems,
+            [
+                "(root): missing required field name",
+                "colour: unknown field",
+                "healthcheck.interval: unknown field",
+                "order: expected an integer",
+                "pull_policy: expected one of always, missing, never",
+                "retries: expected a non-negative integer",
+                "runs_on: expected an array of strings",
+            ]
+        );
+    }
+
+    #[test]
+    fn compose_file() {
+        let base = "name = 'app'\nruns_on = ['vm']\n";
+        assert!(problems(&format!("{base}compose_file = 'a.yml'")).is_empty());
+        assert!(problems(&format!("{base}compose_file = ['a.yml', 'b.yml']")).is_empty());
+        assert_eq!(
+            problems(&format!("{base}compose_file = 1")),
+            ["compose_file: expected a string or an array of strings"]
+        );
+    }
+
+    #[test]
+    fn depends_on() {
+        let base = "name = 'app'\nruns_on = ['vm']\n";
+        assert!(problems(&format!("{base}depends_on = ['db']")).is_empty());
+        assert!(problems(&format!(
+            "{base}depends_on = [{{ stack = 'db', healthy = true, timeout = 60 }}]"
+        ))
+        .is_empty());
+        assert_eq!(
+            problems(&format!("{base}depends_on = [{{ healthy = true }}]")),
+            ["depends_on[0]: missing required field stack"]
+        );
+    }
+
+    #[test]
+    fn wait_for() {
+        let base = "name = 'app'\nruns_on = ['vm']\n";
+        assert!(problems(&format!("{base}wait_for = ['tcp://db:5432']")).is_empty());
+        assert!(problems(&format!(
+            "{base}wait_for = [{{ url = 'https://api/health', timeout = 60 }}]"
+        ))
+        .is_empty());
+        assert_eq!(
+            problems(&format!(
+                "{base}wait_for = [{{ url = 'tcp://db:5432', every = 1 }}]"
+            )),
+            ["wait_for[0].every: unknown field"]
+        );
+    }
+}
@@ -1,39 +1,264 @@
 use anyhow::Context;
 use keepass::db::NodeRef;
 use keepass::{Database, DatabaseKey};
+use std::path::Path;
+use std::sync::OnceLock;
 
+/// How deeply field references may refer to other field references
+/// before they are assumed to form a cycle
+const MAX_REFERENCE_DEPTH: usize = 10;
+
+/// Maps the field codes of a field reference to the fields that they
+/// name.  `I`, for the UUID of the entry, and `O`, for its custom
+/// fields, are handled separately.
+fn reference_field(code: &str) -> Option<&'static str> {
+    match code.to_ascii_uppercase().as_str() {
+        "T" => Some("Title"),
+        "U" => Some("UserName"),
+        "P" => Some("Password"),
+        "A" => Some("URL"),
+        "N" => Some("Notes"),
+        _ => None,
+    }
+}
+
+/// Whether the search_in field of entry matches text, ignoring case
+fn reference_matches(entry: &keepass::db::Entry, search_in: &str, text: &str) -> bool {
+    if search_in.eq_ignore_ascii_case("I") {
+        return entry
+            .get_uuid()
+            .as_simple()
+            .to_string()
+            .eq_ignore_ascii_case(text);
+    }
+    if search_in.eq_ignore_ascii_case("O") {
+        return entry.fields.keys().any(|k| {
+            !["Title", "UserName", "Password", "URL", "Notes"].contains(&k.as_str())
+                && entry.get(k).is_some_and(|v| v.eq_ignore_ascii_case(text))
+        });
+    }
+    reference_field(search_in)
+        .and_then(|field| entry.get(field))
+        .is_some_and(|v| v.eq_ignore_ascii_case(text))
+}
+
+/// A source of secret values, addressed by a `/`-separated path
+pub trait SecretStore: Sync {
+    /// Returns the value at path, or None if it could not be found
+    fn resolve_value(&self, path: &str) -> Option<String>;
+
+    /// Returns the content of the binary attachment at path, or None
+    /// if it could not be found.  Only KeePass supports attachments.
+    fn resolve_binary(&self, _path: &str) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+impl SecretStore for KeePassDB {
+    fn resolve_value(&self, path: &str) -> Option<String> {
+        KeePassDB::resolve_value(self, path)
+    }
+
+    fn resolve_binary(&self, path: &str) -> Option<Vec<u8>> {
+        KeePassDB::resolve_binary(self, path)
+    }
+}
+
+/// The content of an attachment, and the path by which it is addressed
+struct Attachment {
+    path: Vec<String>,
+    content: Vec<u8>,
+}
+
+/// A collection of one or more KeePass databases.
+/// The first element of a secret path selects the database whose
+/// root group has that name.
 pub struct KeePassDB {
-    db: Database,
+    dbs: Vec<Database>,
+    /// The encrypted content and key of each of dbs, which are needed
+    /// to recover the attachments of its entries
+    sources: Vec<(Vec<u8>, DatabaseKey)>,
+    /// The attachments of all of the databases, which are indexed
+    /// when they are first needed, as that decrypts each one again
+    attachments: OnceLock<Vec<Attachment>>,
 }
 
 impl KeePassDB {
-    pub fn open_with_password(path: &str, password: &str) -> anyhow::Result<Self> {
-        let mut db_file = std::fs::File::open(path)
-            .with_context(|| format!("failed to open kdbx file {path}"))?;
-        let key = DatabaseKey::new().with_password(password);
+    /// Open each of the databases using the same composite key.
+    /// It is an error for more than one of them to have the same
+    /// root group name, as paths would then be ambiguous.
+    pub fn open_all(
+        paths: &[String],
+        password: Option<&str>,
+        keyfile: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let mut dbs: Vec<Database> = vec![];
+        let mut sources = vec![];
+        let mut names: Vec<(String, &str)> = vec![];
+        for path in paths {
+            let opened = Self::open_with_key(path, password, keyfile)?;
+            for db in &opened.dbs {
+                if let Some((_, other)) = names
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(&db.root.name))
+                {
+                    anyhow::bail!(
+                        "{path} and {other} both have a root group named {}, \
+                        so secret paths would be ambiguous. Rename the root \
+                        group of one of them.",
+                        db.root.name
+                    );
+                }
+                names.push((db.root.name.clone(), path));
+            }
+            dbs.extend(opened.dbs);
+            sources.extend(opened.sources);
+        }
+        Ok(Self {
+            dbs,
+            sources,
+            attachments: OnceLock::new(),
+        })
+    }
+
+    /// Open the database using a composite key made up of an optional
+    /// password and an optional key file.
+    pub fn open_with_key(
+        path: &str,
+        password: Option<&str>,
+        keyfile: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("failed to open kdbx file {path}"))?;
+        let mut key = DatabaseKey::new();
+        if let Some(password) = password {
+            key = key.with_password(password);
+        }
+        if let Some(keyfile) = keyfile {
+            let mut f = std::fs::File::open(keyfile)
+                .with_context(|| format!("failed to open key file {keyfile:?}"))?;
+            key = key
+                .with_keyfile(&mut f)
+                .with_context(|| format!("failed to read key file {keyfile:?}"))?;
+        }
         log::debug!("Opening database");
-        let db = Database::open(&mut db_file, key)?;
+        let db = Database::parse(&data, key.clone())
+            .with_context(|| format!("failed to open kdbx file {path}"))?;
         log::debug!("Database opened");
 
-        Ok(Self { db })
+        Ok(Self {
+            dbs: vec![db],
+            sources: vec![(data, key)],
+            attachments: OnceLock::new(),
+        })
+    }
+
+    /// Decrypt the XML of each database, to find the attachments of
+    /// its entries, and retrieve their content
+    fn index_attachments(&self) -> Vec<Attachment> {
+        let mut result = vec![];
+        for (db, (data, key)) in self.dbs.iter().zip(&self.sources) {
+            let refs = Database::get_xml(&mut data.as_slice(), key.clone())
+                .map_err(anyhow::Error::from)
+                .and_then(|xml| crate::attachments::index(&xml));
+            let refs = match refs {
+                Ok(refs) => refs,
+                Err(err) => {
+                    log::warn!(
+                        "Failed to index the attachments of {}: {err:#}",
+                        db.root.name
+                    );
+                    continue;
+                }
+            };
+            for attachment in refs {
+                // KDBX4 keeps the content in its inner header, whereas
+                // KDBX3 has it in the metadata
+                let content = if db.header_attachments.is_empty() {
+                    db.meta
+                        .binaries
+                        .binaries
+                        .iter()
+                        .find(|b| b.identifier.as_deref() == Some(attachment.reference.as_str()))
+                        .map(|b| b.content.clone())
+                } else {
+                    attachment
+                        .reference
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|idx| db.header_attachments.get(idx))
+                        .map(|a| a.content.clone())
+                };
+                match content {
+                    Some(content) => result.push(Attachment {
+                        path: attachment.path,
+                        content,
+                    }),
+                    None => log::warn!(
+                        "Attachment {} refers to missing data {}",
+                        attachment.path.join("/"),
+                        attachment.reference
+                    ),
+                }
+            }
+        }
+        result
+    }
+
+    /// Given a path like "Database/group/entryname/attachments/name"
+    /// returns the content of the named attachment of the entry.
+    /// The path elements are case insensitive.
+    pub fn resolve_binary(&self, path: &str) -> Option<Vec<u8>> {
+        let elements: Vec<&str> = path.split('/').collect();
+        let [entry @ .., attachments, name] = &elements[..] else {
+            return None;
+        };
+        if !attachments.eq_ignore_ascii_case("attachments") {
+            return None;
+        }
+
+        let matches = |attachment: &&Attachment| {
+            let [group_path @ .., attachment_name] = &attachment.path[..] else {
+                return false;
+            };
+            attachment_name.eq_ignore_ascii_case(name)
+                && group_path.len() == entry.len()
+                && group_path
+                    .iter()
+                    .zip(entry)
+                    .all(|(a, b)| a.eq_ignore_ascii_case(b))
+        };
+        self.attachments
+            .get_or_init(|| self.index_attachments())
+            .iter()
+            .find(matches)
+            .map(|attachment| attachment.content.clone())
+    }
+
+    /// Returns the root group of each database
+    pub fn roots(&self) -> impl Iterator<Item = &keepass::db::Group> {
+        self.dbs.iter().map(|db| &db.root)
     }
 
     /// Given a path like "Database/group/group/entryname/fieldname"
     /// returns the string value of the field.
     /// The path elements are case insensitive.
+    /// A fieldname of "otp" or "totp" produces the current TOTP code
+    /// for entries that have OTP configured.
+    /// KeePass field references in the value, such as `{REF:P@T:title}`,
+    /// are replaced with the fields to which they refer.
     pub fn resolve_value(&self, path: &str) -> Option<String> {
         fn resolve(parent: NodeRef, path: &[&str]) -> Option<String> {
-            let element = path.get(0)?;
+            let element = path.first()?;
 
             match parent {
                 NodeRef::Group(group) => {
-                    if !group.name.eq_ignore_ascii_case(*element) {
+                    if !group.name.eq_ignore_ascii_case(element) {
                         return None;
                     }
                     for child in &group.children {
-                        match resolve(child.as_ref(), &path[1..]) {
-                            Some(node) => return Some(node),
-                            None => {}
+                        if let Some(node) = resolve(child.as_ref(), &path[1..]) {
+                            return Some(node);
                         }
                     }
                     None
@@ -41,7 +266,7 @@ impl KeePassDB {
                 NodeRef::Entry(entry) => {
                     if !entry
                         .get_title()
-                        .map(|title| title.eq_ignore_ascii_case(*element))
+                        .map(|title| title.eq_ignore_ascii_case(element))
                         .unwrap_or(false)
                     {
                         return None;
@@ -54,10 +279,16 @@ impl KeePassDB {
                         return None;
                     }
 
+                    if element.eq_ignore_ascii_case("otp") || element.eq_ignore_ascii_case("totp") {
+                        // Compute the current code rather than returning
+                        // the raw OTP settings
+                        return crate::totp::current_code(entry);
+                    }
+
                     // We iterate the elements so that we can do a case
                     // insensitive comparison
                     for k in entry.fields.keys() {
-                        if k.eq_ignore_ascii_case(*element) {
+                        if k.eq_ignore_ascii_case(element) {
                             return entry.get(k).map(|s| s.to_string());
                         }
                     }
@@ -68,6 +299,151 @@ impl KeePassDB {
         }
 
         let elements: Vec<&str> = path.split('/').collect();
-        resolve(NodeRef::Group(&self.db.root), &elements)
+        let root = elements.first()?;
+        let db = self
+            .dbs
+            .iter()
+            .find(|db| db.root.name.eq_ignore_ascii_case(root))?;
+        let value = resolve(NodeRef::Group(&db.root), &elements)?;
+        match self.expand_references(&value, 0) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                log::error!("Failed to resolve {path}: {err:#}");
+                None
+            }
+        }
+    }
+
+    /// Replace each field reference in value, of the form
+    /// `{REF:<wanted>@<search in>:<text>}`, with the wanted field of
+    /// the first entry whose search in field matches text.
+    /// The referenced fields may themselves contain references.
+    fn expand_references(&self, value: &str, depth: usize) -> anyhow::Result<String> {
+        const PREFIX: &str = "{REF:";
+        let mut expanded = String::new();
+        let mut rest = value;
+        while let Some(start) = rest.to_ascii_uppercase().find(PREFIX) {
+            let after = &rest[start + PREFIX.len()..];
+            let Some(end) = after.find('}') else {
+                // Unterminated, so not a reference
+                break;
+            };
+            let reference = &rest[start..start + PREFIX.len() + end + 1];
+            anyhow::ensure!(
+                depth < MAX_REFERENCE_DEPTH,
+                "field reference {reference} is nested more than \
+                {MAX_REFERENCE_DEPTH} deep; the references probably form a cycle"
+            );
+            let field = self.follow_reference(reference, &after[..end])?;
+            expanded.push_str(&rest[..start]);
+            expanded.push_str(&self.expand_references(&field, depth + 1)?);
+            rest = &after[end + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    /// Returns the raw value of the field referred to by reference,
+    /// whose `<wanted>@<search in>:<text>` portion is spec
+    fn follow_reference(&self, reference: &str, spec: &str) -> anyhow::Result<String> {
+        let parsed = spec.split_once('@').and_then(|(wanted, search)| {
+            let (search_in, text) = search.split_once(':')?;
+            Some((wanted, search_in, text))
+        });
+        let Some((wanted, search_in, text)) = parsed else {
+            anyhow::bail!("malformed field reference {reference}");
+        };
+        let known = |code: &str, others: &[&str]| {
+            reference_field(code).is_some() || others.iter().any(|o| code.eq_ignore_ascii_case(o))
+        };
+        anyhow::ensure!(
+            known(wanted, &["I"]) && known(search_in, &["I", "O"]),
+            "unsupported field in field reference {reference}"
+        );
+
+        let entry = self
+            .dbs
+            .iter()
+            .flat_map(|db| db.root.iter())
+            .find_map(|node| match node {
+                NodeRef::Entry(entry) if reference_matches(entry, search_in, text) => Some(entry),
+                _ => None,
+            })
+            .with_context(|| format!("no entry matches the field reference {reference}"))?;
+
+        Ok(match reference_field(wanted) {
+            Some(field) => entry.get(field).unwrap_or_default().to_string(),
+            None => entry
+                .get_uuid()
+                .as_simple()
+                .to_string()
+                .to_ascii_uppercase(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keepass::db::{Entry, Group, Value};
+
+    fn entry(title: &str, user: &str, password: &str) -> Entry {
+        let mut entry = Entry::new();
+        for (key, value) in [("Title", title), ("UserName", user), ("Password", password)] {
+            entry
+                .fields
+                .insert(key.to_string(), Value::Unprotected(value.to_string()));
+        }
+        entry
+    }
+
+    fn store(entries: Vec<Entry>) -> KeePassDB {
+        let mut db = Database::new(Default::default());
+        let mut group = Group::new("Apps");
+        for entry in entries {
+            group.add_child(entry);
+        }
+        db.root.add_child(group);
+        KeePassDB {
+            dbs: vec![db],
+            sources: vec![],
+            attachments: OnceLock::new(),
+        }
+    }
+
+    #[test]
+    fn field_references() {
+        let db = store(vec![
+            entry("Db", "admin", "hunter2"),
+            entry("App", "{ref:u@t:db}", "{REF:P@T:Db}"),
+            entry("Nested", "", "x-{REF:P@T:App}-y"),
+            entry("Unterminated", "", "{REF:P@T:Db"),
+            entry("Loop", "", "{REF:P@T:Loop}"),
+        ]);
+
+        assert_eq!(
+            db.resolve_value("Root/Apps/App/Password").unwrap(),
+            "hunter2"
+        );
+        assert_eq!(db.resolve_value("Root/Apps/App/UserName").unwrap(), "admin");
+        assert_eq!(
+            db.resolve_value("Root/Apps/Nested/Password").unwrap(),
+            "x-hunter2-y"
+        );
+        assert_eq!(
+            db.resolve_value("Root/Apps/Unterminated/Password").unwrap(),
+            "{REF:P@T:Db"
+        );
+
+        let err = db.expand_references("{REF:P@T:Loop}", 0).unwrap_err();
+        assert!(
+            format!("{err:#}").contains(&format!("nested more than {MAX_REFERENCE_DEPTH} deep"))
+        );
+        assert_eq!(db.resolve_value("Root/Apps/Loop/Password"), None);
+
+        let err = db.expand_references("{REF:P@T:Missing}", 0).unwrap_err();
+        assert!(format!("{err:#}").contains("no entry matches"));
+        let err = db.expand_references("{REF:P}", 0).unwrap_err();
+        assert!(format!("{err:#}").contains("malformed field reference"));
     }
 }
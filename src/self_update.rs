@@ -0,0 +1,207 @@
+//! Replace the running binary with the one from the latest GitHub
+//! release, for those who run it directly rather than in a container.
+//! The releases API and the assets are fetched using `curl`.
+use crate::json::Value;
+use anyhow::Context;
+use sha2::Digest;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+const RELEASES_URL: &str = "https://api.github.com/repos/wez/docker-stack-deploy/releases/latest";
+
+/// Fetch url, returning the body of the response
+fn fetch(url: &str) -> anyhow::Result<Vec<u8>> {
+    let output = std::process::Command::new("curl")
+        .args([
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--location",
+            "--proto",
+            "=https",
+            "--max-time",
+            "300",
+            "--header",
+            "Accept: application/vnd.github+json",
+            url,
+        ])
+        .output()
+        .context("failed to run curl")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "failed to fetch {url}: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(output.stdout)
+}
+
+/// The name of the release asset that holds the binary for this platform
+fn asset_name() -> String {
+    format!(
+        "docker-stack-deploy-{}-{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS
+    )
+}
+
+/// Parse a version such as `v1.2.3` into its numeric components
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches('v');
+    // Ignore any pre-release or build suffix
+    let version = version.split(['-', '+']).next()?;
+    version.split('.').map(|n| n.parse().ok()).collect()
+}
+
+/// Returns true if latest is a newer version than current
+fn is_newer(latest: &str, current: &str) -> bool {
+    match (parse_version(latest), parse_version(current)) {
+        (Some(latest), Some(current)) => latest > current,
+        _ => false,
+    }
+}
+
+/// The latest release: its version and the download URLs of its assets
+struct Release {
+    version: String,
+    assets: Vec<(String, String)>,
+}
+
+impl Release {
+    fn latest() -> anyhow::Result<Self> {
+        let body = fetch(RELEASES_URL)?;
+        let value = Value::parse(&String::from_utf8_lossy(&body))
+            .context("failed to parse the response of the releases API")?;
+        let version = value
+            .get("tag_name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow::anyhow!("the latest release has no tag_name"))?
+            .to_string();
+        let assets = value
+            .get("assets")
+            .and_then(Value::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|asset| {
+                let name = asset.get("name")?.as_str()?;
+                let url = asset.get("browser_download_url")?.as_str()?;
+                Some((name.to_string(), url.to_string()))
+            })
+            .collect();
+        Ok(Self { version, assets })
+    }
+
+    fn asset_url(&self, name: &str) -> Option<&str> {
+        self.assets
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, url)| url.as_str())
+    }
+}
+
+/// Check for a newer release, and unless check_only is set,
+/// download it and replace the running binary with it
+pub fn self_update(check_only: bool) -> anyhow::Result<()> {
+    let current = env!("CARGO_PKG_VERSION");
+    let release = Release::latest()?;
+    if !is_newer(&release.version, current) {
+        log::info!(
+            "docker-stack-deploy {current} is up to date (the latest release is {})",
+            release.version
+        );
+        return Ok(());
+    }
+    if check_only {
+        log::info!(
+            "An update is available: {current} -> {}. \
+            Run `docker-stack-deploy self-update` to install it",
+            release.version
+        );
+        return Ok(());
+    }
+
+    let name = asset_name();
+    let url = release.asset_url(&name).ok_or_else(|| {
+        anyhow::anyhow!(
+            "release {} has no {name} asset for this platform",
+            release.version
+        )
+    })?;
+    let checksum_name = format!("{name}.sha256");
+    let checksum_url = release.asset_url(&checksum_name).ok_or_else(|| {
+        anyhow::anyhow!(
+            "release {} has no {checksum_name} asset, so the download cannot be verified",
+            release.version
+        )
+    })?;
+
+    log::info!("Downloading {name} from release {}", release.version);
+    let binary = fetch(url)?;
+    let checksum = String::from_utf8_lossy(&fetch(checksum_url)?).to_string();
+    // The format of sha256sum: the hex digest, followed by the file name
+    let expected = checksum
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{checksum_name} is empty"))?;
+    let actual = format!("{:x}", sha2::Sha256::digest(&binary));
+    anyhow::ensure!(
+        actual.eq_ignore_ascii_case(expected),
+        "the sha256 of {name} is {actual}, but {checksum_name} says it should be {expected}"
+    );
+
+    let exe = std::env::current_exe().context("failed to determine the path of this binary")?;
+    replace_binary(&exe, &binary)?;
+    log::info!(
+        "Updated {} from {current} to {}",
+        exe.display(),
+        release.version
+    );
+    Ok(())
+}
+
+/// Write binary alongside exe, and then rename it over exe, so that
+/// exe is never left partially written
+fn replace_binary(exe: &Path, binary: &[u8]) -> anyhow::Result<()> {
+    let file_name = exe
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{exe:?} has no file name"))?
+        .to_string_lossy();
+    let new = exe.with_file_name(format!(".{file_name}.new"));
+    let result = (|| {
+        std::fs::write(&new, binary).with_context(|| format!("failed to write {new:?}"))?;
+        std::fs::set_permissions(&new, std::fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("failed to make {new:?} executable"))?;
+        std::fs::rename(&new, exe).with_context(|| format!("failed to replace {exe:?}"))
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&new);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        assert_eq!(parse_version("v1.2.3"), Some(vec![1, 2, 3]));
+        assert_eq!(parse_version(" 0.1.0\n"), Some(vec![0, 1, 0]));
+        assert_eq!(parse_version("1.2.3-rc.1"), Some(vec![1, 2, 3]));
+        assert_eq!(parse_version("1.2+build.5"), Some(vec![1, 2]));
+        assert_eq!(parse_version("nightly"), None);
+        assert_eq!(parse_version("1..2"), None);
+    }
+
+    #[test]
+    fn newer() {
+        assert!(is_newer("v0.2.0", "0.1.0"));
+        assert!(is_newer("v0.10.0", "0.9.9"));
+        assert!(is_newer("1.0.0", "0.99.0"));
+        assert!(is_newer("0.1.0.1", "0.1.0"));
+        assert!(!is_newer("v0.1.0", "0.1.0"));
+        assert!(!is_newer("v0.1.0", "0.2.0"));
+        // A pre-release is not newer than the release it precedes
+        assert!(!is_newer("v0.1.0-rc.1", "0.1.0"));
+        assert!(!is_newer("nightly", "0.1.0"));
+    }
+}
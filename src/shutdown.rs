@@ -0,0 +1,65 @@
+//! Handling for SIGINT and SIGTERM, so that the `run` loop can complete
+//! the current deploy before exiting, rather than being killed part way
+//! through it.
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+/// How many threads are in the middle of an update and deploy
+static BUSY: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn handle_signal(_signal: libc::c_int) {
+    // Only async-signal-safe operations are permitted here
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Install the signal handlers.  A background thread logs the
+/// request to shut down when the signal arrives, as that cannot
+/// be done safely from the signal handler itself.
+pub fn install() {
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+
+    std::thread::spawn(|| {
+        while !requested() {
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        if BUSY.load(Ordering::SeqCst) > 0 {
+            log::info!("Shutdown requested; it will complete once the current update and deploy have finished");
+        } else {
+            log::info!("Shutdown requested");
+        }
+    });
+}
+
+/// Returns true if a signal has requested that we shut down
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Record whether a deploy is in progress on the calling thread.
+/// Each call with true must be followed by a call with false.
+pub fn set_busy(busy: bool) {
+    if busy {
+        BUSY.fetch_add(1, Ordering::SeqCst);
+    } else {
+        BUSY.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Sleep for the specified duration, returning early if shutdown
+/// is requested.  Returns true if shutdown was requested.
+pub fn sleep(duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while !requested() {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        std::thread::sleep((deadline - now).min(Duration::from_millis(200)));
+    }
+    requested()
+}
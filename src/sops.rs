@@ -0,0 +1,65 @@
+//! A SecretStore backed by a sops-encrypted YAML, JSON or dotenv file,
+//! decrypted via the `sops` CLI.
+use crate::json::Value;
+use crate::secrets::SecretStore;
+use crate::watchdog::TrackedCommand;
+use anyhow::Context;
+use std::path::Path;
+
+pub struct SopsStore {
+    data: Value,
+}
+
+impl SopsStore {
+    /// Decrypt the file.  If age_key_file is specified, it is used
+    /// as the age identity, otherwise sops will use its usual
+    /// configuration, such as $SOPS_AGE_KEY_FILE.
+    pub fn open(file: &Path, age_key_file: Option<&Path>) -> anyhow::Result<Self> {
+        let mut cmd = std::process::Command::new("sops");
+        cmd.args(["--decrypt", "--output-type", "json"]);
+        cmd.arg(file);
+        if let Some(key_file) = age_key_file {
+            cmd.env("SOPS_AGE_KEY_FILE", key_file);
+        }
+        let output = cmd
+            .tracked_output()
+            .with_context(|| format!("failed to run sops to decrypt {file:?}"))?;
+        anyhow::ensure!(
+            output.status.success(),
+            "sops failed to decrypt {file:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+
+        let data = Value::parse(&String::from_utf8_lossy(&output.stdout))
+            .with_context(|| format!("failed to parse decrypted {file:?}"))?;
+        Ok(Self { data })
+    }
+}
+
+impl SecretStore for SopsStore {
+    /// Given a path like "group/key", navigates the decrypted structure
+    /// and returns the value at that location.  As with the KeePass
+    /// backend, the path elements are case insensitive, although an
+    /// exact match is preferred.  Array elements are addressed by index.
+    fn resolve_value(&self, path: &str) -> Option<String> {
+        let mut value = &self.data;
+        for element in path.split('/') {
+            value = match value {
+                Value::Object(fields) => fields
+                    .iter()
+                    .find(|(k, _)| k == element)
+                    .or_else(|| fields.iter().find(|(k, _)| k.eq_ignore_ascii_case(element)))
+                    .map(|(_, v)| v)?,
+                Value::Array(items) => items.get(element.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+
+        match value {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(n.clone()),
+            Value::Bool(_) => Some(value.to_string()),
+            Value::Null | Value::Array(_) | Value::Object(_) => None,
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Support for a `.stackignore` file in the root of the project,
+//! which uses gitignore-style patterns to exclude deploy files from
+//! consideration.
+use anyhow::Context;
+use regex::Regex;
+use std::path::Path;
+
+pub const STACK_IGNORE_FILE: &str = ".stackignore";
+
+struct Rule {
+    regex: Regex,
+    /// The pattern started with `!`, so matching paths are re-included
+    negate: bool,
+    /// The pattern ended with `/`, so it only matches directories
+    dir_only: bool,
+}
+
+#[derive(Default)]
+pub struct StackIgnore {
+    rules: Vec<Rule>,
+}
+
+impl StackIgnore {
+    /// Load the `.stackignore` file from root.
+    /// If there is no such file, nothing will be ignored.
+    pub fn load(root: &Path) -> anyhow::Result<Self> {
+        let path = root.join(STACK_IGNORE_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text).with_context(|| format!("failed to parse {path:?}")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("failed to read {path:?}")),
+        }
+    }
+
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut rules = vec![];
+        for line in text.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (negate, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, line),
+            };
+            let (dir_only, pattern) = match pattern.strip_suffix('/') {
+                Some(pattern) => (true, pattern),
+                None => (false, pattern),
+            };
+            // A pattern containing a slash is relative to the root,
+            // otherwise it may match at any depth
+            let anchored = pattern.contains('/');
+            let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+
+            let body = pattern_to_regex(pattern);
+            let regex = if anchored {
+                format!("^{body}$")
+            } else {
+                format!("^(?:.*/)?{body}$")
+            };
+
+            rules.push(Rule {
+                regex: Regex::new(&regex).with_context(|| format!("invalid pattern {line:?}"))?,
+                negate,
+                dir_only,
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    /// Returns true if the path, which is relative to the root,
+    /// or any of its parent directories, is ignored.
+    pub fn is_ignored(&self, relative: &Path) -> bool {
+        if self.rules.is_empty() {
+            return false;
+        }
+
+        let components: Vec<String> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .collect();
+
+        (1..=components.len()).any(|len| {
+            let is_dir = len < components.len();
+            self.matches(&components[..len].join("/"), is_dir)
+        })
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        let mut ignored = false;
+        // The last matching rule wins
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            if rule.regex.is_match(path) {
+                ignored = !rule.negate;
+            }
+        }
+        ignored
+    }
+}
+
+fn pattern_to_regex(pattern: &str) -> String {
+    let mut result = String::new();
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                if chars.get(i + 2) == Some(&'/') {
+                    // `**/` matches zero or more directories
+                    result.push_str("(?:.*/)?");
+                    i += 3;
+                } else {
+                    result.push_str(".*");
+                    i += 2;
+                }
+                continue;
+            }
+            '*' => result.push_str("[^/]*"),
+            '?' => result.push_str("[^/]"),
+            '[' => match chars[i..].iter().position(|&c| c == ']') {
+                Some(end) => {
+                    let class: String = chars[i + 1..i + end].iter().collect();
+                    let class = match class.strip_prefix('!') {
+                        Some(rest) => format!("^{rest}"),
+                        None => class,
+                    };
+                    result.push('[');
+                    result.push_str(&class.replace('\\', "\\\\"));
+                    result.push(']');
+                    i += end + 1;
+                    continue;
+                }
+                None => result.push_str("\\["),
+            },
+            '\\' if i + 1 < chars.len() => {
+                result.push_str(&regex::escape(&chars[i + 1].to_string()));
+                i += 2;
+                continue;
+            }
+            c => result.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert_eq!(pattern_to_regex("*.toml"), "[^/]*\\.toml");
+        assert_eq!(pattern_to_regex("a?[!x]"), "a[^/][^x]");
+        assert_eq!(pattern_to_regex("**/old"), "(?:.*/)?old");
+        assert_eq!(pattern_to_regex("a/**"), "a/.*");
+    }
+
+    #[test]
+    fn ignored_paths() {
+        let ignore = StackIgnore::parse(
+            "# comment\n\
+             scratch\n\
+             /top/stack-deploy.toml\n\
+             apps/**/test/\n\
+             tmp/\n\
+             archive/*\n\
+             !archive/keep\n\
+             v[!0-9]/\n",
+        )
+        .unwrap();
+        let ignored = |path: &str| ignore.is_ignored(Path::new(path));
+
+        // Unanchored patterns match at any depth
+        assert!(ignored("scratch/stack-deploy.toml"));
+        assert!(ignored("apps/scratch/stack-deploy.toml"));
+        // Anchored patterns only match relative to the root
+        assert!(ignored("top/stack-deploy.toml"));
+        assert!(!ignored("apps/top/stack-deploy.toml"));
+        // `**/` matches zero or more directories
+        assert!(ignored("apps/test/stack-deploy.toml"));
+        assert!(ignored("apps/a/b/test/stack-deploy.toml"));
+        // A trailing `/` only matches directories
+        assert!(ignored("tmp/stack-deploy.toml"));
+        assert!(!ignored("apps/tmp"));
+        // The last matching rule wins
+        assert!(ignored("archive/old/stack-deploy.toml"));
+        assert!(!ignored("archive/keep/stack-deploy.toml"));
+        // `[!x]` excludes the characters in the class
+        assert!(ignored("va/stack-deploy.toml"));
+        assert!(!ignored("v1/stack-deploy.toml"));
+
+        assert!(!StackIgnore::default().is_ignored(Path::new("scratch/stack-deploy.toml")));
+    }
+}
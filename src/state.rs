@@ -0,0 +1,246 @@
+//! Tracks which stacks have been deployed, and from which commit,
+//! so that stacks which are removed from the repo can be brought down.
+use crate::deploy_file::{DeployFile, DeployMode};
+use crate::json::Value;
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The name of the state file, which lives in the `.git` directory
+/// of the repo so that it doesn't appear as an untracked file
+const STATE_FILE: &str = "stack-deploy-state.json";
+
+/// The name of the TOML state file that earlier versions wrote,
+/// which is read if there is no JSON state file yet
+const LEGACY_STATE_FILE: &str = "stack-deploy-state.toml";
+
+#[derive(Deserialize, Debug, Default)]
+pub struct DeployState {
+    /// The stacks that have been deployed, keyed by the stack name
+    #[serde(default)]
+    pub stacks: BTreeMap<String, StackState>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StackState {
+    /// The compose project name of the stack, which allows it to be
+    /// brought down after its compose file has been removed
+    pub project: String,
+    /// The docker context that the stack was deployed to
+    #[serde(default)]
+    pub docker_context: Option<String>,
+    /// How the stack was deployed, which determines how it is brought
+    /// down.  State files written before this was recorded only hold
+    /// compose stacks.
+    #[serde(default)]
+    pub mode: DeployMode,
+    /// The commit of the repo from which the stack was last
+    /// deployed successfully
+    #[serde(default)]
+    pub commit: Option<String>,
+}
+
+impl DeployState {
+    /// The path of the state file named file_name for repo_dir, which is
+    /// in its `.git` directory if it is a git checkout, and in repo_dir
+    /// itself if not
+    fn path(repo_dir: &str, file_name: &str) -> PathBuf {
+        let root = Path::new(repo_dir);
+        let dot_git = root.join(".git");
+        if dot_git.is_dir() {
+            dot_git.join(file_name)
+        } else {
+            root.join(format!(".{file_name}"))
+        }
+    }
+
+    /// Load the state for repo_dir.
+    /// If there is no state file, the state is empty.
+    pub fn load(repo_dir: &str) -> anyhow::Result<Self> {
+        let path = Self::path(repo_dir, STATE_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Value::parse(&text)
+                .and_then(|value| Self::from_json(&value))
+                .with_context(|| format!("failed to parse {path:?}")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::load_legacy(repo_dir),
+            Err(err) => Err(err).with_context(|| format!("failed to read {path:?}")),
+        }
+    }
+
+    /// Load the TOML state file of earlier versions, if there is one
+    fn load_legacy(repo_dir: &str) -> anyhow::Result<Self> {
+        let path = Self::path(repo_dir, LEGACY_STATE_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).with_context(|| format!("failed to parse {path:?}")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("failed to read {path:?}")),
+        }
+    }
+
+    fn from_json(value: &Value) -> anyhow::Result<Self> {
+        let mut state = Self::default();
+        let stacks = match value.get("stacks") {
+            Some(Value::Object(stacks)) => stacks.as_slice(),
+            None => &[],
+            Some(_) => anyhow::bail!("stacks is not an object"),
+        };
+        for (name, stack) in stacks {
+            let field = |key| stack.get(key).and_then(Value::as_str).map(str::to_string);
+            let mode = match stack.get("mode").and_then(Value::as_str) {
+                None | Some("compose") => DeployMode::Compose,
+                Some("swarm") => DeployMode::Swarm,
+                Some(mode) => anyhow::bail!("stack {name} has an unknown mode {mode:?}"),
+            };
+            state.stacks.insert(
+                name.to_string(),
+                StackState {
+                    project: field("project")
+                        .with_context(|| format!("stack {name} has no project"))?,
+                    docker_context: field("docker_context"),
+                    mode,
+                    commit: field("commit"),
+                },
+            );
+        }
+        Ok(state)
+    }
+
+    fn to_json(&self) -> Value {
+        let stacks = self
+            .stacks
+            .iter()
+            .map(|(name, stack)| {
+                let mode = match stack.mode {
+                    DeployMode::Compose => "compose",
+                    DeployMode::Swarm => "swarm",
+                };
+                let stack = Value::Object(vec![
+                    ("project".to_string(), stack.project.as_str().into()),
+                    (
+                        "docker_context".to_string(),
+                        stack.docker_context.as_deref().into(),
+                    ),
+                    ("mode".to_string(), mode.into()),
+                    ("commit".to_string(), stack.commit.as_deref().into()),
+                ]);
+                (name.to_string(), stack)
+            })
+            .collect();
+        Value::Object(vec![("stacks".to_string(), Value::Object(stacks))])
+    }
+
+    /// Update the state of the stack in entry, preserving its commit,
+    /// and return it
+    pub fn update(&mut self, entry: &DeployFile) -> &mut StackState {
+        let state = self
+            .stacks
+            .entry(entry.deploy.name.clone())
+            .or_insert_with(|| StackState {
+                project: String::new(),
+                docker_context: None,
+                mode: DeployMode::default(),
+                commit: None,
+            });
+        state.project = entry.deploy.project_name();
+        state.docker_context = entry.deploy.docker_context.clone();
+        state.mode = entry.deploy.mode;
+        state
+    }
+
+    /// Print a table of the commit from which each stack was last deployed,
+    /// comparing it with head, the current commit of the repo
+    pub fn show_versions(&self, head: Option<&str>) {
+        let mut rows = vec![("STACK", "COMMIT", "STATUS")];
+        for (name, state) in &self.stacks {
+            let commit = state.commit.as_deref();
+            let status = match (commit, head) {
+                (None, _) => "unknown",
+                (Some(commit), Some(head)) if commit == head => "current",
+                (Some(_), Some(_)) => "outdated",
+                (Some(_), None) => "",
+            };
+            rows.push((name, commit.unwrap_or("-"), status));
+        }
+
+        let name_width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0);
+        let commit_width = rows.iter().map(|row| row.1.len()).max().unwrap_or(0);
+        for (name, commit, status) in rows {
+            let line = format!("{name:name_width$}  {commit:commit_width$}  {status}");
+            println!("{}", line.trim_end());
+        }
+    }
+
+    pub fn save(&self, repo_dir: &str) -> anyhow::Result<()> {
+        let path = Self::path(repo_dir, STATE_FILE);
+        let text = self.to_json().to_pretty_string();
+        // Write to a temporary file and rename it into place, so that
+        // the state is not lost if we are interrupted
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, text).with_context(|| format!("failed to write {temp:?}"))?;
+        std::fs::rename(&temp, &path)
+            .with_context(|| format!("failed to rename {temp:?} to {path:?}"))?;
+        // The JSON file supersedes the TOML file of earlier versions
+        let _ = std::fs::remove_file(Self::path(repo_dir, LEGACY_STATE_FILE));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_of_a_directory_without_git() {
+        let root = std::env::temp_dir().join(format!("state-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let repo_dir = root.to_str().unwrap();
+
+        let mut state = DeployState::default();
+        state.stacks.insert(
+            "web".to_string(),
+            StackState {
+                project: "web".to_string(),
+                docker_context: None,
+                mode: DeployMode::Compose,
+                commit: None,
+            },
+        );
+        state.save(repo_dir).unwrap();
+        let saved = root.join(".stack-deploy-state.json").exists();
+        let loaded = DeployState::load(repo_dir).unwrap();
+
+        std::fs::create_dir(root.join(".git")).unwrap();
+        let path = DeployState::path(repo_dir, STATE_FILE);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(saved);
+        assert_eq!(loaded.stacks["web"].project, "web");
+        assert_eq!(path, root.join(".git/stack-deploy-state.json"));
+    }
+
+    #[test]
+    fn json_round_trip_and_legacy_toml() {
+        let root = std::env::temp_dir().join(format!("state-json-test-{}", std::process::id()));
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        let repo_dir = root.to_str().unwrap();
+
+        std::fs::write(
+            root.join(".git/stack-deploy-state.toml"),
+            "[stacks.db]\nproject = \"db\"\nmode = \"swarm\"\ncommit = \"abc\"\n",
+        )
+        .unwrap();
+        let legacy = DeployState::load(repo_dir).unwrap();
+        legacy.save(repo_dir).unwrap();
+        let legacy_removed = !root.join(".git/stack-deploy-state.toml").exists();
+        let loaded = DeployState::load(repo_dir).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(legacy_removed);
+        let db = &loaded.stacks["db"];
+        assert_eq!(db.project, "db");
+        assert_eq!(db.mode, DeployMode::Swarm);
+        assert_eq!(db.commit.as_deref(), Some("abc"));
+        assert_eq!(db.docker_context, None);
+    }
+}
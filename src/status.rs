@@ -0,0 +1,185 @@
+use crate::deploy::{compose_command, docker_command};
+use crate::deploy_file::{DeployFile, DeployMode, StackDeploy};
+use crate::json::Value;
+use crate::watchdog::TrackedCommand;
+use anyhow::Context;
+use std::path::Path;
+
+struct Row {
+    stack: String,
+    service: String,
+    state: String,
+    health: String,
+}
+
+/// The containers reported by `docker compose ps --format json`.
+/// Depending on the version of compose, this is either a single
+/// JSON array, or one JSON object per line.
+fn parse_ps_output(output: &str) -> anyhow::Result<Vec<Value>> {
+    let output = output.trim();
+    if output.is_empty() {
+        return Ok(vec![]);
+    }
+    if output.starts_with('[') {
+        return Ok(Value::parse(output)?
+            .as_array()
+            .unwrap_or_default()
+            .to_vec());
+    }
+    output.lines().map(Value::parse).collect()
+}
+
+/// Returns the containers, including stopped containers, that belong
+/// to the stack, as reported by `docker compose ps`
+pub fn compose_ps(path: &Path, deploy: &StackDeploy) -> anyhow::Result<Vec<Value>> {
+    let name = &deploy.name;
+    let mut cmd = compose_command(path, deploy)?;
+    cmd.args(["ps", "--all", "--format", "json"]);
+    let output = cmd
+        .tracked_output()
+        .with_context(|| format!("failed to run docker compose ps for {name}"))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "docker compose ps for {name} failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    parse_ps_output(&String::from_utf8_lossy(&output.stdout))
+        .with_context(|| format!("failed to parse docker compose ps output for {name}"))
+}
+
+/// Returns the services of a swarm stack, as reported by
+/// `docker service ls`
+fn swarm_services(deploy: &StackDeploy) -> anyhow::Result<Vec<Value>> {
+    let name = &deploy.name;
+    let mut cmd = docker_command(deploy.docker_context.as_deref());
+    cmd.args([
+        "service",
+        "ls",
+        "--filter",
+        &format!("label=com.docker.stack.namespace={}", deploy.project_name()),
+        "--format",
+        "{{json .}}",
+    ]);
+    let output = cmd
+        .tracked_output()
+        .with_context(|| format!("failed to run docker service ls for {name}"))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "docker service ls for {name} failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    parse_ps_output(&String::from_utf8_lossy(&output.stdout))
+        .with_context(|| format!("failed to parse docker service ls output for {name}"))
+}
+
+/// Returns the value of a string field of a container from compose_ps,
+/// or an empty string if it is not present
+pub fn field(container: &Value, name: &str) -> String {
+    container
+        .get(name)
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// The rows for a swarm stack, with the replicas of each service
+/// as its state
+fn swarm_rows(entry: &DeployFile) -> anyhow::Result<Vec<Row>> {
+    let name = &entry.deploy.name;
+    let services = swarm_services(&entry.deploy)?;
+    if services.is_empty() {
+        return Ok(vec![Row {
+            stack: name.to_string(),
+            service: String::new(),
+            state: "down".to_string(),
+            health: String::new(),
+        }]);
+    }
+
+    let prefix = format!("{}_", entry.deploy.project_name());
+    Ok(services
+        .iter()
+        .map(|service| {
+            let service_name = field(service, "Name");
+            Row {
+                stack: name.to_string(),
+                service: service_name
+                    .strip_prefix(&prefix)
+                    .unwrap_or(&service_name)
+                    .to_string(),
+                state: format!("{} replicas", field(service, "Replicas")),
+                health: String::new(),
+            }
+        })
+        .collect())
+}
+
+fn stack_rows(entry: &DeployFile) -> anyhow::Result<Vec<Row>> {
+    if entry.deploy.mode == DeployMode::Swarm {
+        return swarm_rows(entry);
+    }
+    let name = &entry.deploy.name;
+    let containers = compose_ps(&entry.path, &entry.deploy)?;
+
+    let running = containers
+        .iter()
+        .any(|container| field(container, "State") == "running");
+    if !running {
+        return Ok(vec![Row {
+            stack: name.to_string(),
+            service: String::new(),
+            state: "down".to_string(),
+            health: String::new(),
+        }]);
+    }
+
+    Ok(containers
+        .iter()
+        .map(|container| Row {
+            stack: name.to_string(),
+            service: field(container, "Service"),
+            state: field(container, "State"),
+            health: field(container, "Health"),
+        })
+        .collect())
+}
+
+/// Print a table showing the state of the containers of each stack
+pub fn show_status(stacks: &[DeployFile]) {
+    let mut rows = vec![Row {
+        stack: "STACK".to_string(),
+        service: "SERVICE".to_string(),
+        state: "STATE".to_string(),
+        health: "HEALTH".to_string(),
+    }];
+
+    for entry in stacks {
+        match stack_rows(entry) {
+            Ok(stack_rows) => rows.extend(stack_rows),
+            Err(err) => {
+                log::error!("{err:#}");
+                rows.push(Row {
+                    stack: entry.deploy.name.to_string(),
+                    service: String::new(),
+                    state: "unknown".to_string(),
+                    health: String::new(),
+                });
+            }
+        }
+    }
+
+    let width = |f: fn(&Row) -> &str| rows.iter().map(|row| f(row).len()).max().unwrap_or(0);
+    let stack_width = width(|row| &row.stack);
+    let service_width = width(|row| &row.service);
+    let state_width = width(|row| &row.state);
+
+    for row in &rows {
+        let line = format!(
+            "{:stack_width$}  {:service_width$}  {:state_width$}  {}",
+            row.stack, row.service, row.state, row.health
+        );
+        println!("{}", line.trim_end());
+    }
+}
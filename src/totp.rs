@@ -0,0 +1,377 @@
+//! Computes TOTP (RFC 6238) codes from the OTP settings stored
+//! in a KeePass entry.
+//! Both the `otp` field holding an `otpauth://` URI, as used by KeePassXC,
+//! and the `TimeOtp-*` fields used by KeePass 2.x are supported.
+use keepass::db::Entry;
+use sha2::Digest;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+#[derive(Debug)]
+struct TotpParams {
+    secret: Vec<u8>,
+    period: u64,
+    digits: u32,
+    algorithm: Algorithm,
+}
+
+/// Compute the current TOTP code for the entry, returning None if
+/// the entry has no OTP configured, or if the configuration is invalid.
+pub fn current_code(entry: &Entry) -> Option<String> {
+    let params = params_from_uri(entry).or_else(|| params_from_keepass_fields(entry))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(params.code_at(now))
+}
+
+fn get_field<'a>(entry: &'a Entry, name: &str) -> Option<&'a str> {
+    entry
+        .fields
+        .keys()
+        .find(|k| k.eq_ignore_ascii_case(name))
+        .and_then(|k| entry.get(k))
+}
+
+fn parse_algorithm(s: &str) -> Option<Algorithm> {
+    match s.to_ascii_uppercase().replace(['-', '_'], "").as_str() {
+        "SHA1" | "HMACSHA1" => Some(Algorithm::Sha1),
+        "SHA256" | "HMACSHA256" => Some(Algorithm::Sha256),
+        "SHA512" | "HMACSHA512" => Some(Algorithm::Sha512),
+        _ => None,
+    }
+}
+
+/// Parse an `otpauth://totp/label?secret=...&period=...` URI
+fn params_from_uri(entry: &Entry) -> Option<TotpParams> {
+    let uri = get_field(entry, "otp")?;
+    let rest = uri.strip_prefix("otpauth://totp/")?;
+    let (_label, query) = rest.split_once('?')?;
+
+    let mut secret = None;
+    let mut period = 30;
+    let mut digits = 6;
+    let mut algorithm = Algorithm::Sha1;
+
+    for pair in query.split('&') {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        let v = percent_decode(v)?;
+        match k.to_ascii_lowercase().as_str() {
+            "secret" => secret = Some(base32_decode(&v)?),
+            "period" => period = v.parse().ok()?,
+            "digits" => digits = v.parse().ok()?,
+            "algorithm" => algorithm = parse_algorithm(&v)?,
+            _ => {}
+        }
+    }
+
+    TotpParams {
+        secret: secret?,
+        period,
+        digits,
+        algorithm,
+    }
+    .validate()
+}
+
+/// Parse the native KeePass 2.x `TimeOtp-*` fields
+fn params_from_keepass_fields(entry: &Entry) -> Option<TotpParams> {
+    let secret = if let Some(s) = get_field(entry, "TimeOtp-Secret-Base32") {
+        base32_decode(s)?
+    } else if let Some(s) = get_field(entry, "TimeOtp-Secret-Hex") {
+        hex_decode(s)?
+    } else if let Some(s) = get_field(entry, "TimeOtp-Secret") {
+        s.as_bytes().to_vec()
+    } else {
+        return None;
+    };
+
+    let period = match get_field(entry, "TimeOtp-Period") {
+        Some(p) => p.trim().parse().ok()?,
+        None => 30,
+    };
+    let digits = match get_field(entry, "TimeOtp-Length") {
+        Some(d) => d.trim().parse().ok()?,
+        None => 6,
+    };
+    let algorithm = match get_field(entry, "TimeOtp-Algorithm") {
+        Some(a) => parse_algorithm(a)?,
+        None => Algorithm::Sha1,
+    };
+
+    TotpParams {
+        secret,
+        period,
+        digits,
+        algorithm,
+    }
+    .validate()
+}
+
+impl TotpParams {
+    fn validate(self) -> Option<Self> {
+        if self.secret.is_empty() || self.period == 0 || !(1..=10).contains(&self.digits) {
+            return None;
+        }
+        Some(self)
+    }
+
+    fn code_at(&self, unix_time: u64) -> String {
+        let counter = (unix_time / self.period).to_be_bytes();
+        let mac = hmac(self.algorithm, &self.secret, &counter);
+
+        // Dynamic truncation, per RFC 4226
+        let offset = (mac[mac.len() - 1] & 0xf) as usize;
+        let binary = u32::from_be_bytes([
+            mac[offset] & 0x7f,
+            mac[offset + 1],
+            mac[offset + 2],
+            mac[offset + 3],
+        ]);
+        let code = u64::from(binary) % 10u64.pow(self.digits);
+        format!("{code:0width$}", width = self.digits as usize)
+    }
+}
+
+fn hash(algorithm: Algorithm, data: &[u8]) -> Vec<u8> {
+    match algorithm {
+        Algorithm::Sha1 => sha1(data).to_vec(),
+        Algorithm::Sha256 => sha2::Sha256::digest(data).to_vec(),
+        Algorithm::Sha512 => sha2::Sha512::digest(data).to_vec(),
+    }
+}
+
+fn hmac(algorithm: Algorithm, key: &[u8], message: &[u8]) -> Vec<u8> {
+    let block_size = match algorithm {
+        Algorithm::Sha1 | Algorithm::Sha256 => 64,
+        Algorithm::Sha512 => 128,
+    };
+
+    let mut key = if key.len() > block_size {
+        hash(algorithm, key)
+    } else {
+        key.to_vec()
+    };
+    key.resize(block_size, 0);
+
+    let mut inner: Vec<u8> = key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(message);
+    let inner = hash(algorithm, &inner);
+
+    let mut outer: Vec<u8> = key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&inner);
+    hash(algorithm, &outer)
+}
+
+/// SHA-1, which is the default TOTP algorithm.
+/// Implemented here because none of our dependencies provide it.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, w) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*w);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut result = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        result[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    result
+}
+
+/// Decode RFC 4648 base32, ignoring case, padding and whitespace
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut result = vec![];
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+
+    for c in s.chars() {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            '=' | ' ' | '-' => continue,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(result)
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut result = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = s.get(i + 1..i + 3)?;
+                result.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                result.push(b' ');
+                i += 1;
+            }
+            b => {
+                result.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(result).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn sha1_vectors() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // Long enough that the padding needs a second block
+        assert_eq!(
+            hex(&sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn hmac_vectors() {
+        // RFC 2202
+        assert_eq!(
+            hex(&hmac(Algorithm::Sha1, &[0x0b; 20], b"Hi There")),
+            "b617318655057264e28bc0b6fb378c8ef146be00"
+        );
+        // A key longer than the block size is hashed first
+        assert_eq!(
+            hex(&hmac(
+                Algorithm::Sha1,
+                &[0xaa; 80],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+        // RFC 4231
+        assert_eq!(
+            hex(&hmac(Algorithm::Sha256, &[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+    }
+
+    #[test]
+    fn rfc6238_vectors() {
+        let code = |secret: &[u8], algorithm, unix_time| {
+            TotpParams {
+                secret: secret.to_vec(),
+                period: 30,
+                digits: 8,
+                algorithm,
+            }
+            .code_at(unix_time)
+        };
+        let sha1 = b"12345678901234567890";
+        let sha256 = b"12345678901234567890123456789012";
+        let sha512 = b"1234567890123456789012345678901234567890123456789012345678901234";
+        assert_eq!(code(sha1, Algorithm::Sha1, 59), "94287082");
+        assert_eq!(code(sha1, Algorithm::Sha1, 1111111109), "07081804");
+        assert_eq!(code(sha1, Algorithm::Sha1, 20000000000), "65353130");
+        assert_eq!(code(sha256, Algorithm::Sha256, 59), "46119246");
+        assert_eq!(code(sha512, Algorithm::Sha512, 59), "90693936");
+    }
+
+    #[test]
+    fn base32() {
+        assert_eq!(base32_decode(""), Some(vec![]));
+        assert_eq!(base32_decode("MY======"), Some(b"f".to_vec()));
+        assert_eq!(base32_decode("MZXW6YTBOI======"), Some(b"foobar".to_vec()));
+        // Case, padding, spaces and dashes are ignored
+        assert_eq!(base32_decode("mzxw 6ytb-oi"), Some(b"foobar".to_vec()));
+        assert_eq!(
+            base32_decode("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"),
+            Some(b"12345678901234567890".to_vec())
+        );
+        assert_eq!(base32_decode("MZXW1"), None);
+        assert_eq!(base32_decode("MZ!W"), None);
+    }
+
+    #[test]
+    fn other_encodings() {
+        assert_eq!(hex_decode("3132 33"), Some(b"123".to_vec()));
+        assert_eq!(hex_decode("313"), None);
+        assert_eq!(hex_decode("zz"), None);
+        assert_eq!(percent_decode("SHA%2D256+x").as_deref(), Some("SHA-256 x"));
+        assert_eq!(percent_decode("%2"), None);
+    }
+}
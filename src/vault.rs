@@ -0,0 +1,128 @@
+//! A SecretStore backed by the KV v2 secrets engine of HashiCorp Vault,
+//! accessed via its HTTP API using `curl`.
+use crate::json::Value;
+use crate::secrets::SecretStore;
+use crate::watchdog::TrackedCommand;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::Stdio;
+use std::sync::Mutex;
+
+pub struct VaultStore {
+    addr: String,
+    token: String,
+    /// The data of each secret that has been fetched, keyed by its path,
+    /// so that each secret is fetched at most once per deploy
+    cache: Mutex<BTreeMap<String, Option<Value>>>,
+}
+
+/// Make a request to the Vault API, passing stdin to curl, and
+/// returning the parsed JSON response
+fn curl(args: &[&str], stdin: &str) -> anyhow::Result<Value> {
+    let mut child = std::process::Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--max-time", "30"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .tracked_spawn()
+        .context("failed to run curl")?;
+    let written = child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(stdin.as_bytes());
+    // Wait in any case, so that the child is reaped
+    let output = crate::watchdog::wait_with_output(child).context("failed to run curl")?;
+    written.context("failed to write to curl")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Value::parse(&String::from_utf8_lossy(&output.stdout)).context("failed to parse response")
+}
+
+impl VaultStore {
+    /// Prepare to access the Vault server at addr.
+    /// If no token is provided, one is obtained by logging in with
+    /// the provided AppRole credentials.
+    pub fn open(
+        addr: &str,
+        token: Option<String>,
+        approle: Option<(String, String)>,
+    ) -> anyhow::Result<Self> {
+        let addr = addr.trim_end_matches('/').to_string();
+        let token = match (token, approle) {
+            (Some(token), _) => token,
+            (None, Some((role_id, secret_id))) => {
+                let body = Value::Object(vec![
+                    ("role_id".to_string(), role_id.into()),
+                    ("secret_id".to_string(), secret_id.into()),
+                ]);
+                let url = format!("{addr}/v1/auth/approle/login");
+                let response = curl(
+                    &["-X", "POST", "--data-binary", "@-", &url],
+                    &body.to_string(),
+                )
+                .context("failed to log in to Vault using AppRole")?;
+                response
+                    .get("auth")
+                    .and_then(|auth| auth.get("client_token"))
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| anyhow::anyhow!("Vault AppRole login returned no token"))?
+                    .to_string()
+            }
+            (None, None) => {
+                anyhow::bail!("Missing Vault token or AppRole role id and secret id");
+            }
+        };
+
+        Ok(Self {
+            addr,
+            token,
+            cache: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Fetch the data of the secret at path
+    fn fetch(&self, path: &str) -> Option<Value> {
+        let url = format!("{}/v1/{path}", self.addr);
+        // The token is passed via stdin so that it doesn't appear
+        // in the process list
+        match curl(
+            &["-H", "@-", &url],
+            &format!("X-Vault-Token: {}", self.token),
+        ) {
+            Ok(response) => response
+                .get("data")
+                .and_then(|data| data.get("data"))
+                .cloned(),
+            Err(err) => {
+                log::error!("Failed to fetch {path} from Vault: {err:#}");
+                None
+            }
+        }
+    }
+}
+
+impl SecretStore for VaultStore {
+    /// Given a path like "secret/data/app/db#password", fetches the
+    /// secret at "secret/data/app/db" and returns its "password" field.
+    fn resolve_value(&self, path: &str) -> Option<String> {
+        let (path, field) = path.rsplit_once('#')?;
+
+        let mut cache = self.cache.lock().unwrap();
+        let data = cache
+            .entry(path.to_string())
+            .or_insert_with(|| self.fetch(path));
+
+        match data.as_ref()?.get(field)? {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(n.clone()),
+            value @ Value::Bool(_) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+}
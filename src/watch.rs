@@ -0,0 +1,135 @@
+//! Watching a directory tree for changes to files, using inotify,
+//! so that stacks can be redeployed as they are edited.
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO;
+
+pub struct Watcher {
+    fd: OwnedFd,
+    /// The directory that each watch descriptor refers to
+    dirs: BTreeMap<libc::c_int, PathBuf>,
+}
+
+impl Watcher {
+    /// Watch root and all of the directories beneath it, other than
+    /// those whose names start with `.`, such as `.git`
+    pub fn new(root: &Path) -> anyhow::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("inotify_init1 failed");
+        }
+        let mut watcher = Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            dirs: BTreeMap::new(),
+        };
+        watcher.add_tree(root)?;
+        Ok(watcher)
+    }
+
+    fn add_tree(&mut self, dir: &Path) -> anyhow::Result<()> {
+        let c_dir = CString::new(dir.as_os_str().as_bytes())
+            .with_context(|| format!("{dir:?} contains a NUL byte"))?;
+        let wd =
+            unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), c_dir.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("failed to watch {dir:?}"));
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+
+        for entry in std::fs::read_dir(dir).with_context(|| format!("failed to read {dir:?}"))? {
+            let entry = entry.with_context(|| format!("failed to read {dir:?}"))?;
+            let is_hidden = entry.file_name().as_bytes().starts_with(b".");
+            if !is_hidden && entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                self.add_tree(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the events that are currently available, returning the
+    /// paths that they refer to.  New directories are watched too.
+    fn read_events(&mut self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        let mut buf = [0u8; 8192];
+        loop {
+            let len =
+                unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if len < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::WouldBlock {
+                    return Ok(paths);
+                }
+                return Err(err).context("failed to read inotify events");
+            }
+
+            let header = std::mem::size_of::<libc::inotify_event>();
+            let mut offset = 0;
+            while offset + header <= len as usize {
+                let event: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr().cast()) };
+                let name = &buf[offset + header..offset + header + event.len as usize];
+                let name = name.split(|&b| b == 0).next().unwrap_or_default();
+                offset += header + event.len as usize;
+
+                let Some(dir) = self.dirs.get(&event.wd) else {
+                    continue;
+                };
+                let path = dir.join(OsStr::from_bytes(name));
+                if event.mask & libc::IN_ISDIR != 0 && event.mask & libc::IN_CREATE != 0 {
+                    if let Err(err) = self.add_tree(&path) {
+                        log::warn!("{err:#}");
+                    }
+                }
+                paths.push(path);
+            }
+        }
+    }
+
+    /// Wait until files change, and then until no further changes have
+    /// been seen for `settle`, so that a burst of writes from an editor
+    /// or a `git checkout` is reported as one batch.
+    /// Returns None if shutdown is requested while waiting.
+    pub fn wait_for_changes(&mut self, settle: Duration) -> anyhow::Result<Option<Vec<PathBuf>>> {
+        let mut paths = vec![];
+        loop {
+            let timeout = if paths.is_empty() {
+                Duration::from_millis(200)
+            } else {
+                settle
+            };
+            let mut pollfd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ready = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+            if ready < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(err).context("failed to poll inotify");
+                }
+            }
+            if crate::shutdown::requested() {
+                return Ok(None);
+            }
+            if ready > 0 {
+                paths.extend(self.read_events()?);
+            } else if !paths.is_empty() {
+                paths.sort();
+                paths.dedup();
+                return Ok(Some(paths));
+            }
+        }
+    }
+}
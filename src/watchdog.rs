@@ -0,0 +1,284 @@
+//! A cap on how long a deploy may take as a whole, so that a hung deploy
+//! started by cron doesn't overlap with the next one.  When it is
+//! exceeded, the child processes that are still running are killed, the
+//! temporary files that hold secrets are removed, and then either the
+//! process exits, or, for the poll loop of `run`, the stacks that have
+//! yet to be deployed are abandoned until the next deploy.
+//!
+//! Child processes are only killed if they were started through
+//! `PrefixedChild`, `TrackedCommand` or `wait_with_output`, so every
+//! command that a deploy runs should use one of those.
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Identifies the current deadline; zero when there is none
+static ARMED: AtomicUsize = AtomicUsize::new(0);
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+/// Whether the current deadline has been exceeded
+static EXPIRED: AtomicBool = AtomicBool::new(false);
+/// The process ids of the child processes that are running
+static CHILDREN: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+/// The stacks of the current deploy that have not finished yet
+static PENDING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+/// Temporary files and directories that must not outlive the process,
+/// as they may hold secrets
+static TEMP_FILES: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// The exit status used when the deadline is exceeded
+pub const EXIT_STATUS: i32 = 124;
+
+/// What happens once a deadline has been exceeded, and its child
+/// processes have been killed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnExpiry {
+    /// Exit the process with `EXIT_STATUS`
+    Exit,
+    /// Leave the deploy to fail the stacks that it was deploying, and
+    /// to skip the rest, as reported by `expired`
+    Abandon,
+}
+
+/// Disarms the deadline when dropped
+pub struct Deadline {
+    id: usize,
+    on_expiry: OnExpiry,
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        if self.on_expiry == OnExpiry::Exit && expired() && ARMED.load(Ordering::SeqCst) == self.id
+        {
+            // The deploy was cut short; wait for abort to exit the
+            // process, so that it exits with EXIT_STATUS
+            loop {
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+        if ARMED
+            .compare_exchange(self.id, 0, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            EXPIRED.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Abort the deploy if the returned Deadline is not dropped within
+/// max_runtime.  Only one deadline is in effect at a time; arming a new
+/// one replaces the previous one.
+pub fn arm(max_runtime: Duration, on_expiry: OnExpiry) -> Deadline {
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    ARMED.store(id, Ordering::SeqCst);
+    EXPIRED.store(false, Ordering::SeqCst);
+    let deadline = Instant::now() + max_runtime;
+    std::thread::spawn(move || {
+        while ARMED.load(Ordering::SeqCst) == id {
+            let now = Instant::now();
+            if now >= deadline {
+                abort(max_runtime, on_expiry);
+                break;
+            }
+            std::thread::sleep((deadline - now).min(Duration::from_millis(200)));
+        }
+    });
+    Deadline { id, on_expiry }
+}
+
+/// Returns true if the current deadline has been exceeded, in which
+/// case no more stacks should be deployed
+pub fn expired() -> bool {
+    EXPIRED.load(Ordering::SeqCst)
+}
+
+/// Returns an error if the current deadline has been exceeded, for
+/// loops that poll rather than waiting on a child process
+pub fn ensure_not_expired() -> anyhow::Result<()> {
+    anyhow::ensure!(!expired(), "the deploy exceeded --max-runtime");
+    Ok(())
+}
+
+fn abort(max_runtime: Duration, on_expiry: OnExpiry) {
+    // Any child process that is started from now on is killed at once
+    EXPIRED.store(true, Ordering::SeqCst);
+    let pending: Vec<String> = PENDING.lock().unwrap().iter().cloned().collect();
+    if pending.is_empty() {
+        log::error!(
+            "Aborting, as the deploy did not finish within --max-runtime {} seconds",
+            max_runtime.as_secs()
+        );
+    } else {
+        log::error!(
+            "Aborting, as the deploy did not finish within --max-runtime {} seconds. \
+            These stacks were still pending: {}",
+            max_runtime.as_secs(),
+            pending.join(", ")
+        );
+    }
+
+    let children: Vec<u32> = CHILDREN.lock().unwrap().iter().copied().collect();
+    for &pid in &children {
+        log::warn!("Terminating child process {pid}");
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+    }
+    if !children.is_empty() {
+        // Allow them a moment to exit cleanly before killing them
+        let grace = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < grace && !CHILDREN.lock().unwrap().is_empty() {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        for pid in CHILDREN.lock().unwrap().iter() {
+            unsafe {
+                libc::kill(*pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+
+    for path in TEMP_FILES.lock().unwrap().iter() {
+        if path.is_dir() {
+            let _ = std::fs::remove_dir_all(path);
+        } else {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    if on_expiry == OnExpiry::Exit {
+        std::process::exit(EXIT_STATUS);
+    }
+}
+
+/// Record that the child process pid is running, so that it can be
+/// killed if the deadline is exceeded
+pub fn child_started(pid: u32) {
+    CHILDREN.lock().unwrap().insert(pid);
+    if expired() {
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+}
+
+/// Record that the child process pid has exited
+pub fn child_exited(pid: u32) {
+    CHILDREN.lock().unwrap().remove(&pid);
+}
+
+/// Record the stacks that a deploy is about to deploy
+pub fn stacks_pending<'a>(names: impl IntoIterator<Item = &'a str>) {
+    let mut pending = PENDING.lock().unwrap();
+    pending.clear();
+    pending.extend(names.into_iter().map(String::from));
+}
+
+/// Record that a stack has been deployed, or has failed to deploy
+pub fn stack_finished(name: &str) {
+    PENDING.lock().unwrap().remove(name);
+}
+
+/// Record a temporary file that should be removed if the deadline is exceeded
+pub fn temp_file_created(path: PathBuf) {
+    TEMP_FILES.lock().unwrap().insert(path);
+}
+
+/// Record that a temporary file has been removed, or that it no longer
+/// needs to be removed if the deadline is exceeded
+pub fn temp_file_removed(path: &std::path::Path) {
+    TEMP_FILES.lock().unwrap().remove(path);
+}
+
+/// Wait for a child that was started with `tracked_spawn` to exit,
+/// collecting its output as `Child::wait_with_output` does
+pub fn wait_with_output(child: Child) -> std::io::Result<Output> {
+    let pid = child.id();
+    let output = child.wait_with_output();
+    child_exited(pid);
+    output
+}
+
+/// Runs commands in the same way as `Command::spawn`, `Command::output`
+/// and `Command::status`, while recording them as child processes so
+/// that they are killed if the deadline is exceeded
+pub trait TrackedCommand {
+    /// Like `Command::spawn`; the child must be waited for with
+    /// `wait_with_output`
+    fn tracked_spawn(&mut self) -> std::io::Result<Child>;
+
+    /// Like `Command::output`: stdin is null, and stdout and stderr
+    /// are captured
+    fn tracked_output(&mut self) -> std::io::Result<Output>;
+
+    /// Like `Command::status`
+    fn tracked_status(&mut self) -> std::io::Result<ExitStatus>;
+}
+
+impl TrackedCommand for Command {
+    fn tracked_spawn(&mut self) -> std::io::Result<Child> {
+        let child = self.spawn()?;
+        child_started(child.id());
+        Ok(child)
+    }
+
+    fn tracked_output(&mut self) -> std::io::Result<Output> {
+        let child = self
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .tracked_spawn()?;
+        wait_with_output(child)
+    }
+
+    fn tracked_status(&mut self) -> std::io::Result<ExitStatus> {
+        let mut child = self.tracked_spawn()?;
+        let status = child.wait();
+        child_exited(child.id());
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A single test, as the deadline is global to the process
+    #[test]
+    fn abandon() {
+        // A deadline that is disarmed in time has no effect
+        drop(arm(Duration::from_millis(100), OnExpiry::Abandon));
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(!expired());
+
+        let temp = std::env::temp_dir().join(format!("watchdog-test-{}", std::process::id()));
+        std::fs::write(&temp, "secret").unwrap();
+        temp_file_created(temp.clone());
+
+        let deadline = arm(Duration::from_millis(200), OnExpiry::Abandon);
+        let start = Instant::now();
+        let output = Command::new("sleep").arg("30").tracked_output().unwrap();
+        assert!(!output.status.success());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(expired());
+        assert!(ensure_not_expired().is_err());
+        assert!(CHILDREN.lock().unwrap().is_empty());
+        // The files are removed once the children have been killed
+        let removed = Instant::now() + Duration::from_secs(10);
+        while temp.exists() && Instant::now() < removed {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!temp.exists());
+
+        // Commands started once it has expired are killed at once
+        let start = Instant::now();
+        let status = Command::new("sleep").arg("30").tracked_status().unwrap();
+        assert!(!status.success());
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // Dropping the deadline allows the next deploy to run commands
+        drop(deadline);
+        assert!(!expired());
+        assert!(Command::new("true").tracked_status().unwrap().success());
+    }
+}
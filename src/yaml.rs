@@ -0,0 +1,729 @@
+//! A minimal YAML parser, sufficient for deploy files written in YAML,
+//! without pulling in additional dependencies.  It produces a
+//! `toml::Value`, so that YAML and TOML deploy files are deserialized
+//! into `StackDeploy` in the same way, with the same errors for unknown
+//! fields.
+//!
+//! serde_yaml is not used, as it is no longer maintained, and as the
+//! deploy files only need the subset of YAML below; a deploy file that
+//! needs more than that can be written in TOML.  Anything outside of the
+//! subset is reported as an error, rather than being parsed differently
+//! than a full YAML parser would.
+//!
+//! Block and flow mappings and sequences, plain and quoted scalars,
+//! literal and folded block scalars, and comments are supported.
+//! Anchors, aliases, tags, multi-line flow collections and multiple
+//! documents are not.  As TOML has no null, mapping entries whose
+//! value is null are omitted.
+use anyhow::Context;
+use toml::Value;
+
+/// Parse text as a YAML document
+pub fn parse(text: &str) -> anyhow::Result<Value> {
+    let mut parser = Parser {
+        lines: text.lines().map(String::from).collect(),
+        pos: 0,
+    };
+    parser.skip_document_start();
+    let value = match parser.peek()? {
+        None => Node::Null,
+        Some((indent, _)) => parser.parse_node(indent)?,
+    };
+    if let Some((_, text)) = parser.peek()? {
+        anyhow::bail!(
+            "line {}: unexpected {text:?}; multiple documents are not supported",
+            parser.pos + 1
+        );
+    }
+    match value.into_value() {
+        Some(value) => Ok(value),
+        None => Ok(Value::Table(Default::default())),
+    }
+}
+
+/// A parsed value, which, unlike a `toml::Value`, may be null
+enum Node {
+    Null,
+    Value(Value),
+}
+
+impl Node {
+    fn into_value(self) -> Option<Value> {
+        match self {
+            Node::Null => None,
+            Node::Value(value) => Some(value),
+        }
+    }
+}
+
+struct Parser {
+    lines: Vec<String>,
+    pos: usize,
+}
+
+/// Returns the number of leading spaces of line
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// Returns text without any trailing comment
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut prev = ' ';
+    let mut chars = text.char_indices().peekable();
+    while let Some((idx, c)) = chars.next() {
+        match quote {
+            Some('"') if c == '\\' => {
+                chars.next();
+            }
+            // An escaped single quote
+            Some('\'') if c == '\'' && chars.peek().is_some_and(|&(_, next)| next == '\'') => {
+                chars.next();
+            }
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '#' && prev.is_whitespace() => return text[..idx].trim_end(),
+            None if (c == '"' || c == '\'') && (prev.is_whitespace() || "[{,:".contains(prev)) => {
+                quote = Some(c)
+            }
+            None => {}
+        }
+        prev = c;
+    }
+    text.trim_end()
+}
+
+/// If text is a mapping entry, returns its key and the text of its value
+fn split_key(text: &str) -> anyhow::Result<Option<(String, &str)>> {
+    let (key, rest) = if text.starts_with(['"', '\'']) {
+        let (key, len) = parse_quoted(text)?;
+        let rest = text[len..].trim_start();
+        match rest.strip_prefix(':') {
+            Some(rest) if rest.is_empty() || rest.starts_with(' ') => (key, rest),
+            _ => return Ok(None),
+        }
+    } else if text.starts_with(['[', '{']) {
+        return Ok(None);
+    } else {
+        let Some(idx) = text
+            .match_indices(':')
+            .map(|(idx, _)| idx)
+            .find(|&idx| text[idx + 1..].is_empty() || text[idx + 1..].starts_with(' '))
+        else {
+            return Ok(None);
+        };
+        (text[..idx].trim_end().to_string(), &text[idx + 1..])
+    };
+    Ok(Some((key, rest.trim())))
+}
+
+/// Parse the quoted scalar at the start of text, returning its value
+/// and the length of text that it occupies
+fn parse_quoted(text: &str) -> anyhow::Result<(String, usize)> {
+    let mut chars = text.char_indices();
+    let quote = chars.next().map(|(_, c)| c);
+    let mut value = String::new();
+    while let Some((idx, c)) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') => {
+                if text[idx + 1..].starts_with('\'') {
+                    chars.next();
+                    value.push('\'');
+                } else {
+                    return Ok((value, idx + 1));
+                }
+            }
+            (Some('"'), '"') => return Ok((value, idx + 1)),
+            (Some('"'), '\\') => {
+                let Some((_, escaped)) = chars.next() else {
+                    break;
+                };
+                match escaped {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'r' => value.push('\r'),
+                    '0' => value.push('\0'),
+                    '\\' | '"' | '/' | ' ' => value.push(escaped),
+                    'u' | 'x' | 'U' => {
+                        let len = match escaped {
+                            'x' => 2,
+                            'u' => 4,
+                            _ => 8,
+                        };
+                        let start = idx + 2;
+                        let code = text
+                            .get(start..start + len)
+                            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| anyhow::anyhow!("invalid escape in {text}"))?;
+                        value.push(code);
+                        for _ in 0..len {
+                            chars.next();
+                        }
+                    }
+                    _ => anyhow::bail!("unsupported escape \\{escaped} in {text}"),
+                }
+            }
+            _ => value.push(c),
+        }
+    }
+    anyhow::bail!("unterminated quoted string {text}")
+}
+
+/// Resolve the type of a plain scalar, as in the YAML 1.2 core schema
+fn plain_scalar(text: &str) -> anyhow::Result<Node> {
+    anyhow::ensure!(
+        !text.starts_with(['&', '*', '!']),
+        "anchors, aliases and tags are not supported: {text}"
+    );
+    Ok(Node::Value(match text {
+        "" | "~" | "null" | "Null" | "NULL" => return Ok(Node::Null),
+        "true" | "True" | "TRUE" => Value::Boolean(true),
+        "false" | "False" | "FALSE" => Value::Boolean(false),
+        ".inf" | ".Inf" | ".INF" | "+.inf" => Value::Float(f64::INFINITY),
+        "-.inf" | "-.Inf" | "-.INF" => Value::Float(f64::NEG_INFINITY),
+        ".nan" | ".NaN" | ".NAN" => Value::Float(f64::NAN),
+        _ => {
+            let digits = text.trim_start_matches(['-', '+']);
+            if let Some(hex) = text.strip_prefix("0x") {
+                i64::from_str_radix(hex, 16)
+                    .map(Value::Integer)
+                    .unwrap_or_else(|_| Value::String(text.to_string()))
+            } else if let Some(octal) = text.strip_prefix("0o") {
+                i64::from_str_radix(octal, 8)
+                    .map(Value::Integer)
+                    .unwrap_or_else(|_| Value::String(text.to_string()))
+            } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+                text.parse()
+                    .map(Value::Integer)
+                    .unwrap_or_else(|_| Value::String(text.to_string()))
+            } else if digits.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+                && digits
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || "._eE-+".contains(c))
+            {
+                text.parse()
+                    .map(Value::Float)
+                    .unwrap_or_else(|_| Value::String(text.to_string()))
+            } else {
+                Value::String(text.to_string())
+            }
+        }
+    }))
+}
+
+/// A parser for the flow collections and scalars on a single line
+struct Flow<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Flow<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_spaces(&mut self) {
+        self.pos = self.text.len() - self.rest().trim_start().len();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        if self.rest().starts_with(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_value(&mut self) -> anyhow::Result<Node> {
+        self.skip_spaces();
+        let rest = self.rest();
+        if rest.starts_with('[') {
+            self.pos += 1;
+            let mut items = vec![];
+            while !self.eat(']') {
+                match self.parse_value()? {
+                    Node::Value(value) => items.push(value),
+                    Node::Null => {
+                        anyhow::bail!("null is not supported in a sequence: {}", self.text)
+                    }
+                }
+                if !self.eat(',') {
+                    anyhow::ensure!(self.eat(']'), "expected , or ] in {}", self.text);
+                    break;
+                }
+            }
+            Ok(Node::Value(Value::Array(items)))
+        } else if rest.starts_with('{') {
+            self.pos += 1;
+            let mut table = toml::map::Map::new();
+            while !self.eat('}') {
+                let key = match self.parse_value()? {
+                    Node::Value(Value::String(key)) => key,
+                    Node::Value(key) => key.to_string(),
+                    Node::Null => anyhow::bail!("missing key in {}", self.text),
+                };
+                anyhow::ensure!(self.eat(':'), "expected : after {key} in {}", self.text);
+                if let Node::Value(value) = self.parse_value()? {
+                    anyhow::ensure!(
+                        table.insert(key.clone(), value).is_none(),
+                        "duplicate key {key} in {}",
+                        self.text
+                    );
+                }
+                if !self.eat(',') {
+                    anyhow::ensure!(self.eat('}'), "expected , or }} in {}", self.text);
+                    break;
+                }
+            }
+            Ok(Node::Value(Value::Table(table)))
+        } else if rest.starts_with(['"', '\'']) {
+            let (value, len) = parse_quoted(rest)?;
+            self.pos += len;
+            Ok(Node::Value(Value::String(value)))
+        } else {
+            // A plain scalar ends at an indicator of the enclosing collection
+            let end = rest
+                .char_indices()
+                .find(|&(idx, c)| {
+                    ",]}".contains(c)
+                        || (c == ':' && rest[idx + 1..].starts_with([' ', ',', ']', '}']))
+                        || (c == ':' && rest[idx + 1..].is_empty())
+                })
+                .map(|(idx, _)| idx)
+                .unwrap_or(rest.len());
+            self.pos += end;
+            plain_scalar(rest[..end].trim())
+        }
+    }
+}
+
+/// Parse an inline value: a flow collection, or a quoted or plain scalar
+fn inline_value(text: &str) -> anyhow::Result<Node> {
+    if text.starts_with(['[', '{', '"', '\'']) {
+        let mut flow = Flow { text, pos: 0 };
+        let value = flow.parse_value()?;
+        flow.skip_spaces();
+        anyhow::ensure!(
+            flow.rest().is_empty(),
+            "unexpected {:?} after the value",
+            flow.rest()
+        );
+        Ok(value)
+    } else {
+        plain_scalar(text)
+    }
+}
+
+impl Parser {
+    fn skip_document_start(&mut self) {
+        while let Some(line) = self.lines.get(self.pos) {
+            let trimmed = strip_comment(line).trim();
+            if trimmed.is_empty() || trimmed.starts_with('%') {
+                self.pos += 1;
+            } else {
+                if trimmed == "---" {
+                    self.pos += 1;
+                }
+                return;
+            }
+        }
+    }
+
+    /// Skip blank and comment lines, returning the indentation and the
+    /// content of the next line, if any
+    fn peek(&mut self) -> anyhow::Result<Option<(usize, String)>> {
+        while let Some(line) = self.lines.get(self.pos) {
+            let content = strip_comment(line);
+            if content.trim().is_empty() || content.trim() == "..." {
+                self.pos += 1;
+                continue;
+            }
+            anyhow::ensure!(
+                !line.trim_start_matches(' ').starts_with('\t'),
+                "line {}: tabs cannot be used for indentation",
+                self.pos + 1
+            );
+            let indent = indentation(content);
+            return Ok(Some((indent, content[indent..].to_string())));
+        }
+        Ok(None)
+    }
+
+    fn error_context(&self) -> String {
+        format!("line {}", self.pos + 1)
+    }
+
+    /// Parse the block node that starts on the next line, at indent
+    fn parse_node(&mut self, indent: usize) -> anyhow::Result<Node> {
+        let Some((_, text)) = self.peek()? else {
+            return Ok(Node::Null);
+        };
+        if text == "-" || text.starts_with("- ") {
+            self.parse_sequence(indent)
+        } else if split_key(&text)
+            .with_context(|| self.error_context())?
+            .is_some()
+        {
+            self.parse_mapping(indent)
+        } else {
+            let value = inline_value(&text).with_context(|| self.error_context())?;
+            self.pos += 1;
+            Ok(value)
+        }
+    }
+
+    /// Parse the value that follows a key or a sequence indicator,
+    /// whose text is value, on a line indented by indent
+    fn parse_value(
+        &mut self,
+        indent: usize,
+        value: &str,
+        in_mapping: bool,
+    ) -> anyhow::Result<Node> {
+        if value.is_empty() {
+            return match self.peek()? {
+                Some((next, _)) if next > indent => self.parse_node(next),
+                // A sequence may be at the same indentation as its key
+                Some((next, text))
+                    if in_mapping && next == indent && (text == "-" || text.starts_with("- ")) =>
+                {
+                    self.parse_sequence(next)
+                }
+                _ => Ok(Node::Null),
+            };
+        }
+        if value.starts_with(['|', '>']) {
+            return self.parse_block_scalar(indent, value);
+        }
+        // The error refers to the line of the key, which was consumed
+        inline_value(value).with_context(|| format!("line {}", self.pos))
+    }
+
+    fn parse_mapping(&mut self, indent: usize) -> anyhow::Result<Node> {
+        let mut table = toml::map::Map::new();
+        while let Some((next, text)) = self.peek()? {
+            // The start of another document ends this one
+            if next < indent || (next == 0 && text == "---") {
+                break;
+            }
+            anyhow::ensure!(
+                next == indent,
+                "{}: unexpected indentation",
+                self.error_context()
+            );
+            let (key, value) = split_key(&text)
+                .with_context(|| self.error_context())?
+                .ok_or_else(|| {
+                    anyhow::anyhow!("{}: expected a `key: value` entry", self.error_context())
+                })?;
+            anyhow::ensure!(
+                !table.contains_key(&key),
+                "{}: duplicate key {key}",
+                self.error_context()
+            );
+            let value = value.to_string();
+            self.pos += 1;
+            if let Node::Value(value) = self.parse_value(indent, &value, true)? {
+                table.insert(key, value);
+            }
+        }
+        Ok(Node::Value(Value::Table(table)))
+    }
+
+    fn parse_sequence(&mut self, indent: usize) -> anyhow::Result<Node> {
+        let mut items = vec![];
+        while let Some((next, text)) = self.peek()? {
+            if next < indent || !(text == "-" || text.starts_with("- ")) {
+                break;
+            }
+            anyhow::ensure!(
+                next == indent,
+                "{}: unexpected indentation",
+                self.error_context()
+            );
+            let rest = text[1..].trim_start();
+            let node = if split_key(rest)
+                .with_context(|| self.error_context())?
+                .is_some()
+            {
+                // A mapping that starts on the same line as the `-`;
+                // replace the `-` with a space, and parse the mapping
+                // as though it started on its own line
+                let offset = indent + (text.len() - rest.len());
+                self.lines[self.pos].replace_range(indent..indent + 1, " ");
+                self.parse_mapping(offset)?
+            } else {
+                let rest = rest.to_string();
+                self.pos += 1;
+                self.parse_value(indent, &rest, false)?
+            };
+            match node {
+                Node::Value(value) => items.push(value),
+                Node::Null => {
+                    anyhow::bail!(
+                        "{}: null is not supported in a sequence",
+                        self.error_context()
+                    )
+                }
+            }
+        }
+        Ok(Node::Value(Value::Array(items)))
+    }
+
+    fn parse_block_scalar(&mut self, indent: usize, header: &str) -> anyhow::Result<Node> {
+        let folded = header.starts_with('>');
+        let chomping = header[1..].trim();
+        anyhow::ensure!(
+            matches!(chomping, "" | "-" | "+"),
+            "{}: unsupported block scalar header {header}",
+            self.error_context()
+        );
+
+        let mut lines = vec![];
+        let mut content_indent = None;
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.trim().is_empty() {
+                lines.push(String::new());
+                self.pos += 1;
+                continue;
+            }
+            let line_indent = indentation(line);
+            let content_indent = *content_indent.get_or_insert(line_indent);
+            if line_indent <= indent || line_indent < content_indent {
+                break;
+            }
+            lines.push(line[content_indent..].to_string());
+            self.pos += 1;
+        }
+        // Trailing blank lines belong to the chomping indicator
+        let mut trailing = 0;
+        while lines.last().is_some_and(String::is_empty) {
+            lines.pop();
+            trailing += 1;
+        }
+
+        let mut value = String::new();
+        for (idx, line) in lines.iter().enumerate() {
+            if idx > 0 {
+                let prev = &lines[idx - 1];
+                // Folding replaces a line break between two lines of text
+                // with a space, and drops the break before an empty line,
+                // but preserves the breaks around more indented lines
+                if folded && !prev.is_empty() && !prev.starts_with(' ') && !line.starts_with(' ') {
+                    if !line.is_empty() {
+                        value.push(' ');
+                    }
+                } else {
+                    value.push('\n');
+                }
+            }
+            value.push_str(line);
+        }
+        match chomping {
+            "-" => {}
+            "+" => {
+                value.push('\n');
+                value.push_str(&"\n".repeat(trailing));
+            }
+            _ if !lines.is_empty() => value.push('\n'),
+            _ => {}
+        }
+        Ok(Node::Value(Value::String(value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same(yaml: &str, toml_text: &str) {
+        let expected: Value = toml::from_str(toml_text).unwrap();
+        assert_eq!(parse(yaml).unwrap(), expected);
+    }
+
+    #[test]
+    fn deploy_file() {
+        assert_same(
+            r#"---
+# The stack
+name: gitea
+runs_on: [docker1, "docker2"]
+order: -1
+remove_orphans: false
+depends_on:
+- traefik
+- stack: postgres   # wait for it
+  healthy: true
+secret_env:
+  DB_PASSWD: 'Database/Gitea Postgres DB/password'
+  URL: "postgres://gitea:{secret:Db/pw}@db/gitea"
+compose_file: ~
+scale: {web: 2, worker: 3}
+post_deploy:
+  - |
+    echo one
+    echo two
+  - >-
+    curl
+    --fail
+"#,
+            r#"
+name = "gitea"
+runs_on = ["docker1", "docker2"]
+order = -1
+remove_orphans = false
+depends_on = ["traefik", { stack = "postgres", healthy = true }]
+post_deploy = ["echo one\necho two\n", "curl --fail"]
+[secret_env]
+DB_PASSWD = "Database/Gitea Postgres DB/password"
+URL = "postgres://gitea:{secret:Db/pw}@db/gitea"
+[scale]
+web = 2
+worker = 3
+"#,
+        );
+    }
+
+    #[test]
+    fn scalars() {
+        assert_same(
+            "a: 1.5\nb: 'it''s # not a comment'\nc: \"tab\\there\"\nd: http://host:80/x\ne: 0x1f\nf: 1.2.3\n",
+            "a = 1.5\nb = \"it's # not a comment\"\nc = \"tab\\there\"\nd = \"http://host:80/x\"\ne = 31\nf = \"1.2.3\"\n",
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        assert_same(
+            r##"
+"quoted key": 1
+'single key': 2
+a: "say \"hi\"\n\u00e9\x41"
+b: 'it''s'
+c: "# not a comment"
+d: 'a: b'
+e: "true"
+f: "1"
+g: ''
+h: "back\\slash"
+"##,
+            r##"
+"quoted key" = 1
+"single key" = 2
+a = "say \"hi\"\n\u00e9A"
+b = "it's"
+c = "# not a comment"
+d = "a: b"
+e = "true"
+f = "1"
+g = ""
+h = "back\\slash"
+"##,
+        );
+    }
+
+    #[test]
+    fn plain_scalars() {
+        assert_same(
+            "a: yes\nb: True\nc: -3\nd: 1e3\ne: 0o17\nf: .inf\ng: hello world\nh: x:y\ni: '~'\n",
+            "a = \"yes\"\nb = true\nc = -3\nd = 1000.0\ne = 15\nf = inf\ng = \"hello world\"\nh = \"x:y\"\ni = \"~\"\n",
+        );
+        // Null values are omitted, as TOML has no null
+        assert_same("a: ~\nb: null\nc:\nd: 1\n", "d = 1\n");
+    }
+
+    #[test]
+    fn comments() {
+        assert_same(
+            r#"
+# leading
+a: 1 # trailing
+b: a#b
+c: "x" # after a quoted value
+d: [1, 2] # after a flow sequence
+# between
+e:
+  # inside a mapping
+  f: 2
+
+  g: 3
+"#,
+            "a = 1\nb = \"a#b\"\nc = \"x\"\nd = [1, 2]\n[e]\nf = 2\ng = 3\n",
+        );
+    }
+
+    #[test]
+    fn block_scalars() {
+        assert_same(
+            "a: |\n  one\n    two\n\n  three\nb: |-\n  kept # not a comment\nc: |+\n  x\n\nd: >\n  folded\n  text\n\n  para\ne: >-\n  f\n    indented\n  g\n",
+            r#"
+a = "one\n  two\n\nthree\n"
+b = "kept # not a comment"
+c = "x\n\n"
+d = "folded text\npara\n"
+e = "f\n  indented\ng"
+"#,
+        );
+    }
+
+    #[test]
+    fn flow_collections() {
+        assert_same(
+            r#"
+a: []
+b: {}
+c: [x, "y, z", 'w']
+d: {k: v, "q": [1, {n: 2}]}
+e: [http://host:80]
+"#,
+            r#"
+a = []
+b = {}
+c = ["x", "y, z", "w"]
+d = { k = "v", q = [1, { n = 2 }] }
+e = ["http://host:80"]
+"#,
+        );
+    }
+
+    #[test]
+    fn nested_sequences() {
+        assert_same(
+            "a:\n  - 1\n  -\n    - 2\nb:\n- x: 1\n  y:\n    z: 2\n- w: 3\n",
+            "a = [1, [2]]\nb = [{ x = 1, y = { z = 2 } }, { w = 3 }]\n",
+        );
+    }
+
+    #[test]
+    fn documents() {
+        assert_same("", "");
+        assert_same("# only a comment\n", "");
+        assert_same("%YAML 1.2\n---\na: 1\n...\n", "a = 1\n");
+    }
+
+    #[test]
+    fn errors() {
+        let error = |yaml: &str| format!("{:#}", parse(yaml).unwrap_err());
+        assert!(error("a: 1\na: 2\n").contains("duplicate key a"));
+        assert!(error("a: {b: 1, b: 2}\n").contains("duplicate key b"));
+        assert!(error("a: &anchor 1\n").contains("anchors, aliases and tags"));
+        assert!(error("a: *alias\n").contains("anchors, aliases and tags"));
+        assert!(error("a: !!str 1\n").contains("anchors, aliases and tags"));
+        assert!(error("a:\n\t- b\n").contains("tabs"));
+        assert!(error("a: 1\n---\nb: 2\n").contains("multiple documents"));
+        assert!(error("a: \"open\n").contains("unterminated"));
+        assert!(error("a: [1, 2\n").contains("expected , or ]"));
+        assert!(error("a: {b: 1\n").contains("expected , or }"));
+        assert!(error("a: [1] x\n").contains("unexpected"));
+        // Single pair mappings in flow sequences are not supported
+        assert!(error("a: [b: 1]\n").contains("expected , or ]"));
+        assert!(error("a: \"\\q\"\n").contains("unsupported escape"));
+        assert!(error("a: |x\n  b\n").contains("block scalar header"));
+        assert!(error("a:\n  - ~\n").contains("null"));
+        assert!(error("a: 1\n   b: 2\n").contains("line 2: unexpected indentation"));
+        assert!(error("a: 1\njust text\n").contains("line 2"));
+    }
+}